@@ -0,0 +1,213 @@
+//! Compiles every shader in `shader/` to SPIR-V and embeds it in the binary, so shipping builds
+//! need neither shaderc nor the `shader/` directory at runtime.
+//!
+//! Each shader is compiled with no defines, and once more for every `// variant:` line it has,
+//! with the defines listed there, e.g. `// variant: ALPHA_MASK SAMPLES=4`.
+//!
+//! GLSL files are compiled for the stage their extension names. HLSL files are only
+//! precompiled for the entry points they list like `// entry: vertex vs_main`.
+//!
+//! Every entry records the identifiers its source and includes mention, so defines a shader
+//! never uses don't keep it from being found at runtime.
+//!
+//! With the `shader-compiler` feature a shader that fails to compile is only a warning, it's
+//! compiled (and its errors reported) at runtime instead.
+//!
+//! Release builds are optimized without debug info, the others the other way around. The
+//! `optimized_shaders` cfg tells the runtime which, see `ShaderCompileSettings::PRECOMPILED`.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    env,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[path = "src/render/shader_options.rs"]
+mod shader_options;
+
+use shader_options::SHADER_DIR;
+
+fn main() {
+    println!("cargo:rerun-if-changed={SHADER_DIR}");
+
+    let mut paths = fs::read_dir(SHADER_DIR)
+        .expect("failed to read the shader directory")
+        .map(|entry| entry.expect("failed to read the shader directory").path())
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let compiler = shaderc::Compiler::new().expect("failed to initialize shaderc");
+    let optimize = env::var("PROFILE").is_ok_and(|profile| profile == "release");
+    println!("cargo:rustc-check-cfg=cfg(optimized_shaders)");
+    if optimize {
+        println!("cargo:rustc-cfg=optimized_shaders");
+    }
+    let runtime_compiler = env::var_os("CARGO_FEATURE_SHADER_COMPILER").is_some();
+
+    let mut generated = String::from("&[\n");
+    for path in &paths {
+        let source = fs::read_to_string(path)
+            .unwrap_or_else(|err| panic!("failed to read {}: {err}", path.display()));
+        for (kind, entry_point) in entry_points(path, &source) {
+            for defines in variants(&source) {
+                let (spirv, includes) = match compile(
+                    &compiler,
+                    path,
+                    &source,
+                    kind,
+                    &entry_point,
+                    &defines,
+                    optimize,
+                ) {
+                    Ok(compiled) => compiled,
+                    Err(err) if runtime_compiler => {
+                        println!(
+                            "cargo:warning=skipping {} `{entry_point}` with {defines:?}, it will be compiled at runtime:",
+                            path.display()
+                        );
+                        for line in err.lines() {
+                            println!("cargo:warning={line}");
+                        }
+                        continue;
+                    }
+                    Err(err) => panic!(
+                        "failed to compile {} `{entry_point}` with {defines:?}:\n{err}",
+                        path.display()
+                    ),
+                };
+
+                let referenced = identifiers(std::iter::once(&source).chain(&includes));
+                let defines = defines
+                    .iter()
+                    .filter(|(name, _)| referenced.contains(name.as_str()))
+                    .collect::<Vec<_>>();
+
+                let name = format!(
+                    "{SHADER_DIR}/{}",
+                    path.file_name().unwrap().to_string_lossy()
+                );
+                writeln!(generated, "    PrecompiledShader {{").unwrap();
+                writeln!(generated, "        path: {name:?},").unwrap();
+                writeln!(generated, "        entry_point: {entry_point:?},").unwrap();
+                writeln!(generated, "        defines: &{defines:?},").unwrap();
+                let referenced = referenced.into_iter().collect::<Vec<_>>();
+                writeln!(generated, "        referenced: &{referenced:?},").unwrap();
+                writeln!(generated, "        spirv: &[").unwrap();
+                for words in spirv.chunks(8) {
+                    let words = words
+                        .iter()
+                        .map(|word| format!("{word:#010x},"))
+                        .collect::<Vec<_>>();
+                    writeln!(generated, "            {}", words.join(" ")).unwrap();
+                }
+                writeln!(generated, "        ],").unwrap();
+                writeln!(generated, "    }},").unwrap();
+            }
+        }
+    }
+    generated.push_str("]\n");
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("precompiled_shaders.rs"), generated)
+        .expect("failed to write the precompiled shaders");
+}
+
+fn is_hlsl(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "hlsl")
+}
+
+/// The stages and entry points to compile a file for, none for includes.
+fn entry_points(path: &Path, source: &str) -> Vec<(shaderc::ShaderKind, String)> {
+    if is_hlsl(path) {
+        return source
+            .lines()
+            .filter_map(|line| {
+                let mut entry = line.trim().strip_prefix("// entry:")?.split_whitespace();
+                let kind = match entry.next()? {
+                    "vertex" => shaderc::ShaderKind::Vertex,
+                    "fragment" => shaderc::ShaderKind::Fragment,
+                    "compute" => shaderc::ShaderKind::Compute,
+                    stage => panic!("unknown stage `{stage}` in {}", path.display()),
+                };
+                Some((kind, entry.next()?.to_string()))
+            })
+            .collect();
+    }
+
+    let kind = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("vert") => shaderc::ShaderKind::Vertex,
+        Some("frag") => shaderc::ShaderKind::Fragment,
+        Some("comp") => shaderc::ShaderKind::Compute,
+        _ => return Vec::new(),
+    };
+    vec![(kind, "main".to_string())]
+}
+
+/// The default variant and the ones the `// variant:` lines ask for.
+fn variants(source: &str) -> Vec<Vec<(String, String)>> {
+    let listed = source.lines().filter_map(|line| {
+        let defines = line.trim().strip_prefix("// variant:")?;
+        Some(
+            defines
+                .split_whitespace()
+                .map(|define| match define.split_once('=') {
+                    Some((name, value)) => (name.to_string(), value.to_string()),
+                    None => (define.to_string(), "1".to_string()),
+                })
+                .collect::<Vec<_>>(),
+        )
+    });
+    std::iter::once(Vec::new()).chain(listed).collect()
+}
+
+/// Every identifier in `sources`, sorted.
+fn identifiers<'a>(sources: impl Iterator<Item = &'a String>) -> BTreeSet<&'a str> {
+    sources
+        .flat_map(|source| source.split(|char: char| !char.is_ascii_alphanumeric() && char != '_'))
+        .filter(|token| token.starts_with(|char: char| char.is_ascii_alphabetic() || char == '_'))
+        .collect()
+}
+
+/// Compiles with the options `Shader::compile` uses at runtime with the default settings. Returns
+/// the SPIR-V and the contents of every file that was included.
+fn compile(
+    compiler: &shaderc::Compiler,
+    path: &Path,
+    source: &str,
+    kind: shaderc::ShaderKind,
+    entry_point: &str,
+    defines: &[(String, String)],
+    optimize: bool,
+) -> Result<(Vec<u32>, Vec<String>), String> {
+    let includes = RefCell::new(Vec::new());
+    let mut options = shaderc::CompileOptions::new().expect("failed to initialize shaderc");
+    shader_options::configure(
+        &mut options,
+        is_hlsl(path),
+        defines
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+        optimize,
+        !optimize,
+    );
+    options.set_include_callback(|name, include_type, source_file, _depth| {
+        let (path, content) = shader_options::read_include(name, include_type, source_file)?;
+        includes.borrow_mut().push(content.clone());
+        Ok(shaderc::ResolvedInclude {
+            resolved_name: path.to_string_lossy().into_owned(),
+            content,
+        })
+    });
+
+    let name = path.to_string_lossy();
+    let artifact = compiler
+        .compile_into_spirv(source, kind, &name, entry_point, Some(&options))
+        .map_err(|err| err.to_string())?;
+    // the include callback borrows `includes`
+    drop(options);
+    Ok((artifact.as_binary().to_vec(), includes.into_inner()))
+}
@@ -0,0 +1,10 @@
+#version 450
+#include <global.glsl>
+
+layout (location = 0) out vec4 uFragColor;
+
+// Drawn in place of shaders that failed to compile. global.glsl declares the same descriptor sets
+// as the regular shaders, so the pipeline layout doesn't change.
+void main() {
+    uFragColor = vec4(1.0, 0.0, 1.0, 1.0);
+}
@@ -0,0 +1,14 @@
+#version 450
+#include <global.glsl>
+
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    Material material;
+    Camera camera;
+} pc;
+
+layout (location = 0) in vec3 position;
+
+void main() {
+    gl_Position = pc.camera.view_proj * pc.model * vec4(position, 1.0);
+}
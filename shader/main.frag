@@ -1,4 +1,5 @@
 #version 450
+// variant: ALPHA_MASK
 #include <global.glsl>
 
 layout(push_constant) uniform PushConstants {
@@ -11,6 +12,11 @@ layout (location = 0) in vec4 o_color;
 layout (location = 1) in vec2 o_uv;
 layout (location = 0) out vec4 uFragColor;
 
+#ifdef ALPHA_MASK
+// ALPHA_CUTOFF_CONSTANT_ID in material.rs
+layout (constant_id = 0) const float ALPHA_CUTOFF = 0.5;
+#endif
+
 void main() { 
     // uFragColor = texture(textures[0], o_uv);
     if (pc.material.base_color_texture_index != -1)
@@ -19,4 +25,10 @@ void main() {
         uFragColor = vec4(pc.material.base_color, 1.0);
     else
         uFragColor = vec4(1.0, 0.0, 1.0, 1.0);
+
+#ifdef ALPHA_MASK
+    if (uFragColor.a < ALPHA_CUTOFF)
+        discard;
+    uFragColor.a = 1.0;
+#endif
 }
\ No newline at end of file
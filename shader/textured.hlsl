@@ -0,0 +1,35 @@
+// A textured mesh, keeps the HLSL path covered by the tests in shaders.rs
+// entry: vertex vs_main
+// entry: fragment ps_main
+
+struct PushConstants {
+    float4x4 model;
+    float4x4 view_proj;
+    uint texture_index;
+};
+
+[[vk::push_constant]] PushConstants pc;
+
+Texture2D u_textures[16] : register(t0);
+SamplerState sampler_llr : register(s0);
+
+struct VsInput {
+    [[vk::location(0)]] float3 position : POSITION;
+    [[vk::location(2)]] float2 uv : TEXCOORD0;
+};
+
+struct VsOutput {
+    float4 position : SV_Position;
+    float2 uv : TEXCOORD0;
+};
+
+VsOutput vs_main(VsInput input) {
+    VsOutput output;
+    output.position = mul(pc.view_proj, mul(pc.model, float4(input.position, 1.0)));
+    output.uv = input.uv;
+    return output;
+}
+
+float4 ps_main(VsOutput input) : SV_Target0 {
+    return u_textures[pc.texture_index].Sample(sampler_llr, input.uv);
+}
@@ -7,7 +7,11 @@ use gpu_allocator::{
 };
 use image::DynamicImage;
 
-use crate::render::{RenderAllocator, RenderInstance};
+use crate::{
+    ctx::ExampleBase,
+    error::RenderResult,
+    render::{RenderAllocator, RenderInstance},
+};
 
 #[derive(Debug)]
 pub struct Buffer {
@@ -20,12 +24,15 @@ pub struct Buffer {
 }
 
 impl Buffer {
+    /// `name` shows up in allocator leak reports, validation messages and capture tools.
     pub fn new(
-        device: &ash::Device,
+        renderer: &ExampleBase,
         allocator: &mut Allocator,
+        name: &str,
         buffer_info: &vk::BufferCreateInfo,
         location: MemoryLocation,
-    ) -> Buffer {
+    ) -> RenderResult<Buffer> {
+        let device = &renderer.device;
         let size = buffer_info.size;
         let buffer_info = &mut buffer_info.clone();
 
@@ -36,26 +43,33 @@ impl Buffer {
             buffer_info.usage |= vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
         }
 
-        let buffer = unsafe { device.create_buffer(buffer_info, None) }.unwrap();
+        let buffer = unsafe { device.create_buffer(buffer_info, None) }?;
         let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
 
-        let allocation = allocator
-            .allocate(&AllocationCreateDesc {
-                name: "buffer",
-                requirements,
-                location,
-                linear: true,
-                allocation_scheme: gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
-            })
-            .unwrap();
+        let allocation = match allocator.allocate(&AllocationCreateDesc {
+            name,
+            requirements,
+            location,
+            linear: true,
+            allocation_scheme: gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
+        }) {
+            Ok(allocation) => allocation,
+            Err(err) => {
+                unsafe { device.destroy_buffer(buffer, None) };
+                return Err(err.into());
+            }
+        };
 
         let offset = allocation.offset();
         let device_addr: u64;
         unsafe {
-            device
-                .bind_buffer_memory(buffer, allocation.memory(), offset)
-                .unwrap();
+            if let Err(err) = device.bind_buffer_memory(buffer, allocation.memory(), offset) {
+                let _ = allocator.free(allocation);
+                device.destroy_buffer(buffer, None);
+                return Err(err.into());
+            }
 
+            renderer.set_object_name(buffer, name);
             device_addr = device.get_buffer_device_address(&vk::BufferDeviceAddressInfo {
                 buffer,
                 s_type: vk::StructureType::BUFFER_DEVICE_ADDRESS_INFO,
@@ -64,14 +78,14 @@ impl Buffer {
             });
         };
 
-        Self {
+        Ok(Self {
             buffer,
             allocation: Some(allocation),
             size,
             device_addr,
             has_been_written_to: false,
             offset,
-        }
+        })
     }
 
     pub fn destroy(&mut self, device: &ash::Device, allocator: &mut Allocator) {
@@ -134,44 +148,54 @@ impl From<TextureDescriptor> for vk::ImageCreateInfo<'static> {
 }
 
 impl Image {
+    /// `name` shows up in allocator leak reports, validation messages and capture tools.
     pub fn new(
-        device: &ash::Device,
+        renderer: &ExampleBase,
         allocator: &mut Allocator,
+        name: &str,
         image_info: &vk::ImageCreateInfo,
-    ) -> Image {
-        let image = unsafe { device.create_image(image_info, None) }.unwrap();
+    ) -> RenderResult<Image> {
+        let device = &renderer.device;
+        let image = unsafe { device.create_image(image_info, None) }?;
         let requirements = unsafe { device.get_image_memory_requirements(image) };
 
-        let allocation = allocator
-            .allocate(&AllocationCreateDesc {
-                name: "image",
-                requirements,
-                location: MemoryLocation::GpuOnly,
-                linear: false,
-                allocation_scheme: gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
-            })
-            .unwrap();
+        let allocation = match allocator.allocate(&AllocationCreateDesc {
+            name,
+            requirements,
+            location: MemoryLocation::GpuOnly,
+            linear: false,
+            allocation_scheme: gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
+        }) {
+            Ok(allocation) => allocation,
+            Err(err) => {
+                unsafe { device.destroy_image(image, None) };
+                return Err(err.into());
+            }
+        };
         let offset = allocation.offset();
 
         unsafe {
-            device
-                .bind_image_memory(image, allocation.memory(), allocation.offset())
-                .unwrap()
+            if let Err(err) = device.bind_image_memory(image, allocation.memory(), offset) {
+                let _ = allocator.free(allocation);
+                device.destroy_image(image, None);
+                return Err(err.into());
+            }
         };
+        renderer.set_object_name(image, name);
 
-        Self {
+        Ok(Self {
             image,
             allocation: Some(allocation),
             view: None,
             format: image_info.format,
             extent: image_info.extent,
             offset,
-        }
+        })
     }
 
-    pub fn create_view(&mut self, device: &ash::Device) -> vk::ImageView {
-        if self.view.is_some() {
-            return self.view.unwrap();
+    pub fn create_view(&mut self, device: &ash::Device) -> RenderResult<vk::ImageView> {
+        if let Some(view) = self.view {
+            return Ok(view);
         }
         let view = unsafe {
             device.create_image_view(
@@ -195,10 +219,9 @@ impl Image {
                 },
                 None,
             )
-        }
-        .unwrap();
+        }?;
         self.view = Some(view);
-        view
+        Ok(view)
     }
 
     pub fn destroy(&mut self, device: &ash::Device, allocator: &mut Allocator) {
@@ -212,12 +235,14 @@ impl Image {
     pub fn from_image_buffer(
         render_instance: &RenderInstance,
         render_allocator: &mut RenderAllocator,
+        name: &str,
         image: DynamicImage,
         format: vk::Format,
-    ) -> Self {
-        let texture = Self::new(
-            render_instance.device(),
+    ) -> RenderResult<Self> {
+        let mut texture = Self::new(
+            &render_instance.0,
             render_allocator.allocator(),
+            name,
             &vk::ImageCreateInfo::default()
                 .image_type(vk::ImageType::TYPE_2D)
                 .format(format)
@@ -232,7 +257,7 @@ impl Image {
                 .tiling(vk::ImageTiling::OPTIMAL)
                 .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
                 .sharing_mode(vk::SharingMode::EXCLUSIVE),
-        );
+        )?;
 
         {
             // let image_data = match format {
@@ -241,27 +266,47 @@ impl Image {
             //     _ => unimplemented!("Format not supported yet"),
             // };
             let image_data = image.to_rgba8().into_raw();
-            let mut img_buffer = Buffer::new(
-                render_instance.device(),
+            let img_buffer = Buffer::new(
+                &render_instance.0,
                 render_allocator.allocator(),
+                &format!("{name} staging"),
                 &vk::BufferCreateInfo::default()
                     .size(image_data.len() as DeviceSize)
                     .usage(vk::BufferUsageFlags::TRANSFER_SRC)
                     .sharing_mode(vk::SharingMode::EXCLUSIVE),
                 MemoryLocation::CpuToGpu,
             );
+            let mut img_buffer = match img_buffer {
+                Ok(img_buffer) => img_buffer,
+                Err(err) => {
+                    texture.destroy(render_instance.device(), render_allocator.allocator());
+                    return Err(err);
+                }
+            };
             img_buffer.copy_from_slice(&image_data, 0);
 
-            render_instance
+            let uploaded = match render_instance
                 .0
-                .copy_buffer_to_texture(&img_buffer, &texture);
+                .copy_buffer_to_texture(&img_buffer, &texture)
+            {
+                Ok(uploaded) => uploaded,
+                Err(err) => {
+                    // the copy is only submitted when nothing failed
+                    let device = render_instance.device();
+                    img_buffer.destroy(device, render_allocator.allocator());
+                    texture.destroy(device, render_allocator.allocator());
+                    return Err(err);
+                }
+            };
 
-            img_buffer.destroy(render_instance.device(), render_allocator.allocator());
+            render_allocator.destroy_after(uploaded, img_buffer);
         }
 
-        texture
+        Ok(texture)
     }
 
+    // for sizing staging buffers once uploads support more formats than RGBA8
+    #[allow(dead_code)]
     pub fn bytes_per_texel(&self) -> u32 {
         match self.format {
             vk::Format::R8G8B8A8_UNORM => 4,
@@ -5,26 +5,42 @@ use ash::vk::{
 };
 use ash::{
     extensions::{
-        ext::DebugUtils,
+        ext::{DebugUtils, ShaderObject},
         khr::{DynamicRendering, Surface, Swapchain, Synchronization2},
     },
     vk::{
-        BufferImageCopy, CommandBuffer, ExtDescriptorIndexingFn, ImageLayout,
-        PhysicalDeviceBufferDeviceAddressFeaturesKHR, PhysicalDeviceDescriptorIndexingFeatures,
-        API_VERSION_1_2,
+        BufferImageCopy, CommandBuffer, ExtDescriptorIndexingFn, ExtValidationFeaturesFn,
+        ImageLayout, PhysicalDeviceBufferDeviceAddressFeaturesKHR,
+        PhysicalDeviceDescriptorIndexingFeatures, API_VERSION_1_2,
     },
 };
-use ash::{vk, Entry};
+use ash::{prelude::VkResult, vk, Entry};
 use ash::{Device, Instance};
 use bevy::window::{PresentMode, RawHandleWrapper};
 use rayon::ThreadPool;
+use std::collections::HashMap;
 use std::default::Default;
-use std::ffi::CStr;
-use std::{borrow::Cow, collections::HashMap};
-use std::{ops::Drop, sync::RwLock};
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    ops::Drop,
+    sync::{Mutex, RwLock},
+};
 use std::{os::raw::c_char, sync::Arc};
 
+use gpu_allocator::{vulkan::Allocator, MemoryLocation};
+use image::RgbaImage;
+
 use crate::buffer::{Buffer, Image};
+use crate::device::{select_physical_device, DeviceRequirements, DeviceSelection, QueueFamilies};
+use crate::error::{MemoryKind, RenderError, RenderResult};
+use crate::render::{
+    hot_reload::ShaderWatcher, pipeline_cache::PipelineCache, shader_cache::ShaderCache,
+    shaders::ShaderCompileSettings, RenderBackend,
+};
+use crate::sync::{Queue, QueueTimelines, SubmitSemaphores, SyncPoint};
+use crate::validation::{DebugMessengerState, ValidationSettings};
 
 // /// Helper function for submitting command buffers. Immediately waits for the fence before the command buffer
 // /// is executed. That way we can delay the waiting for the fences by 1 frame which is good for performance.
@@ -82,85 +98,75 @@ pub const RESERVED_DESCRIPTOR_COUNT: u32 = 32;
 
 /// Helper function for submitting command buffers. Immediately waits for the fence before the command buffer
 /// is executed. That way we can delay the waiting for the fences by 1 frame which is good for performance.
-/// Make sure to create the fence in a signaled state on the first use.
-pub fn record_submit_commandbuffer<F: FnOnce(&Device, vk::CommandBuffer)>(
+/// Make sure to create the fence in a signaled state on the first use. Nothing is submitted when `f`
+/// fails.
+pub fn record_submit_commandbuffer<F: FnOnce(&Device, vk::CommandBuffer) -> RenderResult<()>>(
     device: &Device,
     command_buffer: vk::CommandBuffer,
     command_buffer_reuse_fence: vk::Fence,
-    submit_queue: vk::Queue,
-    wait_mask: &[vk::PipelineStageFlags],
-    wait_semaphores: &[vk::Semaphore],
-    signal_semaphores: &[vk::Semaphore],
+    submit_queue: &Queue,
+    semaphores: &SubmitSemaphores,
     f: F,
-) {
+) -> RenderResult<()> {
     unsafe {
-        device
-            .wait_for_fences(&[command_buffer_reuse_fence], true, std::u64::MAX)
-            .expect("Wait for fence failed.");
-
-        device
-            .reset_fences(&[command_buffer_reuse_fence])
-            .expect("Reset fences failed.");
-
-        device
-            .reset_command_buffer(
-                command_buffer,
-                vk::CommandBufferResetFlags::RELEASE_RESOURCES,
-            )
-            .expect("Reset command buffer failed.");
+        record_commandbuffer(device, command_buffer, command_buffer_reuse_fence, f)?;
+
+        // only reset right before the submit, a fence that's never signaled again would block the
+        // next wait forever
+        device.reset_fences(&[command_buffer_reuse_fence])?;
+        semaphores.submit(
+            device,
+            submit_queue,
+            &[command_buffer],
+            command_buffer_reuse_fence,
+        )
+    }
+}
 
-        let command_buffer_begin_info = vk::CommandBufferBeginInfo::default()
-            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+/// The recording half of [`record_submit_commandbuffer`], for submits that need more than
+/// [`SubmitSemaphores::submit`]. The fence is left signaled, it has to be reset right before the
+/// submit.
+pub unsafe fn record_commandbuffer<F: FnOnce(&Device, vk::CommandBuffer) -> RenderResult<()>>(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    command_buffer_reuse_fence: vk::Fence,
+    f: F,
+) -> RenderResult<()> {
+    device.wait_for_fences(&[command_buffer_reuse_fence], true, std::u64::MAX)?;
 
-        device
-            .begin_command_buffer(command_buffer, &command_buffer_begin_info)
-            .expect("Begin commandbuffer");
-        f(device, command_buffer);
-        device
-            .end_command_buffer(command_buffer)
-            .expect("End commandbuffer");
+    device.reset_command_buffer(
+        command_buffer,
+        vk::CommandBufferResetFlags::RELEASE_RESOURCES,
+    )?;
 
-        let command_buffers = vec![command_buffer];
+    let command_buffer_begin_info =
+        vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
 
-        let submit_info = vk::SubmitInfo::default()
-            .wait_semaphores(wait_semaphores)
-            .wait_dst_stage_mask(wait_mask)
-            .command_buffers(&command_buffers)
-            .signal_semaphores(signal_semaphores);
-
-        device
-            .queue_submit(submit_queue, &[submit_info], command_buffer_reuse_fence)
-            .expect("queue submit failed.");
-        device.queue_wait_idle(submit_queue).unwrap();
-    }
+    device.begin_command_buffer(command_buffer, &command_buffer_begin_info)?;
+    f(device, command_buffer)?;
+    device.end_command_buffer(command_buffer)?;
+    Ok(())
 }
 
-unsafe extern "system" fn vulkan_debug_callback(
-    message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
-    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
-    p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _user_data: *mut std::os::raw::c_void,
-) -> vk::Bool32 {
-    let callback_data = *p_callback_data;
-    let message_id_number = callback_data.message_id_number;
-
-    let message_id_name = if callback_data.p_message_id_name.is_null() {
-        Cow::from("")
-    } else {
-        CStr::from_ptr(callback_data.p_message_id_name).to_string_lossy()
-    };
-
-    let message = if callback_data.p_message.is_null() {
-        Cow::from("")
-    } else {
-        CStr::from_ptr(callback_data.p_message).to_string_lossy()
+/// Names `handle` in validation messages and capture tools like RenderDoc. Naming is best effort,
+/// a failure is only logged.
+pub fn set_object_name(
+    debug_utils: &DebugUtils,
+    device: &Device,
+    handle: impl vk::Handle,
+    name: &str,
+) {
+    let Ok(name) = CString::new(name) else {
+        return;
     };
-
-    println!(
-      "{message_severity:?}:\n{message_type:?} [{message_id_name} ({message_id_number})] : {message}\n",
-  );
-
-    vk::FALSE
+    let name_info = vk::DebugUtilsObjectNameInfoEXT::default()
+        .object_handle(handle)
+        .object_name(&name);
+    if let Err(err) =
+        unsafe { debug_utils.set_debug_utils_object_name(device.handle(), &name_info) }
+    {
+        tracing::debug!("Failed to name {name:?}: {err}");
+    }
 }
 
 pub fn find_memorytype_index(
@@ -185,78 +191,386 @@ pub struct SamplerDesc {
     pub address_modes: vk::SamplerAddressMode,
 }
 
+/// How the renderer is set up, filled in from the [`RenderPlugin`](crate::render::RenderPlugin).
+#[derive(Debug, Clone)]
+pub struct RendererSettings {
+    pub backend: RenderBackend,
+    pub frames_in_flight: usize,
+    pub device: DeviceSelection,
+    pub validation: ValidationSettings,
+    /// Where caches that survive restarts are kept, nothing is cached on disk when `None`.
+    pub cache_dir: Option<PathBuf>,
+    pub shaders: ShaderCompileSettings,
+    /// Recompile shaders when the files in `shader/` change.
+    pub hot_reload: bool,
+}
+
+/// Where the final image of every frame ends up.
+#[derive(Clone, Copy)]
+pub enum RenderTarget<'a> {
+    Window {
+        handle: &'a RawHandleWrapper,
+        extent: vk::Extent2D,
+        present_mode: PresentMode,
+    },
+    /// No window, surface or swapchain. Frames are rendered into a single offscreen image of this
+    /// size, which can be read back with [`ExampleBase::read_offscreen_image`].
+    Headless { extent: vk::Extent2D },
+}
+
+/// Everything that depends on the size of the window: the swapchain, its image views and the depth buffer.
+///
+/// Rebuilt by [`ExampleBase::recreate_swapchain`] whenever the window is resized or the surface
+/// reports that it's out of date. When headless there's no swapchain and `present_images` holds
+/// the single offscreen color image.
+#[derive(Default)]
+pub struct SwapchainManager {
+    pub swapchain: vk::SwapchainKHR,
+    pub present_images: Vec<vk::Image>,
+    pub present_image_views: Vec<vk::ImageView>,
+    pub surface_resolution: vk::Extent2D,
+    pub image_usage: vk::ImageUsageFlags,
+    /// Only set when headless, backs the offscreen image in `present_images`.
+    pub offscreen_image_memory: vk::DeviceMemory,
+
+    pub depth_image: vk::Image,
+    pub depth_image_view: vk::ImageView,
+    pub depth_image_memory: vk::DeviceMemory,
+
+    /// Physical size of the window, used when the surface doesn't dictate an extent itself.
+    pub window_extent: vk::Extent2D,
+    /// Set on resize and on `ERROR_OUT_OF_DATE_KHR`/`SUBOPTIMAL_KHR`, handled before the next acquire.
+    pub needs_recreate: bool,
+}
+
+impl SwapchainManager {
+    /// A minimized window has a zero sized surface, nothing can be presented until it's restored.
+    pub fn is_minimized(&self) -> bool {
+        self.surface_resolution.width == 0 || self.surface_resolution.height == 0
+    }
+
+    unsafe fn destroy(&mut self, device: &Device, swapchain_loader: &Swapchain) {
+        for &image_view in self.present_image_views.iter() {
+            device.destroy_image_view(image_view, None);
+        }
+        if self.offscreen_image_memory != vk::DeviceMemory::null() {
+            for &image in self.present_images.iter() {
+                device.destroy_image(image, None);
+            }
+            device.free_memory(self.offscreen_image_memory, None);
+            self.offscreen_image_memory = vk::DeviceMemory::null();
+        }
+        self.present_image_views.clear();
+        self.present_images.clear();
+
+        device.destroy_image_view(self.depth_image_view, None);
+        device.destroy_image(self.depth_image, None);
+        device.free_memory(self.depth_image_memory, None);
+        self.depth_image_view = vk::ImageView::null();
+        self.depth_image = vk::Image::null();
+        self.depth_image_memory = vk::DeviceMemory::null();
+
+        if self.swapchain != vk::SwapchainKHR::null() {
+            swapchain_loader.destroy_swapchain(self.swapchain, None);
+            self.swapchain = vk::SwapchainKHR::null();
+        }
+    }
+}
+
+/// Default number of frames the CPU is allowed to record ahead of the GPU.
+pub const FRAMES_IN_FLIGHT: usize = 2;
+
+/// A per-thread command pool and the secondary command buffer allocated from it.
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadCommandBuffer {
+    pub pool: vk::CommandPool,
+    pub command_buffer: CommandBuffer,
+}
+
+pub type ThreadCommandBuffers = Arc<RwLock<HashMap<usize, ThreadCommandBuffer>>>;
+
+/// Everything a single frame records into and synchronizes on. There's one of these per frame in
+/// flight, so nothing in here is touched again until the fence of that frame has been waited on.
+pub struct FrameContext {
+    pub command_pool: vk::CommandPool,
+    pub command_buffer: CommandBuffer,
+    /// Secondary command buffers, keyed by the index of the thread in `command_thread_pool`.
+    pub threaded_command_buffers: ThreadCommandBuffers,
+
+    pub present_complete_semaphore: vk::Semaphore,
+    pub rendering_complete_semaphore: vk::Semaphore,
+    pub in_flight_fence: vk::Fence,
+}
+
+/// How many uploads can be recorded and in flight on the transfer queue at once.
+const TRANSFER_CONTEXT_COUNT: usize = 4;
+
+/// A command buffer for uploads. Each has its own pool, so several threads can record uploads at
+/// the same time.
+pub struct TransferContext {
+    pub command_pool: vk::CommandPool,
+    pub command_buffer: vk::CommandBuffer,
+    pub reuse_fence: vk::Fence,
+}
+
+impl TransferContext {
+    unsafe fn new(device: &Device, queue_family_index: u32) -> RenderResult<Self> {
+        let command_pool = device.create_command_pool(
+            &vk::CommandPoolCreateInfo::default()
+                .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+                .queue_family_index(queue_family_index),
+            None,
+        )?;
+        let command_buffer = device.allocate_command_buffers(
+            &vk::CommandBufferAllocateInfo::default()
+                .command_buffer_count(1)
+                .command_pool(command_pool)
+                .level(vk::CommandBufferLevel::PRIMARY),
+        )?[0];
+        let reuse_fence = device.create_fence(
+            &vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED),
+            None,
+        )?;
+
+        Ok(Self {
+            command_pool,
+            command_buffer,
+            reuse_fence,
+        })
+    }
+
+    unsafe fn destroy(&self, device: &Device) {
+        device.destroy_fence(self.reuse_fence, None);
+        device.destroy_command_pool(self.command_pool, None);
+    }
+}
+
+impl FrameContext {
+    unsafe fn new(
+        device: &Device,
+        queue_family_index: u32,
+        threaded_command_buffers: ThreadCommandBuffers,
+    ) -> RenderResult<Self> {
+        let pool_create_info = vk::CommandPoolCreateInfo::default()
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+            .queue_family_index(queue_family_index);
+        let command_pool = device.create_command_pool(&pool_create_info, None)?;
+
+        let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::default()
+            .command_buffer_count(1)
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY);
+        let command_buffer = device.allocate_command_buffers(&command_buffer_allocate_info)?[0];
+
+        let semaphore_create_info = vk::SemaphoreCreateInfo::default();
+        let present_complete_semaphore = device.create_semaphore(&semaphore_create_info, None)?;
+        let rendering_complete_semaphore = device.create_semaphore(&semaphore_create_info, None)?;
+
+        // signaled, so the first wait on a frame that was never submitted returns immediately
+        let in_flight_fence = device.create_fence(
+            &vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED),
+            None,
+        )?;
+
+        Ok(Self {
+            command_pool,
+            command_buffer,
+            threaded_command_buffers,
+            present_complete_semaphore,
+            rendering_complete_semaphore,
+            in_flight_fence,
+        })
+    }
+
+    unsafe fn destroy(&self, device: &Device) {
+        device.destroy_semaphore(self.present_complete_semaphore, None);
+        device.destroy_semaphore(self.rendering_complete_semaphore, None);
+        device.destroy_fence(self.in_flight_fence, None);
+        for thread in self.threaded_command_buffers.read().unwrap().values() {
+            device.destroy_command_pool(thread.pool, None);
+        }
+        device.destroy_command_pool(self.command_pool, None);
+    }
+}
+
+/// A resource handed from another queue family to the graphics family.
+#[derive(Clone, Copy, Debug)]
+pub enum OwnershipTransfer {
+    Image {
+        image: vk::Image,
+        aspect_mask: vk::ImageAspectFlags,
+        /// The layout transition has to be identical in the release and acquire barrier.
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+    },
+    Buffer {
+        buffer: vk::Buffer,
+    },
+}
+
+impl OwnershipTransfer {
+    fn image_barrier(
+        &self,
+        src_family: u32,
+        dst_family: u32,
+    ) -> Option<vk::ImageMemoryBarrier2<'static>> {
+        match *self {
+            OwnershipTransfer::Image {
+                image,
+                aspect_mask,
+                old_layout,
+                new_layout,
+            } => Some(
+                vk::ImageMemoryBarrier2::default()
+                    .src_queue_family_index(src_family)
+                    .dst_queue_family_index(dst_family)
+                    .old_layout(old_layout)
+                    .new_layout(new_layout)
+                    .image(image)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask,
+                        level_count: vk::REMAINING_MIP_LEVELS,
+                        layer_count: vk::REMAINING_ARRAY_LAYERS,
+                        ..Default::default()
+                    }),
+            ),
+            OwnershipTransfer::Buffer { .. } => None,
+        }
+    }
+
+    fn buffer_barrier(
+        &self,
+        src_family: u32,
+        dst_family: u32,
+    ) -> Option<vk::BufferMemoryBarrier2<'static>> {
+        match *self {
+            OwnershipTransfer::Buffer { buffer } => Some(
+                vk::BufferMemoryBarrier2::default()
+                    .src_queue_family_index(src_family)
+                    .dst_queue_family_index(dst_family)
+                    .buffer(buffer)
+                    .size(vk::WHOLE_SIZE),
+            ),
+            OwnershipTransfer::Image { .. } => None,
+        }
+    }
+}
+
+/// Work released by another queue that the next graphics submit has to wait on and acquire.
+#[derive(Debug)]
+pub struct QueueHandoff {
+    /// Signaled by the submit that contains the release barriers.
+    pub released: SyncPoint,
+    pub src_family: u32,
+    pub transfers: Vec<OwnershipTransfer>,
+}
+
 pub struct ExampleBase {
     pub entry: Entry,
     pub instance: Instance,
     pub device: Device,
     pub synchronization2: Synchronization2,
     pub dynamic_rendering: DynamicRendering,
+    /// Only loaded when the renderer is built with [`RenderBackend::ShaderObject`].
+    pub shader_object: Option<ShaderObject>,
     pub surface_loader: Surface,
     pub swapchain_loader: Swapchain,
     pub debug_utils_loader: DebugUtils,
     pub debug_call_back: vk::DebugUtilsMessengerEXT,
+    /// Pointed to by `debug_call_back`, boxed so it doesn't move.
+    debug_messenger_state: Box<DebugMessengerState>,
     pub immutable_samplers: HashMap<SamplerDesc, vk::Sampler>,
+    /// Shared by every pipeline, saved to disk when the renderer is dropped.
+    pub pipeline_cache: PipelineCache,
+    pub shader_settings: ShaderCompileSettings,
+    /// Compiled SPIR-V, `None` when there's no cache directory.
+    pub shader_cache: Option<ShaderCache>,
+    /// Only there with [`RendererSettings::hot_reload`].
+    pub shader_watcher: Option<ShaderWatcher>,
     pub max_descriptor_count: u32,
     pub command_thread_pool: ThreadPool,
-    pub threaded_command_buffers: Arc<RwLock<HashMap<usize, CommandBuffer>>>,
 
     pub pdevice: vk::PhysicalDevice,
     pub device_memory_properties: vk::PhysicalDeviceMemoryProperties,
     pub queue_family_index: u32,
-    pub present_queue: vk::Queue,
+    pub present_queue: Queue,
+    pub queue_families: QueueFamilies,
+    /// Uploads, falls back to `present_queue` when there's no dedicated family.
+    pub transfer_queue: Queue,
 
+    /// Null when headless.
     pub surface: vk::SurfaceKHR,
     pub surface_format: vk::SurfaceFormatKHR,
-    pub surface_resolution: vk::Extent2D,
+    pub present_mode: vk::PresentModeKHR,
 
-    pub swapchain: vk::SwapchainKHR,
-    pub present_images: Vec<vk::Image>,
-    pub present_image_views: Vec<vk::ImageView>,
+    pub swapchain: RwLock<SwapchainManager>,
 
     pub pool: vk::CommandPool,
-    pub draw_command_buffer: vk::CommandBuffer,
     pub setup_command_buffer: vk::CommandBuffer,
 
-    pub depth_image: vk::Image,
-    pub depth_image_view: vk::ImageView,
-    pub depth_image_memory: vk::DeviceMemory,
     pub depth_image_format: vk::Format,
 
-    pub present_complete_semaphore: vk::Semaphore,
-    pub rendering_complete_semaphore: vk::Semaphore,
-
-    pub draw_commands_reuse_fence: vk::Fence,
     pub setup_commands_reuse_fence: vk::Fence,
+
+    /// Used round robin, an upload only waits when all of them are still in flight.
+    transfer_contexts: Vec<Mutex<TransferContext>>,
+    next_transfer_context: AtomicUsize,
+    queue_handoffs: Mutex<Vec<QueueHandoff>>,
+    pub timelines: QueueTimelines,
+
+    pub frames: Vec<FrameContext>,
+    frame_index: AtomicUsize,
 }
 
 impl ExampleBase {
-    pub fn new(window: &RawHandleWrapper, present_mode: PresentMode) -> Self {
+    /// Failing here leaks whatever was created before the failing call, the renderer can't be
+    /// used at all without it.
+    pub fn new(target: RenderTarget, settings: &RendererSettings) -> RenderResult<Self> {
+        let frames_in_flight = settings.frames_in_flight;
+        assert!(frames_in_flight > 0, "Need at least one frame in flight.");
         unsafe {
             let entry = Entry::linked();
             let app_name = CStr::from_bytes_with_nul_unchecked(b"VulkanTriangle\0");
 
-            let mut layer_names = vec![CStr::from_bytes_with_nul_unchecked(
-                b"VK_LAYER_KHRONOS_synchronization2\0",
-            )];
-
-            if cfg!(debug_assertions) {
-                println!("{:?}", "Debug mode: enable validation layers");
-
-                layer_names.push(CStr::from_bytes_with_nul_unchecked(
-                    b"VK_LAYER_KHRONOS_validation\0",
-                ))
-            }
+            let layer_names = settings.validation.layers(&entry)?;
+            let validation_enabled = layer_names
+                .iter()
+                .any(|layer| layer.to_bytes() == b"VK_LAYER_KHRONOS_validation");
+            let validation_features = if validation_enabled {
+                settings.validation.enabled_features()
+            } else {
+                vec![]
+            };
+            tracing::info!(
+                "Validation: {}",
+                if validation_enabled {
+                    format!("enabled, features {validation_features:?}")
+                } else {
+                    "disabled".to_string()
+                }
+            );
 
             let layers_names_raw: Vec<*const c_char> = layer_names
                 .iter()
                 .map(|raw_name| raw_name.as_ptr())
                 .collect();
 
-            let mut extension_names =
-                ash_window::enumerate_required_extensions(window.display_handle)
-                    .unwrap()
-                    .to_vec();
+            let window = match target {
+                RenderTarget::Window { handle, .. } => Some(handle),
+                RenderTarget::Headless { .. } => None,
+            };
+
+            let mut extension_names = match window {
+                Some(window) => {
+                    ash_window::enumerate_required_extensions(window.display_handle)?.to_vec()
+                }
+                None => vec![],
+            };
             extension_names.push(DebugUtils::NAME.as_ptr());
+            // provided by the validation layer
+            if !validation_features.is_empty() {
+                extension_names.push(ExtValidationFeaturesFn::NAME.as_ptr());
+            }
             #[cfg(any(target_os = "macos", target_os = "ios"))]
             {
                 extension_names.push(KhrPortabilityEnumerationFn::NAME.as_ptr());
@@ -277,83 +591,67 @@ impl ExampleBase {
                 vk::InstanceCreateFlags::default()
             };
 
-            let create_info = vk::InstanceCreateInfo::default()
+            let mut validation_features_info = vk::ValidationFeaturesEXT::default()
+                .enabled_validation_features(&validation_features);
+            let mut create_info = vk::InstanceCreateInfo::default()
                 .application_info(&appinfo)
                 .enabled_layer_names(&layers_names_raw)
                 .enabled_extension_names(&extension_names)
                 .flags(create_flags);
+            if !validation_features.is_empty() {
+                create_info = create_info.push_next(&mut validation_features_info);
+            }
 
-            let instance: Instance = entry
-                .create_instance(&create_info, None)
-                .expect("Instance creation error");
-
-            let debug_info = vk::DebugUtilsMessengerCreateInfoEXT::default()
-                .message_severity(
-                    vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
-                        | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
-                        | vk::DebugUtilsMessageSeverityFlagsEXT::INFO,
-                )
-                .message_type(
-                    vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
-                        | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
-                        | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
-                )
-                .pfn_user_callback(Some(vulkan_debug_callback));
+            let instance: Instance = entry.create_instance(&create_info, None)?;
 
+            let debug_messenger_state = Box::new(DebugMessengerState::new(&settings.validation));
             let debug_utils_loader = DebugUtils::new(&entry, &instance);
             let debug_call_back = debug_utils_loader
-                .create_debug_utils_messenger(&debug_info, None)
-                .unwrap();
-            let surface = ash_window::create_surface(
-                &entry,
-                &instance,
-                window.get_display_handle(),
-                window.get_window_handle(),
-                None,
-            )
-            .unwrap();
-            let pdevices = instance
-                .enumerate_physical_devices()
-                .expect("Physical device error");
+                .create_debug_utils_messenger(&debug_messenger_state.create_info(), None)?;
+            let surface = match window {
+                Some(window) => ash_window::create_surface(
+                    &entry,
+                    &instance,
+                    window.get_display_handle(),
+                    window.get_window_handle(),
+                    None,
+                )?,
+                None => vk::SurfaceKHR::null(),
+            };
             let surface_loader = Surface::new(&entry, &instance);
-            let (pdevice, queue_family_index) = pdevices
-                .iter()
-                .find_map(|pdevice| {
-                    instance
-                        .get_physical_device_queue_family_properties(*pdevice)
-                        .iter()
-                        .enumerate()
-                        .find_map(|(index, info)| {
-                            let supports_graphic_and_surface =
-                                info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                                    && surface_loader
-                                        .get_physical_device_surface_support(
-                                            *pdevice,
-                                            index as u32,
-                                            surface,
-                                        )
-                                        .unwrap();
-                            if supports_graphic_and_surface {
-                                Some((*pdevice, index))
-                            } else {
-                                None
-                            }
-                        })
-                })
-                .expect("Couldn't find suitable device.");
-
-            let device_properties = instance.get_physical_device_properties(pdevice);
-            let queue_family_index = queue_family_index as u32;
-            let device_extension_names_raw = [
-                Swapchain::NAME.as_ptr(),
-                DynamicRendering::NAME.as_ptr(),
-                Synchronization2::NAME.as_ptr(),
-                ExtDescriptorIndexingFn::NAME.as_ptr(),
+            let use_shader_object = settings.backend == RenderBackend::ShaderObject;
+            let mut device_extension_names = vec![
+                DynamicRendering::NAME,
+                Synchronization2::NAME,
+                ExtDescriptorIndexingFn::NAME,
                 #[cfg(any(target_os = "macos", target_os = "ios"))]
-                KhrPortabilitySubsetFn::NAME.as_ptr(),
+                KhrPortabilitySubsetFn::NAME,
                 #[cfg(any(target_os = "macos", target_os = "ios"))]
-                KhrGetMemoryRequirements2Fn::NAME.as_ptr(),
+                KhrGetMemoryRequirements2Fn::NAME,
             ];
+            if window.is_some() {
+                device_extension_names.push(Swapchain::NAME);
+            }
+            if use_shader_object {
+                device_extension_names.push(ShaderObject::NAME);
+            }
+            let device_extension_names_raw: Vec<*const c_char> = device_extension_names
+                .iter()
+                .map(|name| name.as_ptr())
+                .collect();
+
+            let (selected_device, queue_family_index) = select_physical_device(
+                &instance,
+                &DeviceRequirements {
+                    extensions: &device_extension_names,
+                    surface: window.map(|_| (&surface_loader, surface)),
+                    shader_object: use_shader_object,
+                },
+                &settings.device,
+            )?;
+            let pdevice = selected_device.pdevice;
+            let queue_families = QueueFamilies::discover(&instance, pdevice, queue_family_index);
+            let device_properties = instance.get_physical_device_properties(pdevice);
             let features = vk::PhysicalDeviceFeatures {
                 shader_clip_distance: 1,
                 sampler_anisotropy: 1,
@@ -384,45 +682,348 @@ impl ExampleBase {
                 .shader_storage_texel_buffer_array_dynamic_indexing(true)
                 .shader_uniform_texel_buffer_array_dynamic_indexing(true);
 
-            let queue_info = vk::DeviceQueueCreateInfo::default()
-                .queue_family_index(queue_family_index)
-                .queue_priorities(&priorities);
+            let mut timeline_semaphore_features =
+                vk::PhysicalDeviceTimelineSemaphoreFeatures::default().timeline_semaphore(true);
+
+            let mut shader_object_features =
+                vk::PhysicalDeviceShaderObjectFeaturesEXT::default().shader_object(true);
+
+            let queue_infos = queue_families
+                .unique()
+                .into_iter()
+                .map(|family_index| {
+                    vk::DeviceQueueCreateInfo::default()
+                        .queue_family_index(family_index)
+                        .queue_priorities(&priorities)
+                })
+                .collect::<Vec<_>>();
 
-            let device_create_info = vk::DeviceCreateInfo::default()
-                .queue_create_infos(std::slice::from_ref(&queue_info))
+            let mut device_create_info = vk::DeviceCreateInfo::default()
+                .queue_create_infos(&queue_infos)
                 .enabled_extension_names(&device_extension_names_raw)
                 .enabled_features(&features)
                 .push_next(&mut dynamic_rendering_features)
                 .push_next(&mut synchronization2_features)
                 .push_next(&mut buffer_features)
-                .push_next(&mut indexing_features);
+                .push_next(&mut indexing_features)
+                .push_next(&mut timeline_semaphore_features);
+            if use_shader_object {
+                device_create_info = device_create_info.push_next(&mut shader_object_features);
+            }
+
+            let device: Device = instance.create_device(pdevice, &device_create_info, None)?;
+
+            let present_queue = Queue::new(device.get_device_queue(queue_family_index, 0));
+            // the same queue as `present_queue` when the family is shared
+            let transfer_queue = Queue::aliasing(
+                device.get_device_queue(queue_families.transfer, 0),
+                &present_queue,
+            );
+
+            let (surface_format, present_mode, window_extent) = match target {
+                RenderTarget::Window {
+                    extent,
+                    present_mode,
+                    ..
+                } => {
+                    let surface_format =
+                        surface_loader.get_physical_device_surface_formats(pdevice, surface)?[0];
+
+                    let present_modes = surface_loader
+                        .get_physical_device_surface_present_modes(pdevice, surface)?;
+
+                    let present_mode = select_present_mode(present_mode, &present_modes)?;
+                    (surface_format, present_mode, extent)
+                }
+                // RGBA so the offscreen image can be read back without swizzling, sRGB so it's
+                // encoded like the swapchain images surfaces usually have
+                RenderTarget::Headless { extent } => (
+                    vk::SurfaceFormatKHR {
+                        format: vk::Format::R8G8B8A8_SRGB,
+                        color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+                    },
+                    vk::PresentModeKHR::FIFO,
+                    extent,
+                ),
+            };
+            let swapchain_loader = Swapchain::new(&instance, &device);
+
+            let pool_create_info = vk::CommandPoolCreateInfo::default()
+                .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+                .queue_family_index(queue_family_index);
+
+            let pool = device.create_command_pool(&pool_create_info, None)?;
+
+            let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::default()
+                .command_buffer_count(1)
+                .command_pool(pool)
+                .level(vk::CommandBufferLevel::PRIMARY);
+
+            let setup_command_buffer =
+                device.allocate_command_buffers(&command_buffer_allocate_info)?[0];
+
+            let transfer_contexts = (0..TRANSFER_CONTEXT_COUNT)
+                .map(|_| TransferContext::new(&device, queue_families.transfer).map(Mutex::new))
+                .collect::<RenderResult<Vec<_>>>()?;
+
+            let device_memory_properties = instance.get_physical_device_memory_properties(pdevice);
+
+            let fence_create_info =
+                vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED);
+
+            let setup_commands_reuse_fence = device.create_fence(&fence_create_info, None)?;
+
+            let immutable_samplers = Self::create_samplers(&device)?;
+            let (command_thread_pool, threaded_command_buffers) = Self::create_command_thread_pool(
+                device.clone(),
+                debug_utils_loader.clone(),
+                queue_family_index,
+                frames_in_flight,
+            );
+            let frames = threaded_command_buffers
+                .into_iter()
+                .map(|threaded_command_buffers| {
+                    FrameContext::new(&device, queue_family_index, threaded_command_buffers)
+                })
+                .collect::<RenderResult<_>>()?;
+
+            let synchronization2 = Synchronization2::new(&instance, &device);
+            let dynamic_rendering = DynamicRendering::new(&instance, &device);
+            let shader_object = use_shader_object.then(|| ShaderObject::new(&instance, &device));
+
+            let timelines = QueueTimelines::new(&device)?;
+            let pipeline_cache =
+                PipelineCache::load(&instance, pdevice, &device, settings.cache_dir.as_deref())?;
+            let shader_cache = settings.cache_dir.as_deref().map(ShaderCache::new);
+            let shader_watcher = if settings.hot_reload && cfg!(feature = "shader-compiler") {
+                ShaderWatcher::new(
+                    Path::new("shader"),
+                    settings.shaders.clone(),
+                    shader_cache.clone(),
+                )
+                .map_err(|err| tracing::warn!("Shader hot reloading is disabled: {err}"))
+                .ok()
+            } else {
+                None
+            };
+
+            let base = ExampleBase {
+                entry,
+                instance,
+                device,
+                synchronization2,
+                dynamic_rendering,
+                shader_object,
+                queue_family_index,
+                queue_families,
+                transfer_queue,
+                pdevice,
+                immutable_samplers,
+                pipeline_cache,
+                shader_settings: settings.shaders.clone(),
+                shader_cache,
+                shader_watcher,
+                command_thread_pool,
+                // TODO: fetch from device
+                max_descriptor_count: {
+                    (512 * 1024).min(
+                    device_properties
+                        .limits
+                        .max_per_stage_descriptor_sampled_images // https://github.com/KhronosGroup/MoltenVK/issues/394 - prob just use 16 samplers and then bind them instead of COMBINED_IMAGE_SAMPLERS
+                        - RESERVED_DESCRIPTOR_COUNT,
+                    )                     
+                },
+                device_memory_properties,
+                surface_loader,
+                surface_format,
+                present_mode,
+                present_queue,
+                swapchain_loader,
+                swapchain: RwLock::new(SwapchainManager {
+                    window_extent,
+                    ..Default::default()
+                }),
+                pool,
+                setup_command_buffer,
+                depth_image_format: vk::Format::D16_UNORM,
+                setup_commands_reuse_fence,
+                transfer_contexts,
+                next_transfer_context: AtomicUsize::new(0),
+                queue_handoffs: Mutex::new(vec![]),
+                timelines,
+                frames,
+                frame_index: AtomicUsize::new(0),
+                surface,
+                debug_call_back,
+                debug_messenger_state,
+                debug_utils_loader,
+            };
+            base.name_objects();
+            if base.is_headless() {
+                base.create_offscreen_target()?;
+            } else {
+                base.recreate_swapchain()?;
+            }
+
+            Ok(base)
+        }
+    }
+
+    /// Names the objects created in [`ExampleBase::new`]. Swapchain images are named when
+    /// they're created, the secondary command buffers by the thread that allocates them.
+    fn name_objects(&self) {
+        self.set_object_name(self.pool, "setup");
+        self.set_object_name(self.setup_command_buffer, "setup");
+        self.set_object_name(self.setup_commands_reuse_fence, "setup");
+        for (index, transfer) in self.transfer_contexts.iter().enumerate() {
+            let transfer = transfer.lock().unwrap();
+            self.set_object_name(transfer.command_pool, &format!("transfer {index}"));
+            self.set_object_name(transfer.command_buffer, &format!("transfer {index}"));
+            self.set_object_name(transfer.reuse_fence, &format!("transfer {index}"));
+        }
+        self.set_object_name(self.timelines.graphics.semaphore, "graphics timeline");
+        self.set_object_name(self.timelines.transfer.semaphore, "transfer timeline");
+        self.set_object_name(self.pipeline_cache.cache, "pipeline cache");
+        for (index, frame) in self.frames.iter().enumerate() {
+            self.set_object_name(frame.command_pool, &format!("frame {index}"));
+            self.set_object_name(frame.command_buffer, &format!("frame {index}"));
+            self.set_object_name(
+                frame.present_complete_semaphore,
+                &format!("frame {index} present complete"),
+            );
+            self.set_object_name(
+                frame.rendering_complete_semaphore,
+                &format!("frame {index} rendering complete"),
+            );
+            self.set_object_name(frame.in_flight_fence, &format!("frame {index} in flight"));
+        }
+        for (desc, sampler) in &self.immutable_samplers {
+            self.set_object_name(*sampler, &format!("{desc:?}"));
+        }
+    }
+
+    pub fn set_object_name(&self, handle: impl vk::Handle, name: &str) {
+        set_object_name(&self.debug_utils_loader, &self.device, handle, name);
+    }
+
+    /// Opens a labeled region in `command_buffer` for capture tools, close it with
+    /// [`ExampleBase::cmd_end_label`].
+    pub fn cmd_begin_label(&self, command_buffer: vk::CommandBuffer, label: &str) {
+        let Ok(label) = CString::new(label) else {
+            return;
+        };
+        unsafe {
+            self.debug_utils_loader.cmd_begin_debug_utils_label(
+                command_buffer,
+                &vk::DebugUtilsLabelEXT::default().label_name(&label),
+            );
+        }
+    }
+
+    pub fn cmd_end_label(&self, command_buffer: vk::CommandBuffer) {
+        unsafe {
+            self.debug_utils_loader
+                .cmd_end_debug_utils_label(command_buffer)
+        };
+    }
+
+    /// Index into [`ExampleBase::frames`] of the frame that's currently being recorded.
+    pub fn frame_index(&self) -> usize {
+        self.frame_index.load(Ordering::Acquire)
+    }
 
-            let device: Device = instance
-                .create_device(pdevice, &device_create_info, None)
-                .unwrap();
+    pub fn current_frame(&self) -> &FrameContext {
+        &self.frames[self.frame_index()]
+    }
 
-            let present_queue = device.get_device_queue(queue_family_index, 0);
+    /// Waits until the GPU is done with the resources of the current frame, after this it's safe
+    /// to overwrite its command buffers and per-frame buffers.
+    pub fn begin_frame(&self) -> RenderResult<()> {
+        unsafe {
+            self.device.wait_for_fences(
+                &[self.current_frame().in_flight_fence],
+                true,
+                std::u64::MAX,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Panics if validation errors were reported and the renderer was set up to panic on them.
+    pub fn check_validation(&self) {
+        self.debug_messenger_state.check();
+    }
+
+    /// Moves on to the next frame in the ring.
+    pub fn end_frame(&self) {
+        let next = (self.frame_index() + 1) % self.frames.len();
+        self.frame_index.store(next, Ordering::Release);
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.surface == vk::SurfaceKHR::null()
+    }
+
+    /// The layout the color target is left in at the end of a frame.
+    pub fn present_layout(&self) -> vk::ImageLayout {
+        if self.is_headless() {
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL
+        } else {
+            vk::ImageLayout::PRESENT_SRC_KHR
+        }
+    }
+
+    pub fn surface_resolution(&self) -> vk::Extent2D {
+        self.swapchain.read().unwrap().surface_resolution
+    }
+
+    /// Records the new physical window size, the swapchain is rebuilt before the next acquire.
+    pub fn resize(&self, window_extent: vk::Extent2D) {
+        let mut swapchain = self.swapchain.write().unwrap();
+        if swapchain.window_extent != window_extent {
+            swapchain.window_extent = window_extent;
+            swapchain.needs_recreate = true;
+        }
+    }
+
+    /// Waits for the device to go idle and rebuilds the swapchain, its image views and the depth buffer.
+    ///
+    /// When the window is minimized the old swapchain is kept around and `needs_recreate` stays set,
+    /// so the next acquire tries again.
+    pub fn recreate_swapchain(&self) -> RenderResult<()> {
+        let mut swapchain = self.swapchain.write().unwrap();
+        unsafe {
+            self.wait_idle()?;
+
+            let surface_capabilities = self
+                .surface_loader
+                .get_physical_device_surface_capabilities(self.pdevice, self.surface)?;
+            let surface_resolution = match surface_capabilities.current_extent.width {
+                std::u32::MAX => vk::Extent2D {
+                    width: swapchain.window_extent.width.clamp(
+                        surface_capabilities.min_image_extent.width,
+                        surface_capabilities.max_image_extent.width,
+                    ),
+                    height: swapchain.window_extent.height.clamp(
+                        surface_capabilities.min_image_extent.height,
+                        surface_capabilities.max_image_extent.height,
+                    ),
+                },
+                _ => surface_capabilities.current_extent,
+            };
 
-            let surface_format = surface_loader
-                .get_physical_device_surface_formats(pdevice, surface)
-                .unwrap()[0];
+            if surface_resolution.width == 0 || surface_resolution.height == 0 {
+                swapchain.surface_resolution = surface_resolution;
+                swapchain.needs_recreate = true;
+                return Ok(());
+            }
 
-            let surface_capabilities = surface_loader
-                .get_physical_device_surface_capabilities(pdevice, surface)
-                .unwrap();
             let mut desired_image_count = surface_capabilities.min_image_count + 1;
             if surface_capabilities.max_image_count > 0
                 && desired_image_count > surface_capabilities.max_image_count
             {
                 desired_image_count = surface_capabilities.max_image_count;
             }
-            let surface_resolution = match surface_capabilities.current_extent.width {
-                // std::u32::MAX => vk::Extent2D {
-                //     width: 1280,
-                //     height: 720,
-                // },
-                _ => surface_capabilities.current_extent,
-            };
             let pre_transform = if surface_capabilities
                 .supported_transforms
                 .contains(vk::SurfaceTransformFlagsKHR::IDENTITY)
@@ -431,65 +1032,47 @@ impl ExampleBase {
             } else {
                 surface_capabilities.current_transform
             };
-            let present_modes = surface_loader
-                .get_physical_device_surface_present_modes(pdevice, surface)
-                .unwrap();
-
-            let present_mode = match present_mode {
-                PresentMode::Fifo => vk::PresentModeKHR::FIFO,
-                PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
-                PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
-                PresentMode::AutoNoVsync => vk::PresentModeKHR::IMMEDIATE,
-                PresentMode::AutoVsync => vk::PresentModeKHR::FIFO_RELAXED,
-            };
-            assert!(
-                present_modes.contains(&present_mode),
-                "Present mode not supported."
-            );
-            let swapchain_loader = Swapchain::new(&instance, &device);
 
+            // screenshots copy straight out of the present image
+            let image_usage = vk::ImageUsageFlags::COLOR_ATTACHMENT
+                | (surface_capabilities.supported_usage_flags & vk::ImageUsageFlags::TRANSFER_SRC);
+
+            let old_swapchain = swapchain.swapchain;
+            let window_extent = swapchain.window_extent;
             let swapchain_create_info = vk::SwapchainCreateInfoKHR::default()
-                .surface(surface)
+                .surface(self.surface)
                 .min_image_count(desired_image_count)
-                .image_color_space(surface_format.color_space)
-                .image_format(surface_format.format)
+                .image_color_space(self.surface_format.color_space)
+                .image_format(self.surface_format.format)
                 .image_extent(surface_resolution)
-                .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+                .image_usage(image_usage)
                 .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
                 .pre_transform(pre_transform)
                 .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-                .present_mode(present_mode)
+                .present_mode(self.present_mode)
                 .clipped(true)
-                .image_array_layers(1);
+                .image_array_layers(1)
+                .old_swapchain(old_swapchain);
 
-            let swapchain = swapchain_loader
-                .create_swapchain(&swapchain_create_info, None)
-                .unwrap();
+            let new_swapchain = self
+                .swapchain_loader
+                .create_swapchain(&swapchain_create_info, None)?;
 
-            let pool_create_info = vk::CommandPoolCreateInfo::default()
-                .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
-                .queue_family_index(queue_family_index);
+            // the old swapchain is retired by the create call above and can go now that the device is idle
+            swapchain.destroy(&self.device, &self.swapchain_loader);
 
-            let pool = device.create_command_pool(&pool_create_info, None).unwrap();
+            // the new swapchain is the current one from here on, if the rest fails it's rebuilt
+            // again before the next acquire
+            swapchain.swapchain = new_swapchain;
+            swapchain.needs_recreate = true;
 
-            let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::default()
-                .command_buffer_count(2)
-                .command_pool(pool)
-                .level(vk::CommandBufferLevel::PRIMARY);
-
-            let command_buffers = device
-                .allocate_command_buffers(&command_buffer_allocate_info)
-                .unwrap();
-            let setup_command_buffer = command_buffers[0];
-            let draw_command_buffer = command_buffers[1];
-
-            let present_images = swapchain_loader.get_swapchain_images(swapchain).unwrap();
-            let present_image_views: Vec<vk::ImageView> = present_images
+            let present_images = self.swapchain_loader.get_swapchain_images(new_swapchain)?;
+            let present_image_views = present_images
                 .iter()
                 .map(|&image| {
                     let create_view_info = vk::ImageViewCreateInfo::default()
                         .view_type(vk::ImageViewType::TYPE_2D)
-                        .format(surface_format.format)
+                        .format(self.surface_format.format)
                         .components(vk::ComponentMapping {
                             r: vk::ComponentSwizzle::R,
                             g: vk::ComponentSwizzle::G,
@@ -504,169 +1087,323 @@ impl ExampleBase {
                             layer_count: 1,
                         })
                         .image(image);
-                    device.create_image_view(&create_view_info, None).unwrap()
+                    self.device.create_image_view(&create_view_info, None)
                 })
-                .collect();
-            let device_memory_properties = instance.get_physical_device_memory_properties(pdevice);
-            let depth_image_create_info = vk::ImageCreateInfo::default()
-                .image_type(vk::ImageType::TYPE_2D)
-                .format(vk::Format::D16_UNORM)
-                .extent(surface_resolution.into())
-                .mip_levels(1)
-                .array_layers(1)
-                .samples(vk::SampleCountFlags::TYPE_1)
-                .tiling(vk::ImageTiling::OPTIMAL)
-                .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
-                .sharing_mode(vk::SharingMode::EXCLUSIVE);
-
-            let depth_image = device.create_image(&depth_image_create_info, None).unwrap();
-            let depth_image_memory_req = device.get_image_memory_requirements(depth_image);
-            let depth_image_memory_index = find_memorytype_index(
-                &depth_image_memory_req,
-                &device_memory_properties,
-                vk::MemoryPropertyFlags::DEVICE_LOCAL,
-            )
-            .expect("Unable to find suitable memory index for depth image.");
+                .collect::<Result<Vec<_>, _>>()?;
+            self.set_object_name(new_swapchain, "swapchain");
+            for (index, (image, view)) in
+                present_images.iter().zip(&present_image_views).enumerate()
+            {
+                self.set_object_name(*image, &format!("swapchain image {index}"));
+                self.set_object_name(*view, &format!("swapchain image {index}"));
+            }
 
-            let depth_image_allocate_info = vk::MemoryAllocateInfo::default()
-                .allocation_size(depth_image_memory_req.size)
-                .memory_type_index(depth_image_memory_index);
+            let (depth_image, depth_image_view, depth_image_memory) = self.create_attachment(
+                "depth",
+                self.depth_image_format,
+                surface_resolution,
+                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                vk::ImageAspectFlags::DEPTH,
+                vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            )?;
 
-            let depth_image_memory = device
-                .allocate_memory(&depth_image_allocate_info, None)
-                .unwrap();
+            *swapchain = SwapchainManager {
+                swapchain: new_swapchain,
+                present_images,
+                present_image_views,
+                surface_resolution,
+                image_usage,
+                depth_image,
+                depth_image_view,
+                depth_image_memory,
+                window_extent,
+                needs_recreate: false,
+                ..Default::default()
+            };
+        }
+        Ok(())
+    }
 
-            device
-                .bind_image_memory(depth_image, depth_image_memory, 0)
-                .expect("Unable to bind depth image memory");
+    /// Headless counterpart of [`ExampleBase::recreate_swapchain`], creates the offscreen color
+    /// image and depth buffer at the requested size.
+    fn create_offscreen_target(&self) -> RenderResult<()> {
+        let mut swapchain = self.swapchain.write().unwrap();
+        let extent = swapchain.window_extent;
+        unsafe {
+            let image_usage =
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC;
+            let (color_image, color_image_view, color_image_memory) = self.create_attachment(
+                "offscreen color",
+                self.surface_format.format,
+                extent,
+                image_usage,
+                vk::ImageAspectFlags::COLOR,
+                self.present_layout(),
+            )?;
+            let (depth_image, depth_image_view, depth_image_memory) = self.create_attachment(
+                "depth",
+                self.depth_image_format,
+                extent,
+                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                vk::ImageAspectFlags::DEPTH,
+                vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            )?;
+
+            *swapchain = SwapchainManager {
+                present_images: vec![color_image],
+                present_image_views: vec![color_image_view],
+                surface_resolution: extent,
+                image_usage,
+                offscreen_image_memory: color_image_memory,
+                depth_image,
+                depth_image_view,
+                depth_image_memory,
+                window_extent: extent,
+                ..Default::default()
+            };
+        }
+        Ok(())
+    }
 
-            let fence_create_info =
-                vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED);
+    /// Creates a 2D image in its own device local allocation, transitions it to `layout` and
+    /// creates a view for it.
+    unsafe fn create_attachment(
+        &self,
+        name: &str,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        usage: vk::ImageUsageFlags,
+        aspect_mask: vk::ImageAspectFlags,
+        layout: vk::ImageLayout,
+    ) -> RenderResult<(vk::Image, vk::ImageView, vk::DeviceMemory)> {
+        let image_create_info = vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(extent.into())
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+        let image = self.device.create_image(&image_create_info, None)?;
+        self.set_object_name(image, name);
+        let image_memory_req = self.device.get_image_memory_requirements(image);
+        let image_memory_index = find_memorytype_index(
+            &image_memory_req,
+            &self.device_memory_properties,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )
+        .ok_or(RenderError::OutOfMemory(MemoryKind::Device))?;
+
+        let image_allocate_info = vk::MemoryAllocateInfo::default()
+            .allocation_size(image_memory_req.size)
+            .memory_type_index(image_memory_index);
+
+        let image_memory = self.device.allocate_memory(&image_allocate_info, None)?;
+
+        self.device.bind_image_memory(image, image_memory, 0)?;
+
+        record_submit_commandbuffer(
+            &self.device,
+            self.setup_command_buffer,
+            self.setup_commands_reuse_fence,
+            &self.present_queue,
+            &SubmitSemaphores::default(),
+            |device, setup_command_buffer| {
+                let layout_transition_barriers = vk::ImageMemoryBarrier::default()
+                    .image(image)
+                    .dst_access_mask(vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE)
+                    .new_layout(layout)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .subresource_range(
+                        vk::ImageSubresourceRange::default()
+                            .aspect_mask(aspect_mask)
+                            .layer_count(1)
+                            .level_count(1),
+                    );
 
-            let draw_commands_reuse_fence = device
-                .create_fence(&fence_create_info, None)
-                .expect("Create fence failed.");
-            let setup_commands_reuse_fence = device
-                .create_fence(&fence_create_info, None)
-                .expect("Create fence failed.");
+                device.cmd_pipeline_barrier(
+                    setup_command_buffer,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::PipelineStageFlags::ALL_COMMANDS,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[layout_transition_barriers],
+                );
+                Ok(())
+            },
+        )?;
+        self.wait_for_setup_commands()?;
+
+        let image_view_info = vk::ImageViewCreateInfo::default()
+            .subresource_range(
+                vk::ImageSubresourceRange::default()
+                    .aspect_mask(aspect_mask)
+                    .level_count(1)
+                    .layer_count(1),
+            )
+            .image(image)
+            .format(format)
+            .view_type(vk::ImageViewType::TYPE_2D);
 
-            record_submit_commandbuffer(
-                &device,
-                setup_command_buffer,
-                setup_commands_reuse_fence,
-                present_queue,
-                &[],
-                &[],
-                &[],
-                |device, setup_command_buffer| {
-                    let layout_transition_barriers = vk::ImageMemoryBarrier::default()
-                        .image(depth_image)
-                        .dst_access_mask(
-                            vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
-                                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
-                        )
-                        .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-                        .old_layout(vk::ImageLayout::UNDEFINED)
-                        .subresource_range(
-                            vk::ImageSubresourceRange::default()
-                                .aspect_mask(vk::ImageAspectFlags::DEPTH)
-                                .layer_count(1)
-                                .level_count(1),
-                        );
+        let image_view = self.device.create_image_view(&image_view_info, None)?;
+        self.set_object_name(image_view, name);
 
-                    device.cmd_pipeline_barrier(
-                        setup_command_buffer,
-                        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-                        vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
-                        vk::DependencyFlags::empty(),
-                        &[],
-                        &[],
-                        &[layout_transition_barriers],
-                    );
-                },
-            );
+        Ok((image, image_view, image_memory))
+    }
 
-            let depth_image_view_info = vk::ImageViewCreateInfo::default()
-                .subresource_range(
-                    vk::ImageSubresourceRange::default()
-                        .aspect_mask(vk::ImageAspectFlags::DEPTH)
-                        .level_count(1)
-                        .layer_count(1),
-                )
-                .image(depth_image)
-                .format(depth_image_create_info.format)
-                .view_type(vk::ImageViewType::TYPE_2D);
+    /// Reads the offscreen color image of a headless renderer back as RGBA8. Waits for all frames
+    /// in flight to finish first.
+    pub fn read_offscreen_image(&self, allocator: &mut Allocator) -> RenderResult<RgbaImage> {
+        assert!(
+            self.is_headless(),
+            "Only a headless renderer has an offscreen image to read back."
+        );
+        let swapchain = self.swapchain.read().unwrap();
+        let extent = swapchain.surface_resolution;
+
+        let mut buffer = Buffer::new(
+            self,
+            allocator,
+            "offscreen readback",
+            &vk::BufferCreateInfo::default()
+                .size((extent.width * extent.height * 4) as vk::DeviceSize)
+                .usage(vk::BufferUsageFlags::TRANSFER_DST)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::GpuToCpu,
+        )?;
+
+        let copied = self.wait_idle().map_err(Into::into).and_then(|()| {
+            self.copy_image_to_buffer(
+                swapchain.present_images[0],
+                self.present_layout(),
+                extent,
+                &buffer,
+            )
+        });
+        if let Err(err) = copied {
+            buffer.destroy(&self.device, allocator);
+            return Err(err);
+        }
 
-            let depth_image_view = device
-                .create_image_view(&depth_image_view_info, None)
-                .unwrap();
+        let pixels = buffer.allocation.as_ref().unwrap().mapped_slice().unwrap()
+            [..(extent.width * extent.height * 4) as usize]
+            .to_vec();
+        buffer.destroy(&self.device, allocator);
 
-            let semaphore_create_info = vk::SemaphoreCreateInfo::default();
+        Ok(RgbaImage::from_raw(extent.width, extent.height, pixels).unwrap())
+    }
 
-            let present_complete_semaphore = device
-                .create_semaphore(&semaphore_create_info, None)
-                .unwrap();
-            let rendering_complete_semaphore = device
-                .create_semaphore(&semaphore_create_info, None)
-                .unwrap();
+    /// Acquires the next swapchain image, recreating the swapchain first if it's been flagged.
+    ///
+    /// Returns `None` when there's nothing to render to this frame, because the window is minimized
+    /// or the swapchain went out of date. `semaphore` is only signaled when an index is returned.
+    pub fn acquire_next_image(&self, semaphore: vk::Semaphore) -> RenderResult<Option<u32>> {
+        if self.is_headless() {
+            // nothing to acquire, signal the semaphore ourselves so the frame can wait on it as usual
+            self.submit_semaphores(&[], &[semaphore])?;
+            return Ok(Some(0));
+        }
 
-            let immutable_samplers = Self::create_samplers(&device);
-            let (command_thread_pool, threaded_command_buffers) =
-                Self::create_command_thread_pool(device.clone(), queue_family_index);
+        if self.swapchain.read().unwrap().needs_recreate {
+            self.recreate_swapchain()?;
+        }
 
-            let synchronization2 = Synchronization2::new(&instance, &device);
-            let dynamic_rendering = DynamicRendering::new(&instance, &device);
+        let mut swapchain = self.swapchain.write().unwrap();
+        if swapchain.needs_recreate || swapchain.is_minimized() {
+            return Ok(None);
+        }
 
-            println!("{:?}", device_properties);
+        let result = unsafe {
+            self.swapchain_loader.acquire_next_image(
+                swapchain.swapchain,
+                std::u64::MAX,
+                semaphore,
+                vk::Fence::null(),
+            )
+        };
 
-            ExampleBase {
-                entry,
-                instance,
-                device,
-                synchronization2,
-                dynamic_rendering,
-                queue_family_index,
-                pdevice,
-                immutable_samplers,
-                command_thread_pool,
-                threaded_command_buffers,
-                // TODO: fetch from device
-                max_descriptor_count: {
-                    (512 * 1024).min(
-                    device_properties
-                        .limits
-                        .max_per_stage_descriptor_sampled_images // https://github.com/KhronosGroup/MoltenVK/issues/394 - prob just use 16 samplers and then bind them instead of COMBINED_IMAGE_SAMPLERS
-                        - RESERVED_DESCRIPTOR_COUNT,
-                    )                     
-                },
-                device_memory_properties,
-                surface_loader,
-                surface_format,
-                present_queue,
-                surface_resolution,
-                swapchain_loader,
-                swapchain,
-                present_images,
-                present_image_views,
-                pool,
-                draw_command_buffer,
-                setup_command_buffer,
-                depth_image,
-                depth_image_view,
-                depth_image_format: depth_image_create_info.format,
-                present_complete_semaphore,
-                rendering_complete_semaphore,
-                draw_commands_reuse_fence,
-                setup_commands_reuse_fence,
-                surface,
-                debug_call_back,
-                debug_utils_loader,
-                depth_image_memory,
+        match result {
+            Ok((present_index, suboptimal)) => {
+                // the image is still usable, present it and rebuild afterwards
+                swapchain.needs_recreate |= suboptimal;
+                Ok(Some(present_index))
+            }
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                swapchain.needs_recreate = true;
+                Ok(None)
             }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// `device_wait_idle`, with every queue locked like it requires.
+    pub fn wait_idle(&self) -> VkResult<()> {
+        let _present = self.present_queue.lock();
+        let _transfer =
+            (!self.transfer_queue.aliases(&self.present_queue)).then(|| self.transfer_queue.lock());
+        unsafe { self.device.device_wait_idle() }
+    }
+
+    pub fn queue_present(
+        &self,
+        present_index: u32,
+        wait_semaphores: &[vk::Semaphore],
+    ) -> RenderResult<()> {
+        if self.is_headless() {
+            // the semaphores still have to be unsignaled before they're reused next frame
+            return self.submit_semaphores(wait_semaphores, &[]);
+        }
+
+        let mut swapchain = self.swapchain.write().unwrap();
+        let swapchains = [swapchain.swapchain];
+        let image_indices = [present_index];
+        let present_info = vk::PresentInfoKHR::default()
+            .wait_semaphores(wait_semaphores)
+            .swapchains(&swapchains)
+            .image_indices(&image_indices);
+
+        let result = unsafe {
+            let _queue = self.present_queue.lock();
+            self.swapchain_loader
+                .queue_present(self.present_queue.handle, &present_info)
+        };
+
+        match result {
+            Ok(false) => {}
+            Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => swapchain.needs_recreate = true,
+            Err(err) => return Err(err.into()),
+        }
+        Ok(())
+    }
+
+    /// An empty submit, used to wait on and signal semaphores when there's no swapchain to do it.
+    fn submit_semaphores(
+        &self,
+        wait_semaphores: &[vk::Semaphore],
+        signal_semaphores: &[vk::Semaphore],
+    ) -> RenderResult<()> {
+        let wait_mask = vec![vk::PipelineStageFlags::ALL_COMMANDS; wait_semaphores.len()];
+        let submit_info = vk::SubmitInfo::default()
+            .wait_semaphores(wait_semaphores)
+            .wait_dst_stage_mask(&wait_mask)
+            .signal_semaphores(signal_semaphores);
+
+        unsafe {
+            let _queue = self.present_queue.lock();
+            self.device.queue_submit(
+                self.present_queue.handle,
+                &[submit_info],
+                vk::Fence::null(),
+            )?;
         }
+        Ok(())
     }
 
-    fn create_samplers(device: &ash::Device) -> HashMap<SamplerDesc, vk::Sampler> {
+    fn create_samplers(device: &ash::Device) -> RenderResult<HashMap<SamplerDesc, vk::Sampler>> {
         let texel_filters = [vk::Filter::NEAREST, vk::Filter::LINEAR];
         let mipmap_modes = [
             vk::SamplerMipmapMode::NEAREST,
@@ -704,48 +1441,72 @@ impl ExampleBase {
                                     .anisotropy_enable(anisotropy_enable),
                                 None,
                             )
-                        }
-                        .expect("create_sampler"),
+                        }?,
                     );
                 }
             }
         }
 
-        result
+        Ok(result)
     }
 
+    fn wait_for_setup_commands(&self) -> RenderResult<()> {
+        unsafe {
+            self.device
+                .wait_for_fences(&[self.setup_commands_reuse_fence], true, std::u64::MAX)?;
+        }
+        Ok(())
+    }
+
+    /// Every thread gets its own command pool and secondary command buffer for each frame in flight,
+    /// command pools can't be used from multiple threads at once.
     pub fn create_command_thread_pool(
         device: Device,
+        debug_utils: DebugUtils,
         queue_family_index: u32,
-    ) -> (ThreadPool, Arc<RwLock<HashMap<usize, CommandBuffer>>>) {
-        let m_command_buffers: Arc<RwLock<HashMap<usize, CommandBuffer>>> =
-            Arc::new(RwLock::new(HashMap::new()));
+        frames_in_flight: usize,
+    ) -> (ThreadPool, Vec<ThreadCommandBuffers>) {
+        let m_command_buffers: Vec<ThreadCommandBuffers> = (0..frames_in_flight)
+            .map(|_| Arc::new(RwLock::new(HashMap::new())))
+            .collect();
         let m_command_buffers_clone = m_command_buffers.clone();
 
         let pool = rayon::ThreadPoolBuilder::new()
             .thread_name(|x| format!("Command buffer generation thread {}", x))
             .start_handler(move |x| {
-                let pool_create_info = vk::CommandPoolCreateInfo::default()
-                    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
-                    .queue_family_index(queue_family_index);
-
-                let pool = unsafe { device.create_command_pool(&pool_create_info, None).unwrap() };
-
-                let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::default()
-                    .command_buffer_count(1)
-                    .command_pool(pool)
-                    .level(vk::CommandBufferLevel::SECONDARY);
-
-                let command_buffers = unsafe {
-                    device
-                        .allocate_command_buffers(&command_buffer_allocate_info)
-                        .unwrap()
-                };
-
-                m_command_buffers
-                    .write()
-                    .unwrap()
-                    .insert(x, command_buffers[0]);
+                for (frame_index, frame_command_buffers) in m_command_buffers.iter().enumerate() {
+                    let pool_create_info = vk::CommandPoolCreateInfo::default()
+                        .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+                        .queue_family_index(queue_family_index);
+
+                    let pool =
+                        unsafe { device.create_command_pool(&pool_create_info, None).unwrap() };
+
+                    let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::default()
+                        .command_buffer_count(1)
+                        .command_pool(pool)
+                        .level(vk::CommandBufferLevel::SECONDARY);
+
+                    let command_buffers = unsafe {
+                        device
+                            .allocate_command_buffers(&command_buffer_allocate_info)
+                            .unwrap()
+                    };
+                    set_object_name(
+                        &debug_utils,
+                        &device,
+                        command_buffers[0],
+                        &format!("frame {frame_index} thread {x}"),
+                    );
+
+                    frame_command_buffers.write().unwrap().insert(
+                        x,
+                        ThreadCommandBuffer {
+                            pool,
+                            command_buffer: command_buffers[0],
+                        },
+                    );
+                }
             })
             .build()
             .unwrap();
@@ -768,17 +1529,40 @@ impl ExampleBase {
         })
     }
 
-    pub fn copy_buffer_to_texture(&self, buffer: &Buffer, texture: &Image) {
-        unsafe {
-            record_submit_commandbuffer(
+    /// Uploads `buffer` into the first mip of `texture` on the transfer queue and leaves it in
+    /// `SHADER_READ_ONLY_OPTIMAL`. With a dedicated transfer family the texture is released to the
+    /// graphics family and acquired by the next frame.
+    ///
+    /// Doesn't wait for the copy, `buffer` has to stay alive until the returned point is reached.
+    pub fn copy_buffer_to_texture(
+        &self,
+        buffer: &Buffer,
+        texture: &Image,
+    ) -> RenderResult<SyncPoint> {
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            layer_count: 1,
+            level_count: 1,
+            ..Default::default()
+        };
+        let transfer = OwnershipTransfer::Image {
+            image: texture.image,
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        };
+        let dedicated = self.queue_families.transfer != self.queue_families.graphics;
+        let transfer_context = self.transfer_contexts
+            [self.next_transfer_context.fetch_add(1, Ordering::Relaxed) % TRANSFER_CONTEXT_COUNT]
+            .lock()
+            .unwrap();
+
+        let released = unsafe {
+            record_commandbuffer(
                 &self.device,
-                self.setup_command_buffer,
-                self.setup_commands_reuse_fence,
-                self.present_queue,
-                &[],
-                &[],
-                &[],
-                |device, setup_command_buffer| {
+                transfer_context.command_buffer,
+                transfer_context.reuse_fence,
+                |device, transfer_command_buffer| {
                     {
                         let image_memory_barrier = vk::ImageMemoryBarrier2::default()
                             .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
@@ -788,29 +1572,17 @@ impl ExampleBase {
                             .old_layout(vk::ImageLayout::UNDEFINED)
                             .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
                             .image(texture.image)
-                            .subresource_range(vk::ImageSubresourceRange {
-                                aspect_mask: vk::ImageAspectFlags::COLOR,
-                                layer_count: 1,
-                                level_count: 1,
-                                ..Default::default()
-                            });
+                            .subresource_range(subresource_range);
 
                         let dependency_info = vk::DependencyInfo::default()
                             .image_memory_barriers(std::slice::from_ref(&image_memory_barrier));
 
                         self.synchronization2
-                            .cmd_pipeline_barrier2(setup_command_buffer, &dependency_info);
+                            .cmd_pipeline_barrier2(transfer_command_buffer, &dependency_info);
                     }
 
-                    // println!(
-                    //     "{:?} {:?} {:?}",
-                    //     buffer.size,
-                    //     texture.extent.width * texture.bytes_per_texel(),
-                    //     texture.extent.width
-                    // );
-
                     device.cmd_copy_buffer_to_image(
-                        setup_command_buffer,
+                        transfer_command_buffer,
                         buffer.buffer,
                         texture.image,
                         ImageLayout::TRANSFER_DST_OPTIMAL,
@@ -827,42 +1599,335 @@ impl ExampleBase {
                             .image_extent(texture.extent)],
                     );
 
-                    // {
+                    if dedicated {
+                        self.cmd_release_to_graphics(
+                            transfer_command_buffer,
+                            self.queue_families.transfer,
+                            std::slice::from_ref(&transfer),
+                        );
+                    } else {
+                        let image_memory_barrier = vk::ImageMemoryBarrier2::default()
+                            .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                            .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                            .dst_access_mask(vk::AccessFlags2::SHADER_READ)
+                            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                            .image(texture.image)
+                            .subresource_range(subresource_range);
 
-                    // }
+                        let dependency_info = vk::DependencyInfo::default()
+                            .image_memory_barriers(std::slice::from_ref(&image_memory_barrier));
+
+                        self.synchronization2
+                            .cmd_pipeline_barrier2(transfer_command_buffer, &dependency_info);
+                    }
+                    Ok(())
                 },
+            )?;
+
+            self.device.reset_fences(&[transfer_context.reuse_fence])?;
+            SubmitSemaphores::default().submit_signaling(
+                &self.device,
+                &self.transfer_queue,
+                &self.timelines.transfer,
+                &[transfer_context.command_buffer],
+                transfer_context.reuse_fence,
+            )?
+        };
+        drop(transfer_context);
+
+        if dedicated {
+            self.queue_handoff(QueueHandoff {
+                released,
+                src_family: self.queue_families.transfer,
+                transfers: vec![transfer],
+            });
+        }
+        Ok(released)
+    }
+
+    /// Records the release half of queue family ownership transfers from `src_family` to the
+    /// graphics family. The point the submit signals is then passed to
+    /// [`queue_handoff`](Self::queue_handoff).
+    pub unsafe fn cmd_release_to_graphics(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        src_family: u32,
+        transfers: &[OwnershipTransfer],
+    ) {
+        let dst_family = self.queue_families.graphics;
+        let image_barriers = transfers
+            .iter()
+            .filter_map(|transfer| transfer.image_barrier(src_family, dst_family))
+            .map(|barrier| {
+                barrier
+                    .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                    .src_access_mask(vk::AccessFlags2::MEMORY_WRITE)
+            })
+            .collect::<Vec<_>>();
+        let buffer_barriers = transfers
+            .iter()
+            .filter_map(|transfer| transfer.buffer_barrier(src_family, dst_family))
+            .map(|barrier| {
+                barrier
+                    .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                    .src_access_mask(vk::AccessFlags2::MEMORY_WRITE)
+            })
+            .collect::<Vec<_>>();
+
+        self.synchronization2.cmd_pipeline_barrier2(
+            command_buffer,
+            &vk::DependencyInfo::default()
+                .image_memory_barriers(&image_barriers)
+                .buffer_memory_barriers(&buffer_barriers),
+        );
+    }
+
+    /// Queues released resources to be acquired by the next graphics submit.
+    pub fn queue_handoff(&self, handoff: QueueHandoff) {
+        self.queue_handoffs.lock().unwrap().push(handoff);
+    }
+
+    /// Takes everything released to the graphics queue since the last call. The graphics submit
+    /// has to wait on their points and record [`cmd_acquire_from_queues`](Self::cmd_acquire_from_queues)
+    /// before using the resources.
+    pub fn take_queue_handoffs(&self) -> Vec<QueueHandoff> {
+        std::mem::take(&mut *self.queue_handoffs.lock().unwrap())
+    }
+
+    /// Records the acquire half of the ownership transfers in `handoffs`.
+    pub unsafe fn cmd_acquire_from_queues(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        handoffs: &[QueueHandoff],
+    ) {
+        if handoffs.is_empty() {
+            return;
+        }
+
+        let dst_family = self.queue_families.graphics;
+        let image_barriers = handoffs
+            .iter()
+            .flat_map(|handoff| {
+                handoff
+                    .transfers
+                    .iter()
+                    .filter_map(|transfer| transfer.image_barrier(handoff.src_family, dst_family))
+            })
+            .map(|barrier| {
+                barrier
+                    .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                    .dst_access_mask(vk::AccessFlags2::MEMORY_READ)
+            })
+            .collect::<Vec<_>>();
+        let buffer_barriers = handoffs
+            .iter()
+            .flat_map(|handoff| {
+                handoff
+                    .transfers
+                    .iter()
+                    .filter_map(|transfer| transfer.buffer_barrier(handoff.src_family, dst_family))
+            })
+            .map(|barrier| {
+                barrier
+                    .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                    .dst_access_mask(vk::AccessFlags2::MEMORY_READ)
+            })
+            .collect::<Vec<_>>();
+
+        self.synchronization2.cmd_pipeline_barrier2(
+            command_buffer,
+            &vk::DependencyInfo::default()
+                .image_memory_barriers(&image_barriers)
+                .buffer_memory_barriers(&buffer_barriers),
+        );
+    }
+
+    /// Copies the first mip of a color image into `buffer`, tightly packed. The image has to be in
+    /// `layout`, which it's transitioned back to afterwards. Waits for the copy to finish.
+    pub fn copy_image_to_buffer(
+        &self,
+        image: vk::Image,
+        layout: vk::ImageLayout,
+        extent: vk::Extent2D,
+        buffer: &Buffer,
+    ) -> RenderResult<()> {
+        unsafe {
+            record_submit_commandbuffer(
+                &self.device,
+                self.setup_command_buffer,
+                self.setup_commands_reuse_fence,
+                &self.present_queue,
+                &SubmitSemaphores::default(),
+                |_device, setup_command_buffer| {
+                    self.cmd_copy_image_to_buffer(
+                        setup_command_buffer,
+                        image,
+                        layout,
+                        layout,
+                        extent,
+                        buffer.buffer,
+                    );
+                    Ok(())
+                },
+            )?;
+            self.wait_for_setup_commands()
+        }
+    }
+
+    /// Records a copy of the first mip of a color image into `buffer`, tightly packed. The image
+    /// goes from `old_layout` to `TRANSFER_SRC_OPTIMAL` for the copy and ends up in `new_layout`,
+    /// the buffer can be read on the host once the submit has finished.
+    pub unsafe fn cmd_copy_image_to_buffer(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        image: vk::Image,
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+        extent: vk::Extent2D,
+        buffer: vk::Buffer,
+    ) {
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            layer_count: 1,
+            level_count: 1,
+            ..Default::default()
+        };
+
+        {
+            let image_memory_barrier = vk::ImageMemoryBarrier2::default()
+                .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                .src_access_mask(vk::AccessFlags2::MEMORY_WRITE)
+                .old_layout(old_layout)
+                .dst_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
+                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .image(image)
+                .subresource_range(subresource_range);
+
+            let dependency_info = vk::DependencyInfo::default()
+                .image_memory_barriers(std::slice::from_ref(&image_memory_barrier));
+
+            self.synchronization2
+                .cmd_pipeline_barrier2(command_buffer, &dependency_info);
+        }
+
+        self.device.cmd_copy_image_to_buffer(
+            command_buffer,
+            image,
+            ImageLayout::TRANSFER_SRC_OPTIMAL,
+            buffer,
+            &[BufferImageCopy::default()
+                .buffer_offset(0)
+                .buffer_row_length(extent.width)
+                .buffer_image_height(extent.height)
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .image_extent(extent.into())],
+        );
+
+        {
+            let image_memory_barrier = vk::ImageMemoryBarrier2::default()
+                .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                .src_access_mask(vk::AccessFlags2::TRANSFER_READ)
+                .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                .dst_access_mask(vk::AccessFlags2::empty())
+                .new_layout(new_layout)
+                .image(image)
+                .subresource_range(subresource_range);
+
+            // makes the copy visible to the host once the fence of this submit has been waited on
+            let buffer_memory_barrier = vk::BufferMemoryBarrier2::default()
+                .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                .dst_stage_mask(vk::PipelineStageFlags2::HOST)
+                .dst_access_mask(vk::AccessFlags2::HOST_READ)
+                .buffer(buffer)
+                .size(vk::WHOLE_SIZE);
+
+            let dependency_info = vk::DependencyInfo::default()
+                .image_memory_barriers(std::slice::from_ref(&image_memory_barrier))
+                .buffer_memory_barriers(std::slice::from_ref(&buffer_memory_barrier));
+
+            self.synchronization2
+                .cmd_pipeline_barrier2(command_buffer, &dependency_info);
+        }
+    }
+}
+
+/// Maps bevy's present mode to a supported Vulkan one. The `Auto` modes fall back to `FIFO`,
+/// which every surface supports, the explicit ones fail when the surface doesn't have them.
+fn select_present_mode(
+    present_mode: PresentMode,
+    supported: &[vk::PresentModeKHR],
+) -> RenderResult<vk::PresentModeKHR> {
+    let preferred: &[vk::PresentModeKHR] = match present_mode {
+        PresentMode::Fifo => &[vk::PresentModeKHR::FIFO],
+        PresentMode::Immediate => &[vk::PresentModeKHR::IMMEDIATE],
+        PresentMode::Mailbox => &[vk::PresentModeKHR::MAILBOX],
+        PresentMode::AutoNoVsync => &[vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::MAILBOX],
+        PresentMode::AutoVsync => &[vk::PresentModeKHR::FIFO_RELAXED],
+    };
+
+    if let Some(mode) = preferred.iter().find(|mode| supported.contains(mode)) {
+        return Ok(*mode);
+    }
+    match present_mode {
+        PresentMode::AutoVsync | PresentMode::AutoNoVsync => {
+            tracing::warn!(
+                "None of {preferred:?} are supported for {present_mode:?}, falling back to FIFO"
             );
+            Ok(vk::PresentModeKHR::FIFO)
         }
+        _ => Err(RenderError::PresentModeUnsupported(present_mode)),
     }
 }
 
 impl Drop for ExampleBase {
     fn drop(&mut self) {
         unsafe {
-            self.device.device_wait_idle().unwrap();
+            // a lost device is still torn down, waiting just returns right away
+            if let Err(err) = self.wait_idle() {
+                tracing::error!("Failed to wait for the device before destroying it: {err}");
+            }
 
-            self.device
-                .destroy_semaphore(self.present_complete_semaphore, None);
-            self.device
-                .destroy_semaphore(self.rendering_complete_semaphore, None);
-            self.device
-                .destroy_fence(self.draw_commands_reuse_fence, None);
+            for frame in self.frames.iter() {
+                frame.destroy(&self.device);
+            }
             self.device
                 .destroy_fence(self.setup_commands_reuse_fence, None);
-            self.device.free_memory(self.depth_image_memory, None);
-            self.device.destroy_image_view(self.depth_image_view, None);
-            self.device.destroy_image(self.depth_image, None);
-            for &image_view in self.present_image_views.iter() {
-                self.device.destroy_image_view(image_view, None);
+            for transfer in self.transfer_contexts.iter() {
+                transfer.lock().unwrap().destroy(&self.device);
             }
+            self.timelines.destroy(&self.device);
+            self.swapchain
+                .get_mut()
+                .unwrap()
+                .destroy(&self.device, &self.swapchain_loader);
             self.device.destroy_command_pool(self.pool, None);
-            self.swapchain_loader
-                .destroy_swapchain(self.swapchain, None);
+            for sampler in self.immutable_samplers.values() {
+                self.device.destroy_sampler(*sampler, None);
+            }
+            self.pipeline_cache.save(&self.device);
+            self.pipeline_cache.destroy(&self.device);
             self.device.destroy_device(None);
-            self.surface_loader.destroy_surface(self.surface, None);
+            if !self.is_headless() {
+                self.surface_loader.destroy_surface(self.surface, None);
+            }
             self.debug_utils_loader
                 .destroy_debug_utils_messenger(self.debug_call_back, None);
             self.instance.destroy_instance(None);
         }
+        // leaks and other errors during teardown count too, unless we're already unwinding from a
+        // panic, panicking again would abort
+        if !std::thread::panicking() {
+            self.debug_messenger_state.check();
+        }
     }
 }
@@ -0,0 +1,354 @@
+use std::ffi::CStr;
+use std::fmt::Write;
+
+use ash::{extensions::khr::Surface, vk, Instance};
+
+use crate::error::{RenderError, RenderResult};
+
+/// Which physical device the renderer runs on.
+///
+/// Can be overridden without recompiling through the `SOMEDAY_DEVICE` environment variable, a
+/// number picks a device by index and anything else by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DeviceSelection {
+    /// The highest scoring device that supports everything the renderer needs, discrete GPUs
+    /// are preferred over integrated ones, which are preferred over software rasterizers.
+    #[default]
+    Auto,
+    /// Index into the list of physical devices, as printed in the capability report.
+    Index(usize),
+    /// The best suitable device whose name contains this, ignoring case.
+    Name(String),
+}
+
+impl DeviceSelection {
+    pub const ENV_VAR: &'static str = "SOMEDAY_DEVICE";
+
+    /// The selection from `SOMEDAY_DEVICE` if it's set, otherwise `self`.
+    pub fn or_from_env(&self) -> DeviceSelection {
+        match std::env::var(Self::ENV_VAR) {
+            Ok(value) if !value.trim().is_empty() => match value.trim().parse::<usize>() {
+                Ok(index) => DeviceSelection::Index(index),
+                Err(_) => DeviceSelection::Name(value.trim().to_string()),
+            },
+            _ => self.clone(),
+        }
+    }
+
+    fn matches(&self, candidate: &DeviceCandidate) -> bool {
+        match self {
+            DeviceSelection::Auto => true,
+            DeviceSelection::Index(index) => candidate.index == *index,
+            DeviceSelection::Name(name) => {
+                candidate.name.to_lowercase().contains(&name.to_lowercase())
+            }
+        }
+    }
+}
+
+/// What the renderer needs from a physical device.
+pub struct DeviceRequirements<'a> {
+    pub extensions: &'a [&'a CStr],
+    /// When set the device also needs a queue family that can present to it.
+    pub surface: Option<(&'a Surface, vk::SurfaceKHR)>,
+    pub shader_object: bool,
+}
+
+/// A physical device and how well it fits the [`DeviceRequirements`].
+#[derive(Debug, Clone)]
+pub struct DeviceCandidate {
+    pub pdevice: vk::PhysicalDevice,
+    pub index: usize,
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub api_version: u32,
+    pub driver_version: u32,
+    pub device_local_memory: vk::DeviceSize,
+    /// The first queue family with graphics, and present when there's a surface.
+    pub queue_family_index: Option<u32>,
+    /// Everything the device lacks, empty when it's suitable.
+    pub missing: Vec<String>,
+}
+
+impl DeviceCandidate {
+    pub fn is_suitable(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// Higher is better. Device type dominates, then the amount of device local memory.
+    pub fn score(&self) -> u64 {
+        let type_score = match self.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+            vk::PhysicalDeviceType::CPU => 1,
+            _ => 0,
+        };
+        (type_score << 48) + (self.device_local_memory >> 20)
+    }
+
+    unsafe fn new(
+        instance: &Instance,
+        pdevice: vk::PhysicalDevice,
+        index: usize,
+        requirements: &DeviceRequirements,
+    ) -> Self {
+        let properties = instance.get_physical_device_properties(pdevice);
+        let memory_properties = instance.get_physical_device_memory_properties(pdevice);
+        let name = CStr::from_ptr(properties.device_name.as_ptr())
+            .to_string_lossy()
+            .into_owned();
+        let mut missing = vec![];
+
+        if properties.api_version < vk::API_VERSION_1_2 {
+            missing.push(format!(
+                "Vulkan 1.2 (has {}.{})",
+                vk::api_version_major(properties.api_version),
+                vk::api_version_minor(properties.api_version)
+            ));
+        }
+
+        let available_extensions = instance
+            .enumerate_device_extension_properties(pdevice)
+            .unwrap_or_default();
+        for extension in requirements.extensions {
+            let available = available_extensions
+                .iter()
+                .any(|available| CStr::from_ptr(available.extension_name.as_ptr()) == *extension);
+            if !available {
+                missing.push(extension.to_string_lossy().into_owned());
+            }
+        }
+
+        let mut dynamic_rendering = vk::PhysicalDeviceDynamicRenderingFeatures::default();
+        let mut synchronization2 = vk::PhysicalDeviceSynchronization2Features::default();
+        let mut vulkan12 = vk::PhysicalDeviceVulkan12Features::default();
+        let mut shader_object = vk::PhysicalDeviceShaderObjectFeaturesEXT::default();
+        let mut features2 = vk::PhysicalDeviceFeatures2::default()
+            .push_next(&mut dynamic_rendering)
+            .push_next(&mut synchronization2)
+            .push_next(&mut vulkan12);
+        if requirements.shader_object {
+            features2 = features2.push_next(&mut shader_object);
+        }
+        instance.get_physical_device_features2(pdevice, &mut features2);
+        let features = features2.features;
+
+        let mut required_features = vec![
+            ("samplerAnisotropy", features.sampler_anisotropy),
+            ("shaderClipDistance", features.shader_clip_distance),
+            ("dynamicRendering", dynamic_rendering.dynamic_rendering),
+            ("synchronization2", synchronization2.synchronization2),
+            ("bufferDeviceAddress", vulkan12.buffer_device_address),
+            ("timelineSemaphore", vulkan12.timeline_semaphore),
+            (
+                "descriptorBindingPartiallyBound",
+                vulkan12.descriptor_binding_partially_bound,
+            ),
+            ("runtimeDescriptorArray", vulkan12.runtime_descriptor_array),
+            (
+                "shaderSampledImageArrayNonUniformIndexing",
+                vulkan12.shader_sampled_image_array_non_uniform_indexing,
+            ),
+            (
+                "descriptorBindingSampledImageUpdateAfterBind",
+                vulkan12.descriptor_binding_sampled_image_update_after_bind,
+            ),
+            (
+                "descriptorBindingUniformBufferUpdateAfterBind",
+                vulkan12.descriptor_binding_uniform_buffer_update_after_bind,
+            ),
+            (
+                "descriptorBindingStorageBufferUpdateAfterBind",
+                vulkan12.descriptor_binding_storage_buffer_update_after_bind,
+            ),
+            (
+                "shaderInputAttachmentArrayDynamicIndexing",
+                vulkan12.shader_input_attachment_array_dynamic_indexing,
+            ),
+            (
+                "shaderStorageTexelBufferArrayDynamicIndexing",
+                vulkan12.shader_storage_texel_buffer_array_dynamic_indexing,
+            ),
+            (
+                "shaderUniformTexelBufferArrayDynamicIndexing",
+                vulkan12.shader_uniform_texel_buffer_array_dynamic_indexing,
+            ),
+        ];
+        if requirements.shader_object {
+            required_features.push(("shaderObject", shader_object.shader_object));
+        }
+        for (feature, supported) in required_features {
+            if supported == vk::FALSE {
+                missing.push(feature.to_string());
+            }
+        }
+
+        let queue_family_index = instance
+            .get_physical_device_queue_family_properties(pdevice)
+            .iter()
+            .enumerate()
+            .find(|(family_index, info)| {
+                info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                    && requirements
+                        .surface
+                        .map_or(true, |(surface_loader, surface)| {
+                            surface_loader
+                                .get_physical_device_surface_support(
+                                    pdevice,
+                                    *family_index as u32,
+                                    surface,
+                                )
+                                .unwrap_or(false)
+                        })
+            })
+            .map(|(family_index, _)| family_index as u32);
+        if queue_family_index.is_none() {
+            missing.push(if requirements.surface.is_some() {
+                "a queue family with graphics and present".to_string()
+            } else {
+                "a queue family with graphics".to_string()
+            });
+        }
+
+        let device_local_memory = memory_properties.memory_heaps
+            [..memory_properties.memory_heap_count as usize]
+            .iter()
+            .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum();
+
+        Self {
+            pdevice,
+            index,
+            name,
+            device_type: properties.device_type,
+            api_version: properties.api_version,
+            driver_version: properties.driver_version,
+            device_local_memory,
+            queue_family_index,
+            missing,
+        }
+    }
+}
+
+/// Picks the physical device to render with and logs what every device supports. Returns the
+/// device together with its graphics queue family.
+///
+/// Fails with a description of what each device is missing when none of them fit.
+pub fn select_physical_device(
+    instance: &Instance,
+    requirements: &DeviceRequirements,
+    selection: &DeviceSelection,
+) -> RenderResult<(DeviceCandidate, u32)> {
+    let selection = selection.or_from_env();
+    let candidates = unsafe {
+        instance
+            .enumerate_physical_devices()?
+            .into_iter()
+            .enumerate()
+            .map(|(index, pdevice)| DeviceCandidate::new(instance, pdevice, index, requirements))
+            .collect::<Vec<_>>()
+    };
+
+    let report = capability_report(&candidates);
+    tracing::info!("Vulkan devices:\n{report}");
+
+    let Some((candidate, queue_family_index)) = candidates
+        .iter()
+        .filter(|candidate| candidate.is_suitable() && selection.matches(candidate))
+        .filter_map(|candidate| Some((candidate, candidate.queue_family_index?)))
+        .max_by_key(|(candidate, _)| candidate.score())
+    else {
+        return Err(RenderError::NoSuitableDevice {
+            report: format!("Looking for {selection:?} among:\n{report}"),
+        });
+    };
+
+    tracing::info!(
+        "Using {} ({:?}) for rendering",
+        candidate.name,
+        candidate.device_type
+    );
+    Ok((candidate.clone(), queue_family_index))
+}
+
+fn capability_report(candidates: &[DeviceCandidate]) -> String {
+    let mut report = String::new();
+    for candidate in candidates {
+        let _ = write!(
+            report,
+            "  [{}] {} ({:?}), Vulkan {}.{}.{}, driver {:#x}, {} MiB device local memory",
+            candidate.index,
+            candidate.name,
+            candidate.device_type,
+            vk::api_version_major(candidate.api_version),
+            vk::api_version_minor(candidate.api_version),
+            vk::api_version_patch(candidate.api_version),
+            candidate.driver_version,
+            candidate.device_local_memory >> 20,
+        );
+        if candidate.is_suitable() {
+            report.push('\n');
+        } else {
+            let _ = writeln!(report, ", missing: {}", candidate.missing.join(", "));
+        }
+    }
+    if candidates.is_empty() {
+        report.push_str("  no devices found\n");
+    }
+    report
+}
+
+/// The queue family used for each kind of work. Transfer falls back to the graphics family when
+/// the device has no dedicated one. There's no async compute, compute work is recorded on the
+/// graphics queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFamilies {
+    pub graphics: u32,
+    pub transfer: u32,
+}
+
+impl QueueFamilies {
+    /// Looks for a transfer family next to the `graphics` family.
+    pub fn discover(instance: &Instance, pdevice: vk::PhysicalDevice, graphics: u32) -> Self {
+        let families = unsafe { instance.get_physical_device_queue_family_properties(pdevice) };
+        let find = |wanted: vk::QueueFlags, unwanted: vk::QueueFlags| {
+            families
+                .iter()
+                .enumerate()
+                .find(|(_, info)| {
+                    info.queue_count > 0
+                        && info.queue_flags.contains(wanted)
+                        && !info.queue_flags.intersects(unwanted)
+                        // copies of arbitrary regions need a granularity of a single texel
+                        && info.min_image_transfer_granularity
+                            == (vk::Extent3D {
+                                width: 1,
+                                height: 1,
+                                depth: 1,
+                            })
+                })
+                .map(|(index, _)| index as u32)
+        };
+
+        let transfer = find(
+            vk::QueueFlags::TRANSFER,
+            vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE,
+        )
+        .or_else(|| find(vk::QueueFlags::TRANSFER, vk::QueueFlags::GRAPHICS))
+        .unwrap_or(graphics);
+
+        let queue_families = Self { graphics, transfer };
+        tracing::info!("Using queue families {queue_families:?}");
+        queue_families
+    }
+
+    /// Every family once, a device queue gets created for each of them.
+    pub fn unique(&self) -> Vec<u32> {
+        let mut families = vec![self.graphics, self.transfer];
+        families.sort_unstable();
+        families.dedup();
+        families
+    }
+}
@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+
+use ash::vk;
+use bevy::window::PresentMode;
+use gpu_allocator::AllocationError;
+use thiserror::Error;
+
+use crate::{device::DeviceSelection, render::shader_diagnostics::ShaderDiagnostics};
+
+pub type RenderResult<T> = Result<T, RenderError>;
+
+/// Everything the renderer can recover from, or at least report, instead of panicking.
+#[derive(Error, Debug)]
+pub enum RenderError {
+    #[error("the Vulkan device was lost")]
+    DeviceLost,
+    #[error("out of {0} memory")]
+    OutOfMemory(MemoryKind),
+    #[error("the swapchain is out of date")]
+    SwapchainOutOfDate,
+    #[error("the surface was lost")]
+    SurfaceLost,
+    #[error("the surface doesn't support present mode {0:?}")]
+    PresentModeUnsupported(PresentMode),
+    #[error(
+        "no Vulkan device supports everything the renderer needs, set {} to pick another one.\n{report}",
+        DeviceSelection::ENV_VAR
+    )]
+    NoSuitableDevice { report: String },
+    #[error("failed to read shader {path:?}: {source}")]
+    ShaderSource {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("failed to compile shader {name}:\n{diagnostics}")]
+    ShaderCompilation {
+        name: String,
+        diagnostics: ShaderDiagnostics,
+    },
+    #[error("shader {name} wasn't precompiled for this variant and the `shader-compiler` feature is disabled")]
+    ShaderCompilerDisabled { name: String },
+    #[error("failed to reflect shader {name}: {message}")]
+    ShaderReflection { name: String, message: String },
+    #[error("vertex shader {name} doesn't match the vertex layout: {message}")]
+    VertexLayoutMismatch { name: String, message: String },
+    #[error("VK_EXT_shader_object isn't enabled, the renderer has to be created with RenderBackend::ShaderObject")]
+    ShaderObjectDisabled,
+    #[error("failed to allocate GPU memory: {0}")]
+    Allocation(AllocationError),
+    #[error("Vulkan call failed: {0}")]
+    Vulkan(vk::Result),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    Host,
+    Device,
+}
+
+impl std::fmt::Display for MemoryKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemoryKind::Host => f.write_str("host"),
+            MemoryKind::Device => f.write_str("device"),
+        }
+    }
+}
+
+impl RenderError {
+    /// Whether the frame can simply be skipped, everything else leaves the renderer in a state it
+    /// can't render from anymore.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, RenderError::DeviceLost | RenderError::SurfaceLost)
+    }
+}
+
+impl From<vk::Result> for RenderError {
+    fn from(result: vk::Result) -> Self {
+        match result {
+            vk::Result::ERROR_DEVICE_LOST => RenderError::DeviceLost,
+            vk::Result::ERROR_OUT_OF_HOST_MEMORY => RenderError::OutOfMemory(MemoryKind::Host),
+            vk::Result::ERROR_OUT_OF_DEVICE_MEMORY => RenderError::OutOfMemory(MemoryKind::Device),
+            vk::Result::ERROR_OUT_OF_DATE_KHR => RenderError::SwapchainOutOfDate,
+            vk::Result::ERROR_SURFACE_LOST_KHR => RenderError::SurfaceLost,
+            result => RenderError::Vulkan(result),
+        }
+    }
+}
+
+impl From<AllocationError> for RenderError {
+    fn from(error: AllocationError) -> Self {
+        match error {
+            AllocationError::OutOfMemory => RenderError::OutOfMemory(MemoryKind::Device),
+            error => RenderError::Allocation(error),
+        }
+    }
+}
@@ -5,6 +5,7 @@ use bevy::diagnostic::FrameTimeDiagnosticsPlugin;
 use bevy::diagnostic::LogDiagnosticsPlugin;
 use bevy::prelude::*;
 use bevy::window::close_on_esc;
+use bevy::window::PrimaryWindow;
 use bevy::window::WindowMode;
 use camera_controller::CameraController;
 use camera_controller::CameraControllerPlugin;
@@ -14,6 +15,7 @@ use render::bundles::MaterialMeshBundle;
 use render::material::Material;
 use render::mesh::Mesh;
 use render::primitives;
+use render::screenshot::Screenshot;
 use render::RenderPlugin;
 use std::default::Default;
 
@@ -21,8 +23,12 @@ mod buffer;
 mod camera_controller;
 mod chunky_list;
 mod ctx;
+mod device;
+mod error;
 mod passes;
 mod render;
+mod sync;
+mod validation;
 
 fn main() {
     #[cfg(feature = "tracing")]
@@ -40,7 +46,7 @@ fn main() {
                 resolution: (1280.0, 720.0).into(),
                 title: "Someday".to_string(),
                 present_mode: bevy::window::PresentMode::default(),
-                resizable: false,
+                resizable: true,
                 mode: WindowMode::Windowed,
                 ..default()
             }),
@@ -50,11 +56,42 @@ fn main() {
         .add_plugins(LogDiagnosticsPlugin::default())
         .add_plugins(FrameTimeDiagnosticsPlugin)
         .add_plugins(CameraControllerPlugin)
-        .add_systems(Update, close_on_esc)
+        .add_systems(Update, (close_on_esc, toggle_fullscreen, take_screenshot))
         .add_systems(Startup, spawn_stuff)
         .run();
 }
 
+fn toggle_fullscreen(
+    keys: Res<Input<KeyCode>>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+) {
+    if !keys.just_pressed(KeyCode::F11) {
+        return;
+    }
+    let Ok(mut window) = windows.get_single_mut() else {
+        return;
+    };
+
+    window.mode = match window.mode {
+        WindowMode::Windowed => WindowMode::BorderlessFullscreen,
+        _ => WindowMode::Windowed,
+    };
+}
+
+fn take_screenshot(keys: Res<Input<KeyCode>>, mut screenshots: EventWriter<Screenshot>) {
+    if !keys.just_pressed(KeyCode::F12) {
+        return;
+    }
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    screenshots.send(Screenshot {
+        path: format!("screenshot-{timestamp}.png").into(),
+    });
+}
+
 fn spawn_stuff(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -0,0 +1,200 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use ash::vk;
+use bevy::prelude::*;
+use gpu_allocator::MemoryLocation;
+
+use crate::{buffer::Buffer, ctx::ExampleBase, error::RenderResult};
+
+use super::{RenderAllocator, RenderInstance};
+
+/// How many of the most recently recorded passes get reported when the device is lost.
+pub const BREADCRUMB_HISTORY: usize = 32;
+
+/// A pass as it was recorded on the CPU, matched against the markers the GPU wrote.
+#[derive(Debug, Clone)]
+struct PassRecord {
+    marker: u32,
+    frame: u64,
+    frame_index: usize,
+    pass: String,
+    draws: u32,
+}
+
+/// Markers the GPU leaves between passes, so a lost device can be traced back to the pass that was
+/// executing.
+///
+/// Every pass gets its own marker. [`Breadcrumbs::begin_pass`] writes it to the started slot of
+/// the frame with `cmd_fill_buffer`, [`Breadcrumbs::end_pass`] to the finished slot once all work
+/// before it on the queue is done. The buffer is host visible, so it can still be read after the
+/// device is gone.
+#[derive(Resource)]
+pub struct Breadcrumbs {
+    /// Two markers per frame in flight, the last started and the last finished pass.
+    buffer: Buffer,
+    frame: AtomicU64,
+    next_marker: AtomicU32,
+    history: Mutex<VecDeque<PassRecord>>,
+}
+
+impl Breadcrumbs {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) -> RenderResult<Self> {
+        let slots = render_instance.0.frames.len() * 2;
+        let mut buffer = Buffer::new(
+            &render_instance.0,
+            render_allocator.allocator(),
+            "breadcrumbs",
+            &vk::BufferCreateInfo::default()
+                .size((slots * std::mem::size_of::<u32>()) as vk::DeviceSize)
+                .usage(vk::BufferUsageFlags::TRANSFER_DST)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::GpuToCpu,
+        )?;
+        buffer.copy_from_slice(&vec![0u32; slots], 0);
+
+        Ok(Self {
+            buffer,
+            frame: AtomicU64::new(0),
+            // 0 is what the slots start out with, so it can't be a marker
+            next_marker: AtomicU32::new(1),
+            history: Mutex::new(VecDeque::with_capacity(BREADCRUMB_HISTORY)),
+        })
+    }
+
+    /// Counts the frames, so the report can tell which frame a pass belonged to.
+    pub fn begin_frame(&self) {
+        self.frame.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the start of `pass` into `command_buffer`. Has to be called outside of rendering,
+    /// `cmd_fill_buffer` isn't allowed in there.
+    pub fn begin_pass(
+        &self,
+        renderer: &ExampleBase,
+        command_buffer: vk::CommandBuffer,
+        pass: &str,
+    ) -> u32 {
+        let marker = self.next_marker.fetch_add(1, Ordering::Relaxed);
+        let frame_index = renderer.frame_index();
+
+        let mut history = self.history.lock().unwrap();
+        if history.len() == BREADCRUMB_HISTORY {
+            history.pop_front();
+        }
+        history.push_back(PassRecord {
+            marker,
+            frame: self.frame.load(Ordering::Relaxed),
+            frame_index,
+            pass: pass.to_string(),
+            draws: 0,
+        });
+        drop(history);
+
+        unsafe {
+            renderer.device.cmd_fill_buffer(
+                command_buffer,
+                self.buffer.buffer,
+                Self::slot_offset(frame_index, false),
+                std::mem::size_of::<u32>() as vk::DeviceSize,
+                marker,
+            );
+        }
+        marker
+    }
+
+    /// Records the end of the pass started with `marker`, it's only written once everything
+    /// submitted before it has finished executing.
+    pub fn end_pass(
+        &self,
+        renderer: &ExampleBase,
+        command_buffer: vk::CommandBuffer,
+        marker: u32,
+        draws: u32,
+    ) {
+        if let Some(record) = self
+            .history
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .rev()
+            .find(|record| record.marker == marker)
+        {
+            record.draws = draws;
+        }
+
+        let memory_barrier = vk::MemoryBarrier2::default()
+            .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+            .src_access_mask(vk::AccessFlags2::MEMORY_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags2::CLEAR)
+            .dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE);
+        unsafe {
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default()
+                    .memory_barriers(std::slice::from_ref(&memory_barrier)),
+            );
+            renderer.device.cmd_fill_buffer(
+                command_buffer,
+                self.buffer.buffer,
+                Self::slot_offset(renderer.frame_index(), true),
+                std::mem::size_of::<u32>() as vk::DeviceSize,
+                marker,
+            );
+        }
+    }
+
+    /// Logs the most recently recorded passes and how far the GPU got with each of them. Meant to
+    /// be called once the device has been lost.
+    pub fn report(&self) {
+        let Some(slots) = self
+            .buffer
+            .allocation
+            .as_ref()
+            .and_then(|allocation| allocation.mapped_slice())
+        else {
+            error!("The breadcrumb buffer isn't mapped, can't tell which pass was executing");
+            return;
+        };
+        let slots: &[u32] = bytemuck::cast_slice(&slots[..self.buffer.size as usize]);
+
+        let history = self.history.lock().unwrap();
+        error!("The last {} recorded passes:", history.len());
+        for record in history.iter() {
+            let started = slots[record.frame_index * 2];
+            let finished = slots[record.frame_index * 2 + 1];
+            let state = if finished >= record.marker {
+                "finished"
+            } else if started >= record.marker {
+                "started but never finished"
+            } else {
+                "not started"
+            };
+            error!(
+                "  frame {} (slot {}), pass {} with {} draws: {state}",
+                record.frame, record.frame_index, record.pass, record.draws
+            );
+        }
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        self.buffer
+            .destroy(render_instance.device(), render_allocator.allocator());
+    }
+
+    fn slot_offset(frame_index: usize, finished: bool) -> vk::DeviceSize {
+        ((frame_index * 2 + finished as usize) * std::mem::size_of::<u32>()) as vk::DeviceSize
+    }
+}
@@ -0,0 +1,66 @@
+use ash::Device;
+use gpu_allocator::vulkan::Allocator;
+
+use crate::{
+    buffer::{Buffer, Image},
+    sync::SyncPoint,
+};
+
+/// A GPU resource that's no longer used by new frames but may still be read by ones in flight.
+#[derive(Debug)]
+pub enum Garbage {
+    Buffer(Buffer),
+    Image(Image),
+}
+
+impl From<Buffer> for Garbage {
+    fn from(buffer: Buffer) -> Self {
+        Garbage::Buffer(buffer)
+    }
+}
+
+impl From<Image> for Garbage {
+    fn from(image: Image) -> Self {
+        Garbage::Image(image)
+    }
+}
+
+impl Garbage {
+    fn destroy(mut self, device: &Device, allocator: &mut Allocator) {
+        match &mut self {
+            Garbage::Buffer(buffer) => buffer.destroy(device, allocator),
+            Garbage::Image(image) => image.destroy(device, allocator),
+        }
+    }
+}
+
+/// Resources waiting for the GPU to reach the point they were last used at.
+#[derive(Debug, Default)]
+pub struct DeletionQueue {
+    pending: Vec<(SyncPoint, Garbage)>,
+}
+
+impl DeletionQueue {
+    pub fn push(&mut self, point: SyncPoint, garbage: Garbage) {
+        self.pending.push((point, garbage));
+    }
+
+    /// Frees everything whose point the GPU has passed.
+    pub fn collect(&mut self, device: &Device, allocator: &mut Allocator) {
+        let (complete, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(point, _)| point.is_complete(device));
+        self.pending = pending;
+
+        for (_, garbage) in complete {
+            garbage.destroy(device, allocator);
+        }
+    }
+
+    /// Frees everything regardless of the GPU, only call this once the device is idle.
+    pub fn flush(&mut self, device: &Device, allocator: &mut Allocator) {
+        for (_, garbage) in self.pending.drain(..) {
+            garbage.destroy(device, allocator);
+        }
+    }
+}
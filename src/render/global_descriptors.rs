@@ -3,7 +3,11 @@ use std::collections::{BTreeMap, HashMap};
 use ash::vk::{self, ShaderStageFlags};
 use bevy::{asset::HandleId, prelude::*};
 
-use super::RenderInstance;
+use gpu_allocator::MemoryLocation;
+
+use crate::error::RenderResult;
+
+use super::{CameraBuffer, RenderAllocator, RenderInstance};
 
 #[derive(Resource)]
 pub struct GlobalDescriptorSet {
@@ -11,7 +15,19 @@ pub struct GlobalDescriptorSet {
     // pub descriptor_sets: Vec<vk::DescriptorSet>,
     // set_layout_info: Vec<HashMap<u32, vk::DescriptorType>>,
     pub textures: BTreeMap<Handle<super::image::Image>, crate::buffer::Image>,
+    /// The array element of each texture. A texture keeps its slot until it's removed, materials
+    /// store the index.
+    texture_slots: HashMap<Handle<super::image::Image>, u32>,
+    /// Slots of removed textures, they point at `default_texture` until they're reused.
+    free_slots: Vec<u32>,
+    next_slot: u32,
+    /// A white pixel, so stale indices still sample a valid image.
+    default_texture: crate::buffer::Image,
+    /// Bumped whenever the textures change, see [`Self::generation`].
+    generation: u64,
     pub buffers: BTreeMap<HandleId, crate::buffer::Buffer>,
+    /// One camera uniform buffer per frame in flight, indexed by [`ExampleBase::frame_index`](crate::ctx::ExampleBase::frame_index).
+    pub camera_buffers: Vec<crate::buffer::Buffer>,
     image_infos: HashMap<Handle<super::image::Image>, Vec<vk::DescriptorImageInfo>>,
     buffer_infos: HashMap<HandleId, Vec<vk::DescriptorBufferInfo>>,
 }
@@ -20,7 +36,10 @@ impl GlobalDescriptorSet {
     /**
      * binding 0: image with sampler
      */
-    pub fn new(render_instance: &RenderInstance) -> Self {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) -> RenderResult<Self> {
         // TODO: Get device maximum
         // const DESCRIPTOR_COUNT: u32 = 1024;
         // let bindings = &[
@@ -107,15 +126,49 @@ impl GlobalDescriptorSet {
         //         .unwrap()
         // };
 
-        Self {
+        let mut default_texture = crate::buffer::Image::from_image_buffer(
+            render_instance,
+            render_allocator,
+            "default texture",
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+                1,
+                1,
+                image::Rgba([255; 4]),
+            )),
+            vk::Format::R8G8B8A8_UNORM,
+        )?;
+        default_texture.create_view(render_instance.device())?;
+
+        let camera_buffers = (0..render_instance.0.frames.len())
+            .map(|frame_index| {
+                crate::buffer::Buffer::new(
+                    &render_instance.0,
+                    render_allocator.allocator(),
+                    &format!("camera {frame_index}"),
+                    &vk::BufferCreateInfo::default()
+                        .size(std::mem::size_of::<CameraBuffer>() as u64)
+                        .usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+                        .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                    MemoryLocation::CpuToGpu,
+                )
+            })
+            .collect::<RenderResult<_>>()?;
+
+        Ok(Self {
             // set_layouts,
             // descriptor_sets,
             // set_layout_info,
             buffers: BTreeMap::new(),
+            camera_buffers,
             textures: BTreeMap::new(),
+            texture_slots: HashMap::new(),
+            free_slots: Vec::new(),
+            next_slot: 0,
+            default_texture,
+            generation: 0,
             buffer_infos: HashMap::new(),
             image_infos: HashMap::new(),
-        }
+        })
     }
 
     // /// TODO: use a Vec and a hashmap to prevent O(n) lookup
@@ -123,20 +176,61 @@ impl GlobalDescriptorSet {
     //     self.buffers.iter().position(|(k, _)| k.eq(key))
     // }
 
-    /// TODO: use a Vec and a hashmap to prevent O(n) lookup
+    /// Returns the texture that was replaced, it has to outlive the frames still sampling it.
+    pub fn insert_texture(
+        &mut self,
+        handle: Handle<super::image::Image>,
+        texture: crate::buffer::Image,
+    ) -> Option<crate::buffer::Image> {
+        // the cached descriptor points at the view of the old texture
+        self.image_infos.remove(&handle);
+        self.generation += 1;
+        if !self.texture_slots.contains_key(&handle) {
+            let slot = self.free_slots.pop().unwrap_or_else(|| {
+                self.next_slot += 1;
+                self.next_slot - 1
+            });
+            self.texture_slots.insert(handle.clone(), slot);
+        }
+        self.textures.insert(handle, texture)
+    }
+
+    /// Frees the texture's slot, it's given to the next new texture.
+    pub fn remove_texture(
+        &mut self,
+        handle: &Handle<super::image::Image>,
+    ) -> Option<crate::buffer::Image> {
+        self.image_infos.remove(handle);
+        self.generation += 1;
+        if let Some(slot) = self.texture_slots.remove(handle) {
+            self.free_slots.push(slot);
+        }
+        self.textures.remove(handle)
+    }
+
+    /// Changes whenever a texture is added, replaced or removed, sets written at an older
+    /// generation have to be written again.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
     pub fn get_texture_index(&self, key: &Handle<super::image::Image>) -> Option<usize> {
-        self.textures.iter().position(|(k, _)| k == key)
+        self.texture_slots.get(key).map(|slot| *slot as usize)
     }
 
     pub fn update_descriptor_set(
         &mut self,
         set: vk::DescriptorSet,
         render_instance: &RenderInstance,
-    ) {
+    ) -> RenderResult<()> {
         let mut write_desc_sets = vec![];
+        let default_image_info = [vk::DescriptorImageInfo::default()
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image_view(self.default_texture.view.unwrap())
+            .sampler(render_instance.0.get_default_sampler())];
 
         for (key, texture) in self.textures.iter_mut() {
-            let view = texture.create_view(render_instance.device());
+            let view = texture.create_view(render_instance.device())?;
 
             if !self.image_infos.contains_key(key) {
                 self.image_infos.insert(
@@ -161,16 +255,26 @@ impl GlobalDescriptorSet {
         //     }
         // }
 
-        for (index, (key, _)) in self.textures.iter_mut().enumerate() {
+        for key in self.textures.keys() {
             write_desc_sets.push(
                 vk::WriteDescriptorSet::default()
                     .dst_set(set)
                     .dst_binding(0)
                     .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                    .dst_array_element(index as u32)
+                    .dst_array_element(self.texture_slots[key])
                     .image_info(self.image_infos.get(key).unwrap()),
             );
         }
+        for slot in &self.free_slots {
+            write_desc_sets.push(
+                vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .dst_array_element(*slot)
+                    .image_info(&default_image_info),
+            );
+        }
 
         // for (index, (key, _)) in self.buffers.iter_mut().enumerate() {
         //     write_desc_sets.push(
@@ -188,5 +292,30 @@ impl GlobalDescriptorSet {
                 .device()
                 .update_descriptor_sets(&write_desc_sets, &[]);
         };
+        Ok(())
+    }
+
+    /// Frees every texture and buffer, the device has to be idle.
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        for (_, mut texture) in std::mem::take(&mut self.textures) {
+            texture.destroy(device, render_allocator.allocator());
+        }
+        for (_, mut buffer) in std::mem::take(&mut self.buffers) {
+            buffer.destroy(device, render_allocator.allocator());
+        }
+        for mut buffer in self.camera_buffers.drain(..) {
+            buffer.destroy(device, render_allocator.allocator());
+        }
+        self.default_texture
+            .destroy(device, render_allocator.allocator());
+        self.texture_slots.clear();
+        self.free_slots.clear();
+        self.image_infos.clear();
+        self.buffer_infos.clear();
     }
 }
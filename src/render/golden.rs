@@ -0,0 +1,303 @@
+//! Golden image tests. Scenes are rendered with a headless [`RenderPlugin`] and compared against
+//! the PNGs in `tests/golden`.
+//!
+//! The scene tests need a Vulkan device (lavapipe works), run them with
+//! `cargo test -- --include-ignored`. Set `SOMEDAY_BLESS=1` to write the current output as the
+//! new golden image instead of comparing. The golden images have to come from lavapipe, running
+//! the CI workflow with `bless` uploads them as an artifact. On a mismatch the actual output and
+//! a diff image are written to `target/golden`.
+
+use std::path::{Path, PathBuf};
+
+use ::image::{Rgba, RgbaImage};
+use bevy::{app::AppExit, prelude::*};
+
+use crate::validation::ValidationSettings;
+
+use super::{
+    bundles::{Camera, CameraBundle, MaterialMeshBundle},
+    material::Material,
+    mesh::Mesh,
+    primitives, read_offscreen_image, RenderPlugin,
+};
+
+const GOLDEN_DIR: &str = "tests/golden";
+const OUTPUT_DIR: &str = "target/golden";
+
+/// How far two images may drift apart before a comparison fails.
+#[derive(Debug, Clone, Copy)]
+struct Tolerance {
+    /// Largest perceptual difference, from 0 to 1, at which two pixels still count as equal.
+    pixel_threshold: f32,
+    /// Fraction of pixels that may differ by more than `pixel_threshold`.
+    max_differing_pixels: f32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Self {
+            pixel_threshold: 0.02,
+            max_differing_pixels: 0.001,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Comparison {
+    differing_pixels: usize,
+    max_difference: f32,
+    diff: RgbaImage,
+}
+
+/// Difference between two pixels in luma weighted RGB, so a shift in green counts for more than
+/// the same shift in blue. Alpha is ignored, the target is always opaque.
+fn pixel_difference(a: &Rgba<u8>, b: &Rgba<u8>) -> f32 {
+    const WEIGHTS: [f32; 3] = [0.299, 0.587, 0.114];
+
+    WEIGHTS
+        .iter()
+        .enumerate()
+        .map(|(channel, weight)| {
+            let delta = (a[channel] as f32 - b[channel] as f32) / 255.0;
+            weight * delta * delta
+        })
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Compares two images of the same size. The diff image shows the expected image faded out, with
+/// every pixel over the threshold in red.
+fn compare(expected: &RgbaImage, actual: &RgbaImage, tolerance: Tolerance) -> Comparison {
+    assert_eq!(
+        expected.dimensions(),
+        actual.dimensions(),
+        "Golden image and render target differ in size"
+    );
+
+    let mut differing_pixels = 0;
+    let mut max_difference: f32 = 0.0;
+    let mut diff = RgbaImage::new(expected.width(), expected.height());
+
+    for ((expected, actual), diff) in expected
+        .pixels()
+        .zip(actual.pixels())
+        .zip(diff.pixels_mut())
+    {
+        let difference = pixel_difference(expected, actual);
+        max_difference = max_difference.max(difference);
+
+        *diff = if difference > tolerance.pixel_threshold {
+            differing_pixels += 1;
+            Rgba([255, 0, 0, 255])
+        } else {
+            let luma = (expected[0] as u32 + expected[1] as u32 + expected[2] as u32) / 3;
+            let faded = (luma / 4 + 192) as u8;
+            Rgba([faded, faded, faded, 255])
+        };
+    }
+
+    Comparison {
+        differing_pixels,
+        max_difference,
+        diff,
+    }
+}
+
+impl Comparison {
+    fn passes(&self, tolerance: Tolerance) -> bool {
+        let pixel_count = (self.diff.width() * self.diff.height()) as f32;
+        self.differing_pixels as f32 <= pixel_count * tolerance.max_differing_pixels
+    }
+}
+
+/// Renders `frames` frames of the scene built by `spawn` into a `size` offscreen target and
+/// reads back the last one.
+fn render_scene(
+    size: UVec2,
+    frames: usize,
+    spawn: impl FnOnce(&mut Commands, &mut Assets<Mesh>, &mut Assets<Material>),
+) -> RgbaImage {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+        .add_plugins(RenderPlugin {
+            headless: Some(size),
+            validation: ValidationSettings::strict(),
+            ..Default::default()
+        });
+
+    // normally done by `App::run`, which would never return
+    app.finish();
+    app.cleanup();
+
+    app.world
+        .resource_scope(|world, mut meshes: Mut<Assets<Mesh>>| {
+            world.resource_scope(|world, mut materials: Mut<Assets<Material>>| {
+                let mut commands_queue = bevy::ecs::system::CommandQueue::default();
+                let mut commands = Commands::new(&mut commands_queue, world);
+                spawn(&mut commands, &mut meshes, &mut materials);
+                drop(commands);
+                commands_queue.apply(world);
+            })
+        });
+
+    for _ in 0..frames {
+        app.update();
+    }
+
+    let image = read_offscreen_image(&mut app).expect("Failed to read the offscreen image");
+
+    // shut the renderer down like a real app would, so leaks and validation errors show up
+    app.world.send_event(AppExit);
+    app.update();
+
+    image
+}
+
+/// Compares `actual` against `tests/golden/<name>.png`, or overwrites it when blessing.
+fn assert_golden(name: &str, actual: &RgbaImage, tolerance: Tolerance) {
+    let golden_path = Path::new(GOLDEN_DIR).join(format!("{name}.png"));
+
+    if std::env::var_os("SOMEDAY_BLESS").is_some() {
+        std::fs::create_dir_all(GOLDEN_DIR).unwrap();
+        actual.save(&golden_path).unwrap();
+        return;
+    }
+
+    let output_path = |suffix: &str| -> PathBuf {
+        std::fs::create_dir_all(OUTPUT_DIR).unwrap();
+        Path::new(OUTPUT_DIR).join(format!("{name}.{suffix}.png"))
+    };
+
+    let expected = match ::image::open(&golden_path) {
+        Ok(expected) => expected.into_rgba8(),
+        Err(err) => {
+            let actual_path = output_path("actual");
+            actual.save(&actual_path).unwrap();
+            panic!(
+                "Couldn't open golden image {golden_path:?} ({err}), the render was written to {actual_path:?}. Run with SOMEDAY_BLESS=1 to accept it."
+            );
+        }
+    };
+
+    let comparison = compare(&expected, actual, tolerance);
+    if !comparison.passes(tolerance) {
+        let actual_path = output_path("actual");
+        let diff_path = output_path("diff");
+        actual.save(&actual_path).unwrap();
+        comparison.diff.save(&diff_path).unwrap();
+
+        panic!(
+            "{name}: {} pixels differ from {golden_path:?} (max difference {:.3}), see {actual_path:?} and {diff_path:?}",
+            comparison.differing_pixels, comparison.max_difference
+        );
+    }
+}
+
+fn camera_looking_at_origin(size: UVec2, from: Vec3) -> CameraBundle {
+    CameraBundle {
+        transform: Transform::from_translation(from).looking_at(Vec3::ZERO, Vec3::Y),
+        camera: Camera {
+            projection: Mat4::perspective_infinite_reverse_rh(
+                60_f32.to_radians(),
+                size.x as f32 / size.y as f32,
+                0.1,
+            ),
+        },
+    }
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn golden_cube() {
+    let size = UVec2::new(256, 256);
+    let image = render_scene(size, 3, |commands, meshes, materials| {
+        commands.spawn(MaterialMeshBundle {
+            mesh: meshes.add(primitives::Cube::new(2.0).into()),
+            material: materials.add(Material {
+                base_color: Vec3::new(1.0, 0.0, 0.0),
+                ..Default::default()
+            }),
+            transform: Transform::default(),
+        });
+        commands.spawn(camera_looking_at_origin(size, Vec3::new(3.0, 3.0, 5.0)));
+    });
+
+    assert_golden("cube", &image, Tolerance::default());
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn golden_primitives() {
+    let size = UVec2::new(320, 180);
+    let image = render_scene(size, 3, |commands, meshes, materials| {
+        let shapes: [(Mesh, Vec3, Vec3); 3] = [
+            (
+                primitives::Box::new(1.0, 2.0, 1.0).into(),
+                Vec3::new(-2.5, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+            ),
+            (
+                primitives::Cube::new(1.5).into(),
+                Vec3::ZERO,
+                Vec3::new(0.0, 0.0, 1.0),
+            ),
+            (
+                primitives::Quad::new(Vec2::new(1.5, 1.5)).into(),
+                Vec3::new(2.5, 0.0, 0.0),
+                Vec3::new(1.0, 1.0, 0.0),
+            ),
+        ];
+
+        for (mesh, translation, base_color) in shapes {
+            commands.spawn(MaterialMeshBundle {
+                mesh: meshes.add(mesh),
+                material: materials.add(Material {
+                    base_color,
+                    ..Default::default()
+                }),
+                transform: Transform::from_translation(translation),
+            });
+        }
+        commands.spawn(camera_looking_at_origin(size, Vec3::new(0.0, 1.0, 8.0)));
+    });
+
+    assert_golden("primitives", &image, Tolerance::default());
+}
+
+#[test]
+fn compare_identical_images() {
+    let image = RgbaImage::from_pixel(8, 8, Rgba([10, 200, 30, 255]));
+    let comparison = compare(&image, &image, Tolerance::default());
+
+    assert_eq!(comparison.differing_pixels, 0);
+    assert_eq!(comparison.max_difference, 0.0);
+    assert!(comparison.passes(Tolerance::default()));
+}
+
+#[test]
+fn compare_reports_differing_pixels() {
+    let expected = RgbaImage::from_pixel(8, 8, Rgba([0, 0, 0, 255]));
+    let mut actual = expected.clone();
+    actual.put_pixel(3, 4, Rgba([255, 255, 255, 255]));
+
+    let comparison = compare(&expected, &actual, Tolerance::default());
+
+    assert_eq!(comparison.differing_pixels, 1);
+    assert_eq!(*comparison.diff.get_pixel(3, 4), Rgba([255, 0, 0, 255]));
+    assert!(!comparison.passes(Tolerance::default()));
+    assert!(comparison.passes(Tolerance {
+        max_differing_pixels: 1.0 / 64.0,
+        ..Default::default()
+    }));
+}
+
+#[test]
+fn compare_ignores_small_differences() {
+    let expected = RgbaImage::from_pixel(8, 8, Rgba([100, 100, 100, 255]));
+    let actual = RgbaImage::from_pixel(8, 8, Rgba([101, 100, 99, 255]));
+
+    let comparison = compare(&expected, &actual, Tolerance::default());
+
+    assert_eq!(comparison.differing_pixels, 0);
+    assert!(comparison.max_difference > 0.0);
+}
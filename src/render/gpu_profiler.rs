@@ -0,0 +1,403 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+};
+
+use ash::vk;
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticId, DiagnosticsStore},
+    prelude::*,
+};
+
+use crate::{ctx::ExampleBase, error::RenderResult};
+
+/// Timestamps each frame in flight can write, two per scope.
+const MAX_QUERIES: u32 = 256;
+
+/// XORed with the hash of a scope name to get its [`DiagnosticId`].
+const DIAGNOSTIC_NAMESPACE: u128 = 0x5d1e_07a4_6f3b_4c59_9a1e_2b8c_0000_0000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpuProfilerSettings {
+    pub enabled: bool,
+    /// Also time every secondary command buffer. They only show up in Tracy, not as diagnostics.
+    pub secondary_command_buffers: bool,
+}
+
+impl Default for GpuProfilerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            secondary_command_buffers: false,
+        }
+    }
+}
+
+/// GPU time per scope in milliseconds, handed from the render world to the main world where
+/// they're recorded as diagnostics named `gpu <scope>`.
+#[derive(Resource, Clone, Default)]
+pub struct GpuTimings(Arc<Mutex<Vec<(String, f64)>>>);
+
+/// Records the timings the renderer resolved since the last update into the [`DiagnosticsStore`].
+pub(super) fn publish_gpu_timings(
+    timings: Res<GpuTimings>,
+    store: Option<ResMut<DiagnosticsStore>>,
+) {
+    let timings = std::mem::take(&mut *timings.0.lock().unwrap());
+    let Some(mut store) = store else {
+        return;
+    };
+
+    for (name, milliseconds) in timings {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        let id = DiagnosticId::from_u128(DIAGNOSTIC_NAMESPACE ^ hasher.finish() as u128);
+
+        if store.get(id).is_none() {
+            store.add(Diagnostic::new(id, format!("gpu {name}"), 20).with_suffix("ms"));
+        }
+        if let Some(diagnostic) = store.get_mut(id) {
+            diagnostic.add_measurement(milliseconds);
+        }
+    }
+}
+
+/// A scope started with [`GpuProfiler::begin_scope`], has to be ended in the same frame.
+#[derive(Debug, Clone, Copy)]
+pub struct GpuScope {
+    index: usize,
+}
+
+#[derive(Debug)]
+struct Scope {
+    name: String,
+    begin: u32,
+    end: Option<u32>,
+    secondary: bool,
+}
+
+/// The timestamps one frame in flight wrote, read back once its fence has been waited on.
+struct FrameQueries {
+    pool: vk::QueryPool,
+    scopes: Vec<Scope>,
+    next_query: u32,
+    /// Queries have to be reset on the GPU before they're written again.
+    needs_reset: bool,
+}
+
+/// Times scopes of command buffers with timestamp queries, one query pool per frame in flight.
+///
+/// Results are resolved in [`GpuProfiler::begin_frame`], after the fence of that frame has been
+/// waited on, so they lag `frames_in_flight` frames behind. They're published as diagnostics
+/// through [`GpuTimings`], and as GPU zones when Tracy is enabled.
+#[derive(Resource)]
+pub struct GpuProfiler {
+    settings: GpuProfilerSettings,
+    frames: Vec<Mutex<FrameQueries>>,
+    /// Nanoseconds per timestamp tick.
+    timestamp_period: f32,
+    timestamp_mask: u64,
+    timings: GpuTimings,
+    #[cfg(feature = "tracing")]
+    tracy: Option<Mutex<tracy_client::GpuContext>>,
+}
+
+impl GpuProfiler {
+    /// Disables itself when the graphics queue doesn't support timestamps.
+    pub fn new(
+        renderer: &ExampleBase,
+        settings: GpuProfilerSettings,
+        timings: GpuTimings,
+    ) -> RenderResult<Self> {
+        let (timestamp_period, timestamp_valid_bits) = unsafe {
+            let properties = renderer
+                .instance
+                .get_physical_device_properties(renderer.pdevice);
+            let queue_family = renderer
+                .instance
+                .get_physical_device_queue_family_properties(renderer.pdevice)
+                [renderer.queue_family_index as usize];
+            (
+                properties.limits.timestamp_period,
+                queue_family.timestamp_valid_bits,
+            )
+        };
+
+        let mut settings = settings;
+        if settings.enabled && timestamp_valid_bits == 0 {
+            warn!("The graphics queue doesn't support timestamps, GPU profiling is disabled");
+            settings.enabled = false;
+        }
+
+        let mut frames = vec![];
+        if settings.enabled {
+            for index in 0..renderer.frames.len() {
+                let pool = unsafe {
+                    renderer.device.create_query_pool(
+                        &vk::QueryPoolCreateInfo::default()
+                            .query_type(vk::QueryType::TIMESTAMP)
+                            .query_count(MAX_QUERIES),
+                        None,
+                    )?
+                };
+                renderer.set_object_name(pool, &format!("frame {index} timestamps"));
+                frames.push(Mutex::new(FrameQueries {
+                    pool,
+                    scopes: vec![],
+                    next_query: 0,
+                    needs_reset: true,
+                }));
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        let tracy = if settings.enabled {
+            Self::create_tracy_context(renderer, timestamp_period)?.map(Mutex::new)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            settings,
+            frames,
+            timestamp_period,
+            timestamp_mask: match timestamp_valid_bits {
+                64.. => u64::MAX,
+                bits => (1 << bits) - 1,
+            },
+            timings,
+            #[cfg(feature = "tracing")]
+            tracy,
+        })
+    }
+
+    /// Tracy lines GPU zones up with the CPU timeline using a timestamp taken right now.
+    #[cfg(feature = "tracing")]
+    fn create_tracy_context(
+        renderer: &ExampleBase,
+        timestamp_period: f32,
+    ) -> RenderResult<Option<tracy_client::GpuContext>> {
+        let Some(client) = tracy_client::Client::running() else {
+            return Ok(None);
+        };
+
+        let device = &renderer.device;
+        let pool = unsafe {
+            device.create_query_pool(
+                &vk::QueryPoolCreateInfo::default()
+                    .query_type(vk::QueryType::TIMESTAMP)
+                    .query_count(1),
+                None,
+            )?
+        };
+        let timestamp = crate::ctx::record_submit_commandbuffer(
+            device,
+            renderer.setup_command_buffer,
+            renderer.setup_commands_reuse_fence,
+            &renderer.present_queue,
+            &Default::default(),
+            |device, command_buffer| unsafe {
+                device.cmd_reset_query_pool(command_buffer, pool, 0, 1);
+                device.cmd_write_timestamp(
+                    command_buffer,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    pool,
+                    0,
+                );
+                Ok(())
+            },
+        )
+        .and_then(|()| unsafe {
+            let mut timestamp = [0u64];
+            device.get_query_pool_results(
+                pool,
+                0,
+                &mut timestamp,
+                vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+            )?;
+            Ok(timestamp[0])
+        });
+        unsafe { device.destroy_query_pool(pool, None) };
+
+        match client.new_gpu_context(
+            Some("graphics"),
+            tracy_client::GpuContextType::Vulkan,
+            timestamp? as i64,
+            timestamp_period,
+        ) {
+            Ok(context) => Ok(Some(context)),
+            Err(err) => {
+                warn!("Failed to create the Tracy GPU context: {err:?}");
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn secondary_command_buffers(&self) -> bool {
+        self.settings.enabled && self.settings.secondary_command_buffers
+    }
+
+    /// Resolves the scopes the current frame recorded the last time around. Call it after the
+    /// fence of the frame has been waited on.
+    pub fn begin_frame(&self, renderer: &ExampleBase) {
+        let Some(frame) = self.frames.get(renderer.frame_index()) else {
+            return;
+        };
+        let mut frame = frame.lock().unwrap();
+        let scopes = std::mem::take(&mut frame.scopes);
+        let query_count = std::mem::take(&mut frame.next_query);
+        frame.needs_reset = true;
+        if query_count == 0 {
+            return;
+        }
+
+        let mut timestamps = vec![0u64; query_count as usize];
+        let resolved = unsafe {
+            renderer.device.get_query_pool_results(
+                frame.pool,
+                0,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        };
+        // NOT_READY when the frame was skipped before it got submitted
+        if resolved.is_err() {
+            return;
+        }
+
+        let mut timings = self.timings.0.lock().unwrap();
+        for scope in scopes {
+            let Some(end) = scope.end else {
+                continue;
+            };
+            let begin = timestamps[scope.begin as usize] & self.timestamp_mask;
+            let end = timestamps[end as usize] & self.timestamp_mask;
+
+            #[cfg(feature = "tracing")]
+            if let Some(tracy) = &self.tracy {
+                if let Ok(mut span) =
+                    tracy
+                        .lock()
+                        .unwrap()
+                        .span_alloc(&scope.name, "", file!(), line!())
+                {
+                    span.end_zone();
+                    span.upload_timestamp(begin as i64, end as i64);
+                }
+            }
+
+            if !scope.secondary {
+                let nanoseconds = end.saturating_sub(begin) as f64 * self.timestamp_period as f64;
+                timings.push((scope.name, nanoseconds / 1_000_000.0));
+            }
+        }
+    }
+
+    /// Writes the begin timestamp of a scope into a primary command buffer. The first scope of a
+    /// frame resets the query pool, so it has to be outside of rendering. Returns `None` when
+    /// profiling is disabled or the frame ran out of queries.
+    pub fn begin_scope(
+        &self,
+        renderer: &ExampleBase,
+        command_buffer: vk::CommandBuffer,
+        name: &str,
+    ) -> Option<GpuScope> {
+        self.begin(renderer, command_buffer, name, false)
+    }
+
+    /// Like [`GpuProfiler::begin_scope`] for secondary command buffers, only when
+    /// [`GpuProfilerSettings::secondary_command_buffers`] is set.
+    pub fn begin_secondary_scope(
+        &self,
+        renderer: &ExampleBase,
+        command_buffer: vk::CommandBuffer,
+        name: &str,
+    ) -> Option<GpuScope> {
+        if !self.settings.secondary_command_buffers {
+            return None;
+        }
+        self.begin(renderer, command_buffer, name, true)
+    }
+
+    pub fn end_scope(
+        &self,
+        renderer: &ExampleBase,
+        command_buffer: vk::CommandBuffer,
+        scope: GpuScope,
+    ) {
+        let mut frame = self.frames[renderer.frame_index()].lock().unwrap();
+        if frame.next_query == MAX_QUERIES {
+            return;
+        }
+        let query = frame.next_query;
+        frame.next_query += 1;
+        frame.scopes[scope.index].end = Some(query);
+
+        unsafe {
+            renderer.device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                frame.pool,
+                query,
+            );
+        }
+    }
+
+    fn begin(
+        &self,
+        renderer: &ExampleBase,
+        command_buffer: vk::CommandBuffer,
+        name: &str,
+        secondary: bool,
+    ) -> Option<GpuScope> {
+        let mut frame = self.frames.get(renderer.frame_index())?.lock().unwrap();
+        if frame.needs_reset {
+            // resetting isn't allowed in the render pass secondary command buffers continue
+            if secondary {
+                return None;
+            }
+            unsafe {
+                renderer
+                    .device
+                    .cmd_reset_query_pool(command_buffer, frame.pool, 0, MAX_QUERIES);
+            }
+            frame.needs_reset = false;
+        }
+        // keep room for the end timestamp
+        if frame.next_query + 2 > MAX_QUERIES {
+            return None;
+        }
+
+        let query = frame.next_query;
+        frame.next_query += 1;
+        frame.scopes.push(Scope {
+            name: name.to_string(),
+            begin: query,
+            end: None,
+            secondary,
+        });
+
+        unsafe {
+            renderer.device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                frame.pool,
+                query,
+            );
+        }
+        Some(GpuScope {
+            index: frame.scopes.len() - 1,
+        })
+    }
+
+    pub fn destroy(&mut self, renderer: &ExampleBase) {
+        for frame in self.frames.drain(..) {
+            unsafe {
+                renderer
+                    .device
+                    .destroy_query_pool(frame.into_inner().unwrap().pool, None)
+            };
+        }
+    }
+}
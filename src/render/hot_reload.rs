@@ -0,0 +1,214 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, Sender};
+use notify::{RecursiveMode, Watcher};
+
+use crate::error::{RenderError, RenderResult};
+
+use super::{
+    shader_cache::ShaderCache,
+    shaders::{CompiledShader, Shader, ShaderCompileSettings, ShaderKind, ShaderVariantKey},
+};
+
+/// A shader loaded with [`Shader::from_file`] and the files it was compiled from.
+struct TrackedShader {
+    kind: ShaderKind,
+    entry_point: String,
+    /// See [`watched_files`].
+    files: HashSet<PathBuf>,
+    /// Bumped for every recompile, so an older compile finishing last doesn't win.
+    generation: u64,
+}
+
+/// A shader loaded from a path, one of them per variant.
+type ShaderId = (String, ShaderVariantKey);
+
+struct Recompiled {
+    id: ShaderId,
+    generation: u64,
+    result: RenderResult<CompiledShader>,
+}
+
+/// Shaders that were recompiled since the last frame, keyed by the path they were loaded with
+/// and their variant.
+#[derive(Default)]
+pub struct ReloadedShaders(HashMap<ShaderId, CompiledShader>);
+
+impl ReloadedShaders {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, path: &str, variant: &ShaderVariantKey) -> Option<&CompiledShader> {
+        self.0.get(&(path.to_string(), variant.clone()))
+    }
+
+    /// Whether any variant of the shader at `path` was reloaded.
+    pub fn contains(&self, path: &str) -> bool {
+        self.0.keys().any(|(reloaded, _)| reloaded == path)
+    }
+}
+
+/// Watches the shader directory and recompiles every loaded shader that depends on a file that
+/// changed, on a thread of its own.
+///
+/// [`ShaderWatcher::poll`] is called between frames and hands the shaders that compiled to
+/// [`SequentialNode::reload_shaders`](super::SequentialNode::reload_shaders). A shader that fails
+/// to compile is only reported, whatever was built from it keeps using the last version that
+/// worked.
+pub struct ShaderWatcher {
+    /// Stops watching when dropped.
+    _watcher: Mutex<notify::RecommendedWatcher>,
+    events: Receiver<notify::Result<notify::Event>>,
+    shaders: Mutex<HashMap<ShaderId, TrackedShader>>,
+    settings: ShaderCompileSettings,
+    cache: Option<ShaderCache>,
+    recompiled: (Sender<Recompiled>, Receiver<Recompiled>),
+}
+
+impl ShaderWatcher {
+    pub fn new(
+        dir: &Path,
+        settings: ShaderCompileSettings,
+        cache: Option<ShaderCache>,
+    ) -> notify::Result<Self> {
+        let (sender, events) = crossbeam_channel::unbounded();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            // the receiver is only gone while the renderer is being dropped
+            let _ = sender.send(event);
+        })?;
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+
+        Ok(Self {
+            _watcher: Mutex::new(watcher),
+            events,
+            shaders: Mutex::new(HashMap::new()),
+            settings,
+            cache,
+            recompiled: crossbeam_channel::unbounded(),
+        })
+    }
+
+    /// Starts watching the files a variant of `path` was compiled from, replacing what was
+    /// tracked for it.
+    pub fn track(
+        &self,
+        path: &str,
+        kind: &ShaderKind,
+        entry_point: &str,
+        variant: &ShaderVariantKey,
+        includes: &[PathBuf],
+    ) {
+        let files = watched_files(path, includes);
+        let id = (path.to_string(), variant.clone());
+        let mut shaders = self.shaders.lock().unwrap();
+        let generation = shaders.get(&id).map_or(0, |shader| shader.generation);
+        shaders.insert(
+            id,
+            TrackedShader {
+                kind: kind.clone(),
+                entry_point: entry_point.to_string(),
+                files,
+                generation,
+            },
+        );
+    }
+
+    /// Starts recompiling the shaders whose files changed and returns the ones that finished
+    /// compiling since the last call.
+    pub fn poll(&self) -> ReloadedShaders {
+        let mut changed = HashSet::new();
+        for event in self.events.try_iter() {
+            match event {
+                Ok(event) if !event.kind.is_access() => {
+                    changed.extend(
+                        event
+                            .paths
+                            .iter()
+                            .filter_map(|path| path.canonicalize().ok()),
+                    );
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Shader watcher error: {err}"),
+            }
+        }
+
+        let mut shaders = self.shaders.lock().unwrap();
+        if !changed.is_empty() {
+            for (id, shader) in shaders.iter_mut() {
+                if shader.files.is_disjoint(&changed) {
+                    continue;
+                }
+                shader.generation += 1;
+                self.spawn_compile(id, shader);
+            }
+        }
+
+        let mut reloaded = ReloadedShaders::default();
+        for recompiled in self.recompiled.1.try_iter() {
+            let Some(shader) = shaders.get_mut(&recompiled.id) else {
+                continue;
+            };
+            if recompiled.generation != shader.generation {
+                continue;
+            }
+            match recompiled.result {
+                Ok(compiled) => {
+                    info!("Reloading {}", recompiled.id.0);
+                    // the includes may have changed along with the source
+                    shader.files = watched_files(&recompiled.id.0, &compiled.includes);
+                    reloaded.0.insert(recompiled.id, compiled);
+                }
+                Err(RenderError::ShaderCompilation { name, diagnostics }) => {
+                    diagnostics.log();
+                    error!("Keeping the last version of {name} that compiled");
+                }
+                Err(err) => error!(
+                    "Keeping the last version of {} that compiled. {err}",
+                    recompiled.id.0
+                ),
+            }
+        }
+        reloaded
+    }
+
+    fn spawn_compile(&self, id: &ShaderId, shader: &TrackedShader) {
+        let id = id.clone();
+        let kind = shader.kind.clone();
+        let entry_point = shader.entry_point.clone();
+        let generation = shader.generation;
+        let settings = self.settings.clone();
+        let cache = self.cache.clone();
+        let sender = self.recompiled.0.clone();
+
+        std::thread::spawn(move || {
+            let (path, variant) = &id;
+            let result = Shader::compile(
+                path,
+                &kind,
+                &entry_point,
+                variant,
+                &settings,
+                cache.as_ref(),
+            );
+            let _ = sender.send(Recompiled {
+                id,
+                generation,
+                result,
+            });
+        });
+    }
+}
+
+/// The source and its includes, canonicalized so they match the paths in the watcher's events.
+fn watched_files(path: &str, includes: &[PathBuf]) -> HashSet<PathBuf> {
+    std::iter::once(Path::new(path))
+        .chain(includes.iter().map(PathBuf::as_path))
+        .filter_map(|file| file.canonicalize().ok())
+        .collect()
+}
@@ -5,7 +5,7 @@ use bevy::{
     reflect::{Reflect, TypeUuid},
     utils::BoxedFuture,
 };
-use image::{DynamicImage, GenericImageView};
+use image::DynamicImage;
 
 use crate::ctx::SamplerDesc;
 
@@ -42,8 +42,6 @@ impl AssetLoader for ImageTextureLoader {
                 },
             };
 
-            println!("{:?} {:?}", img.data.dimensions(), ext);
-
             load_context.set_default_asset(LoadedAsset::new(img));
             Ok(())
         })
@@ -2,7 +2,10 @@ use ash::vk::CullModeFlags;
 use bevy::prelude::*;
 use bevy::reflect::{TypePath, TypeUuid};
 
-use super::image::Image;
+use super::{image::Image, shaders::ShaderVariantKey};
+
+/// `constant_id` of the alpha cutoff in the material shaders, see [`Material::shader_variant`].
+pub const ALPHA_CUTOFF_CONSTANT_ID: u32 = 0;
 
 #[derive(Debug, TypeUuid, Clone, TypePath)]
 #[uuid = "c94c1494-85e5-4a4c-8575-48baadfef3ab"]
@@ -43,6 +46,34 @@ pub struct MaterialUniform {
     pub depth_bias: f32,
 }
 
+impl Material {
+    /// The variant of the material shaders that draws this material: `ALPHA_MASK` is defined for
+    /// [`AlphaMode::Mask`], with the cutoff as a specialization constant. The shaders that use the
+    /// defines list them with `// variant:` so `build.rs` precompiles them.
+    pub fn shader_variant(&self) -> ShaderVariantKey {
+        let mut variant = ShaderVariantKey::default();
+        if let AlphaMode::Mask(cutoff) = self.alpha_mode {
+            variant = variant
+                .define("ALPHA_MASK")
+                .specialize(ALPHA_CUTOFF_CONSTANT_ID, cutoff.to_bits());
+        }
+        variant
+    }
+
+    /// Every texture the material uses.
+    pub fn textures(&self) -> impl Iterator<Item = &Handle<Image>> {
+        [
+            &self.base_color_texture,
+            &self.emissive_texture,
+            &self.metallic_roughness_texture,
+            &self.normal_map_texture,
+            &self.occlusion_texture,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
 impl MaterialUniform {
     pub fn from_material(material: &Material) -> Self {
         Self {
@@ -1,26 +1,38 @@
+pub mod breadcrumbs;
 pub mod bundles;
+pub mod deletion_queue;
 pub mod extract;
 pub mod global_descriptors;
 pub mod gltf;
+#[cfg(test)]
+mod golden;
+pub mod gpu_profiler;
+pub mod hot_reload;
 pub mod image;
 pub mod material;
 pub mod mesh;
 pub mod nodes;
 pub mod pipeline;
+pub mod pipeline_cache;
 pub mod primitives;
+pub mod screenshot;
+pub mod shader_cache;
+pub mod shader_diagnostics;
+#[cfg(feature = "shader-compiler")]
+mod shader_options;
 pub mod shaders;
 
 use std::{
     collections::{BTreeMap, HashMap},
     mem::size_of,
     ops::{Deref, DerefMut},
+    path::PathBuf,
     sync::Arc,
 };
 
 use ash::vk::{
-    self, DescriptorImageInfo, ImageCreateInfo, PrimitiveTopology, VertexInputAttributeDescription,
-    VertexInputAttributeDescription2EXT, VertexInputBindingDescription,
-    VertexInputBindingDescription2EXT, VertexInputRate,
+    self, DescriptorImageInfo, ImageCreateInfo, PrimitiveTopology, VertexInputBindingDescription,
+    VertexInputRate,
 };
 use bevy::{
     app::{AppExit, AppLabel, SubApp},
@@ -29,29 +41,87 @@ use bevy::{
     prelude::*,
     time::{create_time_channels, TimeSender},
     utils::Instant,
-    window::{PrimaryWindow, RawHandleWrapper},
+    window::{PrimaryWindow, RawHandleWrapper, WindowResized},
 };
 use bytemuck::offset_of;
 use gpu_allocator::{
     vulkan::{Allocator, AllocatorCreateDesc},
-    MemoryLocation,
+    AllocatorDebugSettings, MemoryLocation,
 };
 
-use crate::{buffer::Buffer, ctx::ExampleBase};
+use crate::{
+    buffer::Buffer,
+    ctx::{ExampleBase, RenderTarget, RendererSettings, FRAMES_IN_FLIGHT},
+    device::DeviceSelection,
+    error::{RenderError, RenderResult},
+    sync::SyncPoint,
+    validation::ValidationSettings,
+};
 
 use self::{
+    breadcrumbs::Breadcrumbs,
     bundles::{Camera, MaterialMeshBundle},
+    deletion_queue::{DeletionQueue, Garbage},
     extract::Extract,
     global_descriptors::GlobalDescriptorSet,
+    gpu_profiler::{GpuProfiler, GpuProfilerSettings, GpuScope, GpuTimings},
+    hot_reload::ReloadedShaders,
     image::Image,
     material::{Material, MaterialUniform},
     mesh::Mesh,
     nodes::PresentNode,
+    pipeline::{VertexAttribute, VertexLayout},
+    screenshot::{Screenshot, Screenshots},
+    shaders::{ShaderCompileSettings, ShaderVariantKey},
 };
 
 /// Contains the default Bevy rendering backend based on wgpu.
-#[derive(Default)]
-pub struct RenderPlugin {}
+pub struct RenderPlugin {
+    pub backend: RenderBackend,
+    /// How many frames the CPU may record ahead of the GPU, see [`FRAMES_IN_FLIGHT`].
+    pub frames_in_flight: usize,
+    /// Render without a window into an offscreen target of this size, read it back with
+    /// [`ExampleBase::read_offscreen_image`]. Works with software implementations like lavapipe.
+    pub headless: Option<UVec2>,
+    /// Which GPU to render on, `SOMEDAY_DEVICE` takes precedence over this.
+    pub device: DeviceSelection,
+    pub validation: ValidationSettings,
+    pub gpu_profiler: GpuProfilerSettings,
+    /// Where the pipeline cache and compiled shaders are kept between launches, `None` disables
+    /// caching on disk.
+    pub cache_dir: Option<PathBuf>,
+    pub shaders: ShaderCompileSettings,
+    /// Recompile shaders when they change on disk and rebuild the pipelines using them. On in
+    /// debug builds.
+    pub hot_reload: bool,
+}
+
+impl Default for RenderPlugin {
+    fn default() -> Self {
+        Self {
+            backend: RenderBackend::default(),
+            frames_in_flight: FRAMES_IN_FLIGHT,
+            headless: None,
+            device: DeviceSelection::default(),
+            validation: ValidationSettings::default(),
+            gpu_profiler: GpuProfilerSettings::default(),
+            cache_dir: Some(std::env::temp_dir().join(env!("CARGO_PKG_NAME"))),
+            shaders: ShaderCompileSettings::default(),
+            hot_reload: cfg!(debug_assertions),
+        }
+    }
+}
+
+/// How the graphics stages are bound when drawing, picked once when the [`RenderPlugin`] is built.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RenderBackend {
+    /// Monolithic `vk::Pipeline`s, see [`GraphicsPipeline`](pipeline::GraphicsPipeline).
+    #[default]
+    Pipeline,
+    /// Linked `vk::ShaderEXT`s from `VK_EXT_shader_object` with fully dynamic state,
+    /// see [`ShaderObjectPipeline`](pipeline::ShaderObjectPipeline).
+    ShaderObject,
+}
 
 /// The labels of the default App rendering sets.
 ///
@@ -172,10 +242,18 @@ pub struct NonSendMarker;
 pub struct RenderApp;
 
 impl Plugin for RenderPlugin {
-    fn build(&self, _app: &mut App) {}
+    fn build(&self, app: &mut App) {
+        app.add_event::<Screenshot>();
+    }
 
     fn ready(&self, app: &App) -> bool {
-        app.world.components().iter().find(|c| c.name() == "bevy_window::raw_handle::RawHandleWrapper").is_some()
+        self.headless.is_some()
+            || app
+                .world
+                .components()
+                .iter()
+                .find(|c| c.name() == "bevy_window::raw_handle::RawHandleWrapper")
+                .is_some()
     }
 
     /// Initializes the renderer, sets up the [`RenderSet`](RenderSet) and creates the rendering sub-app.
@@ -190,24 +268,71 @@ impl Plugin for RenderPlugin {
             Query<(&RawHandleWrapper, &Window), With<PrimaryWindow>>,
         > = SystemState::new(&mut app.world);
         let window_query = system_state.get(&app.world);
-        let (window_handle, window) = window_query.get_single().unwrap();
-        let render_instance = RenderInstance(Arc::new(ExampleBase::new(
-            window_handle,
-            window.present_mode,
-        )));
+        let target = match self.headless {
+            Some(size) => RenderTarget::Headless {
+                extent: vk::Extent2D {
+                    width: size.x,
+                    height: size.y,
+                },
+            },
+            None => {
+                let (window_handle, window) = window_query.get_single().unwrap();
+                RenderTarget::Window {
+                    handle: window_handle,
+                    extent: vk::Extent2D {
+                        width: window.physical_width(),
+                        height: window.physical_height(),
+                    },
+                    present_mode: window.present_mode,
+                }
+            }
+        };
+        let render_instance = RenderInstance(Arc::new(
+            ExampleBase::new(
+                target,
+                &RendererSettings {
+                    backend: self.backend,
+                    frames_in_flight: self.frames_in_flight,
+                    device: self.device.clone(),
+                    validation: self.validation.clone(),
+                    cache_dir: self.cache_dir.clone(),
+                    shaders: self.shaders.clone(),
+                    hot_reload: self.hot_reload,
+                },
+            )
+            .unwrap_or_else(|err| panic!("Failed to create the renderer: {err}")),
+        ));
 
-        let render_allocator = RenderAllocator(
+        let mut render_allocator = RenderAllocator::new(
             Allocator::new(&AllocatorCreateDesc {
                 instance: render_instance.0.instance.clone(),
                 device: render_instance.0.device.clone(),
                 physical_device: render_instance.0.pdevice,
-                debug_settings: Default::default(),
+                debug_settings: AllocatorDebugSettings {
+                    // leaks are reported by `shutdown`, with the backtrace of the allocation in debug builds
+                    log_leaks_on_shutdown: false,
+                    store_stack_traces: cfg!(debug_assertions),
+                    ..Default::default()
+                },
                 buffer_device_address: true, // Ideally, check the BufferDeviceAddressFeatures struct.
                 allocation_sizes: Default::default(),
             })
             .unwrap(),
         );
-        let global_descriptor_set = GlobalDescriptorSet::new(&render_instance);
+        let global_descriptor_set =
+            GlobalDescriptorSet::new(&render_instance, &mut render_allocator)
+                .unwrap_or_else(|err| panic!("Failed to create the global descriptors: {err}"));
+        let breadcrumbs = Breadcrumbs::new(&render_instance, &mut render_allocator)
+            .unwrap_or_else(|err| panic!("Failed to create the breadcrumb buffer: {err}"));
+        let gpu_timings = GpuTimings::default();
+        let gpu_profiler = GpuProfiler::new(
+            &render_instance.0,
+            self.gpu_profiler.clone(),
+            gpu_timings.clone(),
+        )
+        .unwrap_or_else(|err| panic!("Failed to create the GPU profiler: {err}"));
+        app.insert_resource(gpu_timings)
+            .add_systems(Update, gpu_profiler::publish_gpu_timings);
 
         let mut render_app = App::empty();
         render_app.main_schedule_label = Box::new(Render);
@@ -227,16 +352,31 @@ impl Plugin for RenderPlugin {
             )
             .init_non_send_resource::<NonSendMarker>()
             .init_resource::<ProcessedRenderAssets>()
+            .init_resource::<ExtractedCamera>()
+            .init_resource::<Screenshots>()
             .init_resource::<SequentialPassSystem>()
             .insert_resource(render_instance)
             .insert_resource(render_allocator)
+            .insert_resource(self.backend)
             .insert_resource(global_descriptor_set)
+            .insert_resource(breadcrumbs)
+            .insert_resource(gpu_profiler)
             .add_systems(ExtractSchedule, extract_meshes)
             .add_systems(ExtractSchedule, extract_materials)
             .add_systems(ExtractSchedule, extract_camera_uniform)
             .add_systems(ExtractSchedule, extract_objects)
             .add_systems(ExtractSchedule, extract_textures_from_materials)
-            .add_systems(Render, basic_renderer_setup.in_set(RenderSet::Prepare));
+            .add_systems(ExtractSchedule, screenshot::extract_screenshots)
+            .add_systems(
+                Render,
+                basic_renderer_setup
+                    .in_set(RenderSet::Prepare)
+                    .run_if(resource_exists::<RenderInstance>()),
+            );
+
+        if self.headless.is_none() {
+            render_app.add_systems(ExtractSchedule, extract_window_resize);
+        }
 
         let (sender, receiver) = create_time_channels();
         app.insert_resource(receiver);
@@ -278,6 +418,26 @@ struct ScratchMainWorld(World);
 /// Executes the [`ExtractSchedule`] step of the renderer.
 /// This updates the render world with the extracted ECS data of the current frame.
 fn extract(main_world: &mut World, render_app: &mut App) {
+    if !render_app.world.contains_resource::<RenderInstance>() {
+        // nothing will ever be drawn again, so don't leave the app running without a renderer
+        if render_app
+            .world
+            .remove_resource::<RendererFailed>()
+            .is_some()
+        {
+            if let Some(mut app_exit_events) = main_world.get_resource_mut::<Events<AppExit>>() {
+                app_exit_events.send(AppExit);
+            }
+        }
+        return;
+    }
+    if main_world
+        .get_resource::<Events<AppExit>>()
+        .map_or(false, |events| !events.is_empty())
+    {
+        render_app.world.init_resource::<ShutdownRequested>();
+    }
+
     // temporarily add the app world to the render world as a resource
     let scratch_world = main_world.remove_resource::<ScratchMainWorld>().unwrap();
     let inserted_world = std::mem::replace(main_world, scratch_world.0);
@@ -291,6 +451,78 @@ fn extract(main_world: &mut World, render_app: &mut App) {
     main_world.insert_resource(ScratchMainWorld(scratch_world));
 }
 
+/// Inserted when the main app sends [`AppExit`], the renderer shuts down after the frame.
+#[derive(Resource, Default)]
+struct ShutdownRequested;
+
+/// Inserted when the renderer shuts down because of an error it can't recover from, the next
+/// extract sends [`AppExit`] to the main app.
+#[derive(Resource, Default)]
+struct RendererFailed;
+
+/// Tears the renderer down in dependency order: waits for the GPU, destroys the nodes, assets and
+/// descriptors, reports leaked allocations and finally drops the device. Nothing renders after
+/// this.
+fn shutdown(world: &mut World) {
+    let renderer = world.resource::<RenderInstance>().0.clone();
+    // a lost device can still be torn down, the wait just fails right away
+    let device_lost = match renderer.wait_idle() {
+        Ok(()) => false,
+        Err(err) => {
+            error!("Failed to wait for the GPU before shutting down: {err}");
+            err == vk::Result::ERROR_DEVICE_LOST
+        }
+    };
+    info!("Shutting down the renderer");
+
+    // the GPU is idle, so every frame's screenshots can be read back. After a device loss they
+    // would only contain garbage
+    if device_lost {
+        Screenshots::discard_in_flight(world);
+    } else {
+        for frame_index in 0..renderer.frames.len() {
+            Screenshots::save_finished(world, frame_index);
+        }
+    }
+
+    world.resource_scope(|world, mut graph: Mut<SequentialPassSystem>| {
+        graph.destroy(world);
+    });
+
+    let render_instance = world.remove_resource::<RenderInstance>().unwrap();
+    let mut render_allocator = world.remove_resource::<RenderAllocator>().unwrap();
+
+    if let Some(mut assets) = world.remove_resource::<ProcessedRenderAssets>() {
+        for (_, gpu_mesh) in assets.meshes.drain() {
+            gpu_mesh.destroy_deferred(&render_instance, &mut render_allocator);
+        }
+    }
+    if let Some(mut global_descriptors) = world.remove_resource::<GlobalDescriptorSet>() {
+        global_descriptors.destroy(&render_instance, &mut render_allocator);
+    }
+    if let Some(mut breadcrumbs) = world.remove_resource::<Breadcrumbs>() {
+        breadcrumbs.destroy(&render_instance, &mut render_allocator);
+    }
+    if let Some(mut gpu_profiler) = world.remove_resource::<GpuProfiler>() {
+        gpu_profiler.destroy(&render_instance.0);
+    }
+
+    render_allocator
+        .deletion_queue
+        .flush(render_instance.device(), &mut render_allocator.allocator);
+    render_allocator
+        .allocator
+        .report_memory_leaks(log::Level::Warn);
+    // the allocator frees its memory blocks on drop, which needs the device
+    drop(render_allocator);
+
+    drop(renderer);
+    if Arc::strong_count(&render_instance.0) > 1 {
+        warn!("The renderer is still referenced during shutdown, the device won't be destroyed");
+    }
+    drop(render_instance);
+}
+
 /// Applies the commands from the extract schedule. This happens during
 /// the render schedule rather than during extraction to allow the commands to run in parallel with the
 /// main app when pipelined rendering is enabled.
@@ -305,9 +537,75 @@ fn apply_extract_commands(render_world: &mut World) {
 
 pub trait SequentialNode: Send + Sync + 'static {
     /// Updates internal node state using the current render [`World`] prior to the run method.
-    fn update(&mut self, _world: &mut World) {}
+    fn update(&mut self, _world: &mut World) -> RenderResult<()> {
+        Ok(())
+    }
+
+    /// `id` is the one the pass was added with, wrap the recorded commands in a [`PassScope`] with
+    /// it. An error skips the rest of the frame, see [`RenderError::is_recoverable`].
+    fn run(&self, world: &mut World, id: &str) -> RenderResult<()>;
+
+    /// Called between frames with the shaders that were edited and compiled, rebuild whatever
+    /// uses them. The GPU may still be executing earlier frames. When rebuilding fails, log it and
+    /// keep the old version.
+    fn reload_shaders(
+        &mut self,
+        _world: &mut World,
+        _shaders: &ReloadedShaders,
+    ) -> RenderResult<()> {
+        Ok(())
+    }
+
+    /// Destroys the Vulkan objects the node owns when the renderer shuts down. The device is idle
+    /// by the time this is called.
+    fn destroy(&mut self, _world: &mut World) {}
+}
 
-    fn run(&self, world: &mut World) -> anyhow::Result<()>;
+/// Debug labels, breadcrumbs and GPU timestamps around the commands a pass records into its
+/// primary command buffer. Begin it before anything else is recorded, outside of rendering.
+pub struct PassScope {
+    breadcrumb: u32,
+    gpu_scope: Option<GpuScope>,
+}
+
+impl PassScope {
+    pub fn begin(
+        world: &World,
+        renderer: &ExampleBase,
+        command_buffer: vk::CommandBuffer,
+        id: &str,
+    ) -> Self {
+        renderer.cmd_begin_label(command_buffer, id);
+        let breadcrumb = world
+            .resource::<Breadcrumbs>()
+            .begin_pass(renderer, command_buffer, id);
+        let gpu_scope = world
+            .resource::<GpuProfiler>()
+            .begin_scope(renderer, command_buffer, id);
+        Self {
+            breadcrumb,
+            gpu_scope,
+        }
+    }
+
+    /// Ends the scope as the last thing recorded by the pass, `draws` shows up in crash reports.
+    pub fn end(
+        self,
+        world: &World,
+        renderer: &ExampleBase,
+        command_buffer: vk::CommandBuffer,
+        draws: u32,
+    ) {
+        if let Some(gpu_scope) = self.gpu_scope {
+            world
+                .resource::<GpuProfiler>()
+                .end_scope(renderer, command_buffer, gpu_scope);
+        }
+        world
+            .resource::<Breadcrumbs>()
+            .end_pass(renderer, command_buffer, self.breadcrumb, draws);
+        renderer.cmd_end_label(command_buffer);
+    }
 }
 
 struct SequentialPass {
@@ -325,23 +623,35 @@ impl SequentialPassSystem {
         self.passes.push(SequentialPass { id, node });
     }
 
-    pub fn remove_pass(&mut self, id: &str) {
-        self.passes.retain(|pass| pass.id != id);
+    pub fn update(&mut self, world: &mut World) -> RenderResult<()> {
+        for pass in self.passes.iter_mut() {
+            pass.node.update(world)?;
+        }
+        Ok(())
     }
 
-    pub fn get_pass(&self, id: &str) -> Option<&SequentialPass> {
-        self.passes.iter().find(|pass| pass.id == id)
+    pub fn reload_shaders(
+        &mut self,
+        world: &mut World,
+        shaders: &ReloadedShaders,
+    ) -> RenderResult<()> {
+        for pass in self.passes.iter_mut() {
+            pass.node.reload_shaders(world, shaders)?;
+        }
+        Ok(())
     }
 
-    pub fn update(&mut self, world: &mut World) {
+    /// Stops at the first pass that fails.
+    pub fn run(&mut self, world: &mut World) -> RenderResult<()> {
         for pass in self.passes.iter_mut() {
-            pass.node.update(world);
+            pass.node.run(world, &pass.id)?;
         }
+        Ok(())
     }
 
-    pub fn run(&mut self, world: &mut World) {
-        for pass in self.passes.iter_mut() {
-            pass.node.run(world).unwrap();
+    pub fn destroy(&mut self, world: &mut World) {
+        for mut pass in self.passes.drain(..) {
+            pass.node.destroy(world);
         }
     }
 }
@@ -350,10 +660,31 @@ impl SequentialPassSystem {
  * This runs after all the extraction has been done
  */
 fn render_system(world: &mut World) {
-    world.resource_scope(|world, mut graph: Mut<SequentialPassSystem>| {
-        graph.update(world);
-        graph.run(world);
-    });
+    let Some(render_instance) = world.get_resource::<RenderInstance>() else {
+        // the renderer has been shut down
+        return;
+    };
+    let renderer = render_instance.0.clone();
+    if let Err(err) = render_frame(world, &renderer) {
+        if err.is_recoverable() {
+            warn!("Skipping the frame: {err}");
+        } else {
+            error!("The renderer can't recover from this, shutting it down: {err}");
+            if matches!(err, RenderError::DeviceLost) {
+                world.resource::<Breadcrumbs>().report();
+            }
+            world.init_resource::<ShutdownRequested>();
+            world.init_resource::<RendererFailed>();
+        }
+    }
+
+    renderer.end_frame();
+    renderer.check_validation();
+    drop(renderer);
+
+    if world.contains_resource::<ShutdownRequested>() {
+        shutdown(world);
+    }
 
     // update the time and send it to the app world
     let time_sender = world.resource::<TimeSender>();
@@ -362,22 +693,111 @@ fn render_system(world: &mut World) {
     );
 }
 
+fn render_frame(world: &mut World, renderer: &ExampleBase) -> RenderResult<()> {
+    renderer.begin_frame()?;
+    world.resource::<Breadcrumbs>().begin_frame();
+    world.resource::<GpuProfiler>().begin_frame(renderer);
+    world.resource_scope(|world, mut render_allocator: Mut<RenderAllocator>| {
+        render_allocator.collect_garbage(world.resource::<RenderInstance>())
+    });
+    write_camera_uniform(world, renderer.frame_index());
+    Screenshots::save_finished(world, renderer.frame_index());
+
+    let reloaded_shaders = renderer
+        .shader_watcher
+        .as_ref()
+        .map(|watcher| watcher.poll())
+        .unwrap_or_default();
+
+    world.resource_scope(|world, mut graph: Mut<SequentialPassSystem>| {
+        if !reloaded_shaders.is_empty() {
+            graph.reload_shaders(world, &reloaded_shaders)?;
+        }
+        graph.update(world)?;
+        graph.run(world)
+    })
+}
+
 #[derive(Resource)]
 pub struct RenderInstance(pub Arc<ExampleBase>);
 impl RenderInstance {
     pub fn device(&self) -> &ash::Device {
         &self.0.device
     }
+
+    pub fn pipeline_cache(&self) -> vk::PipelineCache {
+        self.0.pipeline_cache.cache
+    }
 }
 
 #[derive(Resource)]
-pub struct RenderAllocator(Allocator);
+pub struct RenderAllocator {
+    allocator: Allocator,
+    deletion_queue: DeletionQueue,
+}
 impl RenderAllocator {
+    pub fn new(allocator: Allocator) -> Self {
+        Self {
+            allocator,
+            deletion_queue: DeletionQueue::default(),
+        }
+    }
+
     pub fn allocator(&mut self) -> &mut Allocator {
-        &mut self.0
+        &mut self.allocator
+    }
+
+    /// Frees `garbage` once the GPU has finished every frame submitted so far, use this for
+    /// anything a frame in flight may still reference.
+    pub fn destroy_deferred(
+        &mut self,
+        render_instance: &RenderInstance,
+        garbage: impl Into<Garbage>,
+    ) {
+        let point = render_instance.0.timelines.graphics.last_submitted();
+        self.destroy_after(point, garbage);
+    }
+
+    /// Frees `garbage` once the GPU has reached `point`.
+    pub fn destroy_after(&mut self, point: SyncPoint, garbage: impl Into<Garbage>) {
+        self.deletion_queue.push(point, garbage.into());
+    }
+
+    /// Frees everything in the deletion queue the GPU is done with.
+    pub fn collect_garbage(&mut self, render_instance: &RenderInstance) {
+        self.deletion_queue
+            .collect(render_instance.device(), &mut self.allocator);
     }
 }
 
+/// Reads the last frame rendered by a headless [`RenderPlugin`] back to the CPU.
+pub fn read_offscreen_image(app: &mut App) -> RenderResult<::image::RgbaImage> {
+    let render_world = &mut app.sub_app_mut(RenderApp).world;
+    let renderer = render_world.resource::<RenderInstance>().0.clone();
+    let mut render_allocator = render_world.resource_mut::<RenderAllocator>();
+
+    renderer.read_offscreen_image(render_allocator.allocator())
+}
+
+/// Forwards resizes of the primary window to the swapchain, this also covers fullscreen toggles.
+fn extract_window_resize(
+    mut resized: Extract<EventReader<WindowResized>>,
+    windows: Extract<Query<&Window, With<PrimaryWindow>>>,
+    render_instance: Res<RenderInstance>,
+) {
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+    if resized.iter().last().is_none() {
+        return;
+    }
+
+    render_instance.0.resize(vk::Extent2D {
+        width: window.physical_width(),
+        height: window.physical_height(),
+    });
+}
+
 #[derive(Debug)]
 struct GpuMesh {
     vertex_buffer: Buffer,
@@ -388,166 +808,180 @@ struct GpuMesh {
 }
 
 impl GpuMesh {
-    pub fn vertex_binding_descriptors() -> VertexInputBindingDescription {
-        VertexInputBindingDescription::default()
-            .binding(0)
-            .input_rate(VertexInputRate::VERTEX)
-            .stride(std::mem::size_of::<mesh::Vertex>() as u32)
-    }
-
-    pub fn vertex_binding_descriptors2() -> VertexInputBindingDescription2EXT<'static> {
-        VertexInputBindingDescription2EXT::default()
-            .binding(0)
-            .input_rate(VertexInputRate::VERTEX)
-            .divisor(1)
-            .stride(std::mem::size_of::<mesh::Vertex>() as u32)
-    }
-
-    pub fn vertex_input_descriptors() -> [vk::VertexInputAttributeDescription; 5] {
-        return [
-            VertexInputAttributeDescription::default()
-                .binding(0)
-                .location(0)
-                .format(ash::vk::Format::R32G32B32_SFLOAT)
-                .offset(offset_of!(mesh::Vertex, position) as u32),
-            VertexInputAttributeDescription::default()
-                .binding(0)
-                .location(1)
-                .format(ash::vk::Format::R32G32B32_SFLOAT)
-                .offset(offset_of!(mesh::Vertex, normal) as u32),
-            VertexInputAttributeDescription::default()
-                .binding(0)
-                .location(2)
-                .format(ash::vk::Format::R32G32_SFLOAT)
-                .offset(offset_of!(mesh::Vertex, uv) as u32),
-            VertexInputAttributeDescription::default()
-                .binding(0)
-                .location(3)
-                .format(ash::vk::Format::R32G32B32_SFLOAT)
-                .offset(offset_of!(mesh::Vertex, tangent) as u32),
-            VertexInputAttributeDescription::default()
-                .binding(0)
-                .location(4)
-                .format(ash::vk::Format::R32G32B32A32_SFLOAT)
-                .offset(offset_of!(mesh::Vertex, color) as u32),
-        ];
-    }
+    /// Every mesh is uploaded as a single interleaved buffer of [`mesh::Vertex`].
+    pub fn vertex_layout() -> VertexLayout {
+        let attribute = |name, location, format, offset: usize| VertexAttribute {
+            name,
+            location,
+            binding: 0,
+            format,
+            offset: offset as u32,
+        };
 
-    pub fn vertex_input_descriptors2() -> [vk::VertexInputAttributeDescription2EXT<'static>; 5] {
-        return [
-            VertexInputAttributeDescription2EXT::default()
+        VertexLayout {
+            bindings: vec![VertexInputBindingDescription::default()
                 .binding(0)
-                .location(0)
-                .format(ash::vk::Format::R32G32B32_SFLOAT)
-                .offset(offset_of!(mesh::Vertex, position) as u32),
-            VertexInputAttributeDescription2EXT::default()
-                .binding(0)
-                .location(1)
-                .format(ash::vk::Format::R32G32B32_SFLOAT)
-                .offset(offset_of!(mesh::Vertex, normal) as u32),
-            VertexInputAttributeDescription2EXT::default()
-                .binding(0)
-                .location(2)
-                .format(ash::vk::Format::R32G32_SFLOAT)
-                .offset(offset_of!(mesh::Vertex, uv) as u32),
-            VertexInputAttributeDescription2EXT::default()
-                .binding(0)
-                .location(3)
-                .format(ash::vk::Format::R32G32B32_SFLOAT)
-                .offset(offset_of!(mesh::Vertex, tangent) as u32),
-            VertexInputAttributeDescription2EXT::default()
-                .binding(0)
-                .location(4)
-                .format(ash::vk::Format::R32G32B32A32_SFLOAT)
-                .offset(offset_of!(mesh::Vertex, color) as u32),
-        ];
+                .input_rate(VertexInputRate::VERTEX)
+                .stride(std::mem::size_of::<mesh::Vertex>() as u32)],
+            attributes: vec![
+                attribute(
+                    "position",
+                    0,
+                    vk::Format::R32G32B32_SFLOAT,
+                    offset_of!(mesh::Vertex, position),
+                ),
+                attribute(
+                    "normal",
+                    1,
+                    vk::Format::R32G32B32_SFLOAT,
+                    offset_of!(mesh::Vertex, normal),
+                ),
+                attribute(
+                    "uv",
+                    2,
+                    vk::Format::R32G32_SFLOAT,
+                    offset_of!(mesh::Vertex, uv),
+                ),
+                attribute(
+                    "tangent",
+                    3,
+                    vk::Format::R32G32B32_SFLOAT,
+                    offset_of!(mesh::Vertex, tangent),
+                ),
+                attribute(
+                    "color",
+                    4,
+                    vk::Format::R32G32B32A32_SFLOAT,
+                    offset_of!(mesh::Vertex, color),
+                ),
+            ],
+        }
     }
 }
 
 #[derive(Resource, Default)]
 struct ProcessedRenderAssets {
     meshes: HashMap<Handle<Mesh>, GpuMesh>,
+    /// See [`Material::shader_variant`].
+    material_variants: HashMap<HandleId, ShaderVariantKey>,
+}
+
+impl GpuMesh {
+    fn upload(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        name: &str,
+        mesh: &Mesh,
+    ) -> RenderResult<Self> {
+        let mut vertex_buffer = Buffer::new(
+            &render_instance.0,
+            render_allocator.allocator(),
+            &format!("{name} vertices"),
+            &vk::BufferCreateInfo {
+                size: mesh.vertices.len() as u64 * std::mem::size_of::<mesh::Vertex>() as u64,
+                usage: vk::BufferUsageFlags::VERTEX_BUFFER,
+                sharing_mode: vk::SharingMode::EXCLUSIVE,
+                ..Default::default()
+            },
+            MemoryLocation::CpuToGpu,
+        )?;
+        vertex_buffer.copy_from_slice(&mesh.vertices, 0);
+
+        let index_buffer = if mesh.indices.is_empty() {
+            None
+        } else {
+            let index_buffer = Buffer::new(
+                &render_instance.0,
+                render_allocator.allocator(),
+                &format!("{name} indices"),
+                &vk::BufferCreateInfo::default()
+                    .size((size_of::<u32>() * mesh.indices.len()) as vk::DeviceSize)
+                    .usage(vk::BufferUsageFlags::INDEX_BUFFER)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                MemoryLocation::CpuToGpu,
+            );
+            let mut index_buffer = match index_buffer {
+                Ok(index_buffer) => index_buffer,
+                Err(err) => {
+                    vertex_buffer.destroy(render_instance.device(), render_allocator.allocator());
+                    return Err(err);
+                }
+            };
+            index_buffer.copy_from_slice(&mesh.indices, 0);
+            Some(index_buffer)
+        };
+
+        Ok(GpuMesh {
+            vertex_buffer,
+            index_buffer,
+            vertex_count: mesh.vertices.len() as u32,
+            index_count: mesh.indices.len() as u32,
+            topology: mesh.primitive_topology,
+        })
+    }
+
+    fn destroy_deferred(
+        self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        render_allocator.destroy_deferred(render_instance, self.vertex_buffer);
+        if let Some(index_buffer) = self.index_buffer {
+            render_allocator.destroy_deferred(render_instance, index_buffer);
+        }
+    }
 }
 
 fn extract_meshes(
     objects_with_mesh: Extract<Query<&Handle<Mesh>, Changed<Handle<Mesh>>>>,
     mesh_assets: Extract<Res<Assets<Mesh>>>,
+    mut mesh_events: Extract<EventReader<AssetEvent<Mesh>>>,
+    asset_server: Extract<Res<AssetServer>>,
     render_instance: Res<RenderInstance>,
     mut render_allocator: ResMut<RenderAllocator>,
     mut processed_assets: ResMut<ProcessedRenderAssets>,
 ) {
+    for event in mesh_events.iter() {
+        if let AssetEvent::Removed { handle } = event {
+            if let Some(gpu_mesh) = processed_assets.meshes.remove(handle) {
+                gpu_mesh.destroy_deferred(&render_instance, &mut render_allocator);
+            }
+        }
+    }
+
     for mesh_handle in objects_with_mesh.iter() {
         let _ = info_span!("Extracting mesh").entered();
         // if processed_assets.meshes.contains_key(mesh_handle) {
         //     continue;
         // }
         let mesh = mesh_assets.get(mesh_handle).unwrap();
-        let vertex_buffer = {
-            let mut buf = Buffer::new(
-                &render_instance.0.device,
-                &mut render_allocator.0,
-                &vk::BufferCreateInfo {
-                    size: mesh.vertices.len() as u64 * std::mem::size_of::<mesh::Vertex>() as u64,
-                    usage: vk::BufferUsageFlags::VERTEX_BUFFER,
-                    sharing_mode: vk::SharingMode::EXCLUSIVE,
-                    ..Default::default()
-                },
-                MemoryLocation::CpuToGpu,
-            );
-
-            buf.copy_from_slice(&mesh.vertices, 0);
-            buf
-        };
-
-        let (index_buffer, index_len) = || -> (Option<Buffer>, u32) {
-            if mesh.indices.is_empty() {
-                return (None, 0);
+        let name = asset_name(&asset_server, mesh_handle);
+        let gpu_mesh = match GpuMesh::upload(&render_instance, &mut render_allocator, &name, mesh) {
+            Ok(gpu_mesh) => gpu_mesh,
+            Err(err) => {
+                error!("Failed to upload mesh {mesh_handle:?}: {err}");
+                continue;
             }
-            let mut buf = Buffer::new(
-                &render_instance.0.device,
-                &mut render_allocator.0,
-                &vk::BufferCreateInfo::default()
-                    .size((size_of::<u32>() * mesh.indices.len()) as vk::DeviceSize)
-                    .usage(vk::BufferUsageFlags::INDEX_BUFFER)
-                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
-                MemoryLocation::CpuToGpu,
-            );
+        };
 
-            buf.copy_from_slice(&mesh.indices, 0);
-            (Some(buf), mesh.indices.len() as u32)
-        }();
-
-        processed_assets.meshes.insert(
-            mesh_handle.clone(),
-            GpuMesh {
-                vertex_buffer,
-                index_buffer,
-                vertex_count: mesh.vertices.len() as u32,
-                index_count: index_len,
-                topology: mesh.primitive_topology,
-            },
-        );
+        let previous = processed_assets
+            .meshes
+            .insert(mesh_handle.clone(), gpu_mesh);
+        // frames in flight may still be drawing the old buffers
+        if let Some(previous) = previous {
+            previous.destroy_deferred(&render_instance, &mut render_allocator);
+        }
     }
+}
 
-    // cleanup old meshes and delete gpu buffers
-    // let mut keys_to_delete = vec![];
-    // for (handle, gpu_mesh) in processed_assets.meshes.iter_mut() {
-    //     if !objects_with_mesh.into_iter().any(|h| h.0 == handle) {
-    //         gpu_mesh
-    //             .vertex_buffer
-    //             .destroy(render_instance.device(), render_allocator.allocator());
-
-    //         if let Some(index_buffer) = &mut gpu_mesh.index_buffer {
-    //             index_buffer.destroy(render_instance.device(), render_allocator.allocator());
-    //         }
-
-    //         keys_to_delete.push(handle.clone());
-    //     }
-    // }
-
-    // for i in keys_to_delete.iter().rev() {
-    //     processed_assets.meshes.remove(i);
-    // }
+/// GPU resources are named after the asset path when the asset was loaded from disk, the handle
+/// id otherwise.
+fn asset_name(asset_server: &AssetServer, handle: impl Into<HandleId>) -> String {
+    let id = handle.into();
+    match asset_server.get_handle_path(id) {
+        Some(path) => path.path().display().to_string(),
+        None => format!("{id:?}"),
+    }
 }
 
 fn extract_objects(
@@ -578,6 +1012,7 @@ fn extract_textures_from_materials(
     material_assets: Extract<Res<Assets<Material>>>,
     texture_assets: Extract<Res<Assets<Image>>>,
     mut ev_asset: Extract<EventReader<AssetEvent<Image>>>,
+    asset_server: Extract<Res<AssetServer>>,
     render_instance: Res<RenderInstance>,
     mut render_allocator: ResMut<RenderAllocator>,
     mut global_descriptors: ResMut<GlobalDescriptorSet>,
@@ -585,107 +1020,51 @@ fn extract_textures_from_materials(
     for ev in ev_asset.iter() {
         match ev {
             AssetEvent::Created { handle } => {
-                let material = material_assets
+                let Some((material_handle_id, material)) = material_assets
                     .iter()
-                    .map(|(material_handle_id, material)| {
-                        if let Some(base_color_texture) = material.base_color_texture.as_ref() {
-                            if base_color_texture == handle {
-                                return Some((
-                                    material_handle_id,
-                                    base_color_texture,
-                                    offset_of!(MaterialUniform, base_color_texture_index),
-                                ));
-                            }
-                        }
-
-                        if let Some(emissive_texture) = material.emissive_texture.as_ref() {
-                            if emissive_texture == handle {
-                                return Some((
-                                    material_handle_id,
-                                    emissive_texture,
-                                    offset_of!(MaterialUniform, emissive_texture_index),
-                                ));
-                            }
-                        }
-
-                        if let Some(occlusion_texture) = material.occlusion_texture.as_ref() {
-                            if occlusion_texture == handle {
-                                return Some((
-                                    material_handle_id,
-                                    occlusion_texture,
-                                    offset_of!(MaterialUniform, occlusion_texture_index),
-                                ));
-                            }
-                        }
-
-                        if let Some(normal_map_texture) = material.normal_map_texture.as_ref() {
-                            if normal_map_texture == handle {
-                                return Some((
-                                    material_handle_id,
-                                    normal_map_texture,
-                                    offset_of!(MaterialUniform, normal_map_texture_index),
-                                ));
-                            }
-                        }
-
-                        if let Some(metallic_roughness_texture) =
-                            material.metallic_roughness_texture.as_ref()
-                        {
-                            if metallic_roughness_texture == handle {
-                                return Some((
-                                    material_handle_id,
-                                    metallic_roughness_texture,
-                                    offset_of!(MaterialUniform, metallic_roughness_texture_index),
-                                ));
-                            }
-                        }
-
-                        None
-                    })
-                    .find(|x| x.is_some())
-                    .flatten();
-
-                let Some((material_handle_id, texture_handle, bytes_offset))  = material else {
+                    .find(|(_, material)| material.textures().any(|texture| texture == handle))
+                else {
                     continue;
                 };
 
-                let texture = texture_assets.get(texture_handle).unwrap();
-                global_descriptors.textures.insert(
-                    texture_handle.clone(),
-                    crate::buffer::Image::from_image_buffer(
-                        &render_instance,
-                        &mut render_allocator,
-                        texture.data.clone(),
-                        texture.format,
-                    ),
-                );
-                let index = global_descriptors
-                    .get_texture_index(texture_handle)
-                    .unwrap() as i32;
-
-                if let Some(buffer) = global_descriptors.buffers.get_mut(&material_handle_id) {
-                    buffer.copy_from_slice(&[index], bytes_offset);
-                } else {
-                    let mut buffer: Buffer = Buffer::new(
-                        render_instance.device(),
-                        render_allocator.allocator(),
-                        &vk::BufferCreateInfo::default()
-                            .size(std::mem::size_of::<material::MaterialUniform>() as u64)
-                            .usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
-                            .sharing_mode(vk::SharingMode::EXCLUSIVE),
-                        MemoryLocation::CpuToGpu,
-                    );
-                    buffer.copy_from_slice(&[index], bytes_offset);
-                    global_descriptors
-                        .buffers
-                        .insert(material_handle_id, buffer);
+                let texture = texture_assets.get(handle).unwrap();
+                let texture = match crate::buffer::Image::from_image_buffer(
+                    &render_instance,
+                    &mut render_allocator,
+                    &asset_name(&asset_server, handle),
+                    texture.data.clone(),
+                    texture.format,
+                ) {
+                    Ok(texture) => texture,
+                    Err(err) => {
+                        error!("Failed to upload texture {handle:?}: {err}");
+                        continue;
+                    }
+                };
+                let previous = global_descriptors.insert_texture(handle.clone(), texture);
+                if let Some(previous) = previous {
+                    render_allocator.destroy_deferred(&render_instance, previous);
+                }
+
+                let uniform = material_uniform(material, &global_descriptors);
+                if let Err(err) = write_material_uniform(
+                    &render_instance,
+                    &mut render_allocator,
+                    &mut global_descriptors,
+                    &asset_name(&asset_server, material_handle_id),
+                    material_handle_id,
+                    &uniform,
+                ) {
+                    error!("Failed to create the material buffer: {err}");
                 }
             }
-            AssetEvent::Modified { handle } => {
+            AssetEvent::Modified { .. } => {
                 // an image was modified
             }
             AssetEvent::Removed { handle } => {
-                // an image was unloaded
+                if let Some(texture) = global_descriptors.remove_texture(handle) {
+                    render_allocator.destroy_deferred(&render_instance, texture);
+                }
             }
         }
     }
@@ -695,54 +1074,115 @@ fn extract_materials(
     materials: Extract<Query<&Handle<Material>, Changed<Handle<Material>>>>,
     material_assets: Extract<Res<Assets<Material>>>,
     texture_assets: Extract<Res<Assets<Image>>>,
+    mut material_events: Extract<EventReader<AssetEvent<Material>>>,
+    asset_server: Extract<Res<AssetServer>>,
     render_instance: Res<RenderInstance>,
     mut render_allocator: ResMut<RenderAllocator>,
     mut global_descriptors: ResMut<GlobalDescriptorSet>,
+    mut processed_assets: ResMut<ProcessedRenderAssets>,
 ) {
+    for event in material_events.iter() {
+        if let AssetEvent::Removed { handle } = event {
+            if let Some(buffer) = global_descriptors.buffers.remove(&handle.id()) {
+                render_allocator.destroy_deferred(&render_instance, buffer);
+            }
+            processed_assets.material_variants.remove(&handle.id());
+        }
+    }
+
     for handle in materials.iter() {
         let _ = info_span!("Extracting material").entered();
         let material = material_assets.get(handle).unwrap();
-        let mut material_buffer = MaterialUniform::from_material(material);
+        processed_assets
+            .material_variants
+            .insert(handle.id(), material.shader_variant());
 
         if let Some(handle) = material.base_color_texture.as_ref() {
             if let Some(img) = texture_assets.get(handle) {
-                let mut texture = crate::buffer::Image::from_image_buffer(
+                let texture = crate::buffer::Image::from_image_buffer(
                     &render_instance,
                     &mut render_allocator,
+                    &asset_name(&asset_server, handle),
                     img.data.clone(),
                     img.format,
-                );
-
-                let _ = texture.create_view(render_instance.device());
-                global_descriptors.textures.insert(handle.clone(), texture);
-                material_buffer.base_color_texture_index =
-                    global_descriptors.get_texture_index(handle).unwrap() as i32;
+                )
+                .and_then(|mut texture| {
+                    texture.create_view(render_instance.device())?;
+                    Ok(texture)
+                });
+                match texture {
+                    Ok(texture) => {
+                        let previous = global_descriptors.insert_texture(handle.clone(), texture);
+                        if let Some(previous) = previous {
+                            render_allocator.destroy_deferred(&render_instance, previous);
+                        }
+                    }
+                    // the material is still drawn, without the texture
+                    Err(err) => error!("Failed to upload texture {handle:?}: {err}"),
+                }
             }
         }
 
-        if let Some(buffer) = global_descriptors.buffers.get_mut(&handle.id()) {
-            buffer.copy_from_slice(&[material_buffer], 0);
-        } else {
-            let buffer = {
-                let mut buf = Buffer::new(
-                    render_instance.device(),
-                    render_allocator.allocator(),
-                    &vk::BufferCreateInfo {
-                        size: std::mem::size_of::<material::MaterialUniform>() as u64,
-                        usage: vk::BufferUsageFlags::UNIFORM_BUFFER,
-                        sharing_mode: vk::SharingMode::EXCLUSIVE,
-                        ..Default::default()
-                    },
-                    MemoryLocation::CpuToGpu,
-                );
+        let uniform = material_uniform(material, &global_descriptors);
+        if let Err(err) = write_material_uniform(
+            &render_instance,
+            &mut render_allocator,
+            &mut global_descriptors,
+            &asset_name(&asset_server, handle),
+            handle.id(),
+            &uniform,
+        ) {
+            error!("Failed to create the buffer of material {handle:?}: {err}");
+        }
+    }
+}
 
-                buf.copy_from_slice(&[material_buffer], 0);
-                buf
-            };
+/// The uniform of `material`, pointing at the slots of its textures that are uploaded.
+fn material_uniform(
+    material: &Material,
+    global_descriptors: &GlobalDescriptorSet,
+) -> MaterialUniform {
+    let index = |texture: &Option<Handle<Image>>| {
+        texture
+            .as_ref()
+            .and_then(|texture| global_descriptors.get_texture_index(texture))
+            .map_or(-1, |index| index as i32)
+    };
+    MaterialUniform {
+        base_color_texture_index: index(&material.base_color_texture),
+        emissive_texture_index: index(&material.emissive_texture),
+        metallic_roughness_texture_index: index(&material.metallic_roughness_texture),
+        normal_map_texture_index: index(&material.normal_map_texture),
+        occlusion_texture_index: index(&material.occlusion_texture),
+        ..MaterialUniform::from_material(material)
+    }
+}
 
-            global_descriptors.buffers.insert(handle.id(), buffer);
-        }
+/// Puts `uniform` in a new buffer instead of writing the material's current one, which frames in
+/// flight may still be reading. The current one is freed once they're done with it.
+fn write_material_uniform(
+    render_instance: &RenderInstance,
+    render_allocator: &mut RenderAllocator,
+    global_descriptors: &mut GlobalDescriptorSet,
+    name: &str,
+    id: HandleId,
+    uniform: &MaterialUniform,
+) -> RenderResult<()> {
+    let mut buffer = Buffer::new(
+        &render_instance.0,
+        render_allocator.allocator(),
+        name,
+        &vk::BufferCreateInfo::default()
+            .size(size_of::<MaterialUniform>() as u64)
+            .usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        MemoryLocation::CpuToGpu,
+    )?;
+    buffer.copy_from_slice(std::slice::from_ref(uniform), 0);
+    if let Some(previous) = global_descriptors.buffers.insert(id, buffer) {
+        render_allocator.destroy_deferred(render_instance, previous);
     }
+    Ok(())
 }
 
 #[repr(C, align(16))]
@@ -756,15 +1196,15 @@ struct CameraBuffer {
     inverse_proj: Mat4,
     world_position: Vec3,
 }
-pub static CAMERA_HANDLE: once_cell::sync::Lazy<HandleId> =
-    once_cell::sync::Lazy::new(|| HandleId::from(String::from("camera")));
+/// The latest camera uniform. Copied into the camera buffer of every frame in flight as it starts,
+/// the buffers of frames the GPU is still reading from can't be touched.
+#[derive(Resource, Default)]
+struct ExtractedCamera(Option<CameraBuffer>);
 
 /// only runs whenever the camera component or transform component changes
 fn extract_camera_uniform(
     camera: Extract<Query<(&Camera, &Transform), Or<(Changed<Camera>, Changed<Transform>)>>>,
-    mut global_descriptor_set: ResMut<GlobalDescriptorSet>,
-    render_instance: Res<RenderInstance>,
-    mut render_allocator: ResMut<RenderAllocator>,
+    mut extracted_camera: ResMut<ExtractedCamera>,
 ) {
     let Ok((camera, camera_transform)) = camera.get_single() else {
         return;
@@ -776,7 +1216,7 @@ fn extract_camera_uniform(
     let projection = camera.projection;
     let inverse_projection = projection.inverse();
 
-    let uniform = CameraBuffer {
+    extracted_camera.0 = Some(CameraBuffer {
         view_proj: projection * inverse_view,
         inverse_view_proj: view * inverse_projection,
         view,
@@ -784,36 +1224,39 @@ fn extract_camera_uniform(
         proj: projection,
         inverse_proj: inverse_projection,
         world_position: camera_transform.translation,
+    });
+}
+
+/// Runs after the fence of the current frame has been waited on, so its camera buffer is free.
+fn write_camera_uniform(world: &mut World, frame_index: usize) {
+    let Some(uniform) = world.resource::<ExtractedCamera>().0 else {
+        return;
     };
 
-    if let Some(buffer) = global_descriptor_set.buffers.get_mut(&CAMERA_HANDLE) {
-        buffer.copy_from_slice(&[uniform], 0);
-    } else {
-        let mut buffer: Buffer = Buffer::new(
-            render_instance.device(),
-            render_allocator.allocator(),
-            &vk::BufferCreateInfo::default()
-                .size(std::mem::size_of::<CameraBuffer>() as u64)
-                .usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
-                .sharing_mode(vk::SharingMode::EXCLUSIVE),
-            MemoryLocation::CpuToGpu,
-        );
-        buffer.copy_from_slice(&[uniform], 0);
-        global_descriptor_set.buffers.insert(*CAMERA_HANDLE, buffer);
-    }
+    // this happens every frame, don't trigger a descriptor set update for it
+    world
+        .resource_mut::<GlobalDescriptorSet>()
+        .bypass_change_detection()
+        .camera_buffers[frame_index]
+        .copy_from_slice(&[uniform], 0);
 }
 
 fn basic_renderer_setup(
     mut sequential_pass_system: ResMut<SequentialPassSystem>,
     render_instance: Res<RenderInstance>,
     mut render_allocator: ResMut<RenderAllocator>,
+    backend: Res<RenderBackend>,
+    mut failed: Local<bool>,
 ) {
-    if !sequential_pass_system.passes.is_empty() {
+    if !sequential_pass_system.passes.is_empty() || *failed {
         return;
     }
 
-    sequential_pass_system.add_pass(
-        "present_node".into(),
-        Box::new(PresentNode::new(&render_instance, &mut render_allocator)),
-    );
+    match PresentNode::new(&render_instance, &mut render_allocator, *backend) {
+        Ok(node) => sequential_pass_system.add_pass("present_node".into(), Box::new(node)),
+        Err(err) => {
+            error!("Failed to create the present node, nothing will be drawn: {err}");
+            *failed = true;
+        }
+    }
 }
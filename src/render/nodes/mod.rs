@@ -1,21 +1,45 @@
-use std::mem::size_of;
+use std::{
+    collections::{HashMap, HashSet},
+    mem::size_of,
+};
 
-use ash::vk::{self, PipelineBindPoint, RenderingFlags, SampleCountFlags, ShaderStageFlags};
+use ash::vk::{self, RenderingFlags, SampleCountFlags};
 use bevy::prelude::*;
 
 use crate::ctx::record_submit_commandbuffer;
+use crate::error::{RenderError, RenderResult};
+use crate::sync::SubmitSemaphores;
 
 use super::{
+    global_descriptors::GlobalDescriptorSet,
+    gpu_profiler::GpuProfiler,
+    hot_reload::ReloadedShaders,
     material::Material,
     mesh::Mesh,
-    pipeline::{GraphicsPipeline, GraphicsPipelineDescriptor, PrimitiveState},
-    shaders::Shader,
-    GpuMesh, ProcessedRenderAssets, RenderAllocator, RenderInstance, SequentialNode, CAMERA_HANDLE,
+    pipeline::{
+        GraphicsPipeline, GraphicsPipelineDescriptor, PrimitiveState, RenderPipeline,
+        ShaderObjectPipeline,
+    },
+    screenshot::Screenshots,
+    shaders::{Shader, ShaderKind, ShaderVariantKey},
+    GpuMesh, PassScope, ProcessedRenderAssets, RenderAllocator, RenderBackend, RenderInstance,
+    SequentialNode,
 };
 
+const VERTEX_SHADER: &str = "./shader/main.vert";
+const FRAGMENT_SHADER: &str = "./shader/main.frag";
+
 #[derive(Debug)]
 pub struct PresentNode {
-    pipeline: GraphicsPipeline,
+    /// One pipeline per shader variant the materials use, built on demand in `update`. The
+    /// default variant is always there and draws everything whose variant didn't build.
+    pipelines: HashMap<ShaderVariantKey, RenderPipeline>,
+    /// Variants that failed to build, tried again when the shaders are reloaded.
+    failed_variants: HashSet<ShaderVariantKey>,
+    /// The [`GlobalDescriptorSet::generation`] each frame's copy of a pipeline's first set was
+    /// last written at, `None` until it's written.
+    written_generations: HashMap<ShaderVariantKey, Vec<Option<u64>>>,
+    backend: RenderBackend,
     draw_command_recording_chunk_size: usize,
 }
 
@@ -28,106 +52,330 @@ struct PushConstants {
 }
 
 impl PresentNode {
-    pub fn new(render_instance: &RenderInstance, _render_allocator: &mut RenderAllocator) -> Self {
-        let vert = Shader::from_file(
+    /// Falls back to the magenta error shaders when the regular ones don't compile.
+    pub fn new(
+        render_instance: &RenderInstance,
+        _render_allocator: &mut RenderAllocator,
+        backend: RenderBackend,
+    ) -> RenderResult<Self> {
+        let no_reloads = ReloadedShaders::default();
+        let default_variant = ShaderVariantKey::default();
+        let (vert, frag) = match load_shaders(
             render_instance,
-            "./shader/main.vert",
-            super::shaders::ShaderKind::Vertex,
-            "main",
-        );
-        let frag = Shader::from_file(
-            render_instance,
-            "./shader/main.frag",
-            super::shaders::ShaderKind::Fragment,
-            "main",
-        );
+            &no_reloads,
+            VERTEX_SHADER,
+            FRAGMENT_SHADER,
+            &default_variant,
+        ) {
+            Ok(shaders) => shaders,
+            Err(err) => {
+                error!("{err}, drawing with the error shader instead");
+                load_shaders(
+                    render_instance,
+                    &no_reloads,
+                    "./shader/error.vert",
+                    "./shader/error.frag",
+                    &default_variant,
+                )?
+            }
+        };
+        let pipeline = create_pipeline(render_instance, backend, &default_variant, vert, frag)?;
+
+        Ok(Self {
+            pipelines: HashMap::from([(default_variant, pipeline)]),
+            failed_variants: HashSet::new(),
+            written_generations: HashMap::new(),
+            backend,
+            draw_command_recording_chunk_size: 50,
+        })
+    }
 
-        let pipeline = GraphicsPipeline::new(
+    fn build_variant(
+        &self,
+        render_instance: &RenderInstance,
+        reloaded: &ReloadedShaders,
+        variant: &ShaderVariantKey,
+    ) -> RenderResult<RenderPipeline> {
+        let (vert, frag) = load_shaders(
             render_instance,
-            GraphicsPipelineDescriptor {
-                vertex_shader: vert,
-                vertex_input: vk::PipelineVertexInputStateCreateInfo::default()
-                    .vertex_binding_descriptions(&[GpuMesh::vertex_binding_descriptors()])
-                    .vertex_attribute_descriptions(&GpuMesh::vertex_input_descriptors()),
-                fragment_shader: frag,
-                primitive: PrimitiveState {
-                    topology: vk::PrimitiveTopology::TRIANGLE_LIST,
-                    ..Default::default()
-                },
-                depth_stencil: None,
-                push_constant_range: Some(
-                    vk::PushConstantRange::default()
-                        .stage_flags(ShaderStageFlags::ALL_GRAPHICS)
-                        .offset(0)
-                        .size(size_of::<PushConstants>() as u32),
-                ),
-                viewport: render_instance.0.surface_resolution,
-            },
-        );
+            reloaded,
+            VERTEX_SHADER,
+            FRAGMENT_SHADER,
+            variant,
+        )?;
+        create_pipeline(render_instance, self.backend, variant, vert, frag)
+    }
 
-        Self {
-            pipeline,
-            draw_command_recording_chunk_size: 50,
+    /// Builds the pipelines of variants materials started using since the last frame.
+    fn build_missing_variants(&mut self, world: &mut World) -> RenderResult<()> {
+        let missing = world
+            .resource::<ProcessedRenderAssets>()
+            .material_variants
+            .values()
+            .filter(|variant| {
+                !self.pipelines.contains_key(variant) && !self.failed_variants.contains(variant)
+            })
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        for variant in missing {
+            let render_instance = world.resource::<RenderInstance>();
+            match self.build_variant(render_instance, &ReloadedShaders::default(), &variant) {
+                Ok(pipeline) => {
+                    self.pipelines.insert(variant, pipeline);
+                }
+                Err(err) => {
+                    error!("Failed to build the present pipeline for {variant:?}, drawing with the default one instead: {err}");
+                    self.failed_variants.insert(variant);
+                }
+            }
         }
+        Ok(())
+    }
+
+    fn default_pipeline(&self) -> &RenderPipeline {
+        &self.pipelines[&ShaderVariantKey::default()]
     }
 }
 
-impl SequentialNode for PresentNode {
-    #[tracing::instrument(name = "PresentNode::update", skip_all)]
-    fn update(&mut self, world: &mut bevy::prelude::World) {
-        if !world
-            .resource_mut::<super::global_descriptors::GlobalDescriptorSet>()
-            .is_changed()
+/// Puts secondary command buffers back into the initial state after recording them failed, so the
+/// next frame can begin them again.
+unsafe fn reset_secondary_command_buffers(
+    device: &ash::Device,
+    command_buffers: impl IntoIterator<Item = vk::CommandBuffer>,
+) {
+    for command_buffer in command_buffers {
+        if let Err(err) =
+            device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())
         {
-            return;
+            error!("Failed to reset a secondary command buffer: {err}");
+        }
+    }
+}
+
+fn create_pipeline(
+    render_instance: &RenderInstance,
+    backend: RenderBackend,
+    variant: &ShaderVariantKey,
+    vert: Shader,
+    frag: Shader,
+) -> RenderResult<RenderPipeline> {
+    let label = std::iter::once("present")
+        .chain(variant.defines.keys().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let vertex_layout = GpuMesh::vertex_layout();
+    let descriptor = GraphicsPipelineDescriptor {
+        label: &label,
+        vertex_shader: vert,
+        vertex_layout: &vertex_layout,
+        fragment_shader: frag,
+        primitive: PrimitiveState {
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            ..Default::default()
+        },
+        depth_stencil: None,
+        viewport: render_instance.0.surface_resolution(),
+    };
+
+    let pipeline = match backend {
+        RenderBackend::Pipeline => {
+            RenderPipeline::Graphics(GraphicsPipeline::new(render_instance, descriptor)?)
         }
+        RenderBackend::ShaderObject => {
+            RenderPipeline::ShaderObject(ShaderObjectPipeline::new(render_instance, descriptor)?)
+        }
+    };
+
+    // the layout comes from the shaders, their push constant block has to fit what `run` pushes
+    let push_constants_fit = pipeline
+        .push_constant_range()
+        .is_some_and(|range| range.offset == 0 && range.size >= size_of::<PushConstants>() as u32);
+    if !push_constants_fit {
+        pipeline.destroy(&render_instance.0);
+        return Err(RenderError::ShaderReflection {
+            name: label,
+            message: format!(
+                "the shaders don't declare a push constant block of at least {} bytes at offset 0",
+                size_of::<PushConstants>()
+            ),
+        });
+    }
+    Ok(pipeline)
+}
+
+/// Takes the shaders from `reloaded` when they're in there, compiles them otherwise.
+fn load_shaders(
+    render_instance: &RenderInstance,
+    reloaded: &ReloadedShaders,
+    vertex_path: &str,
+    fragment_path: &str,
+    variant: &ShaderVariantKey,
+) -> RenderResult<(Shader, Shader)> {
+    let vert = load_shader(
+        render_instance,
+        reloaded,
+        vertex_path,
+        ShaderKind::Vertex,
+        variant,
+    )?;
+    match load_shader(
+        render_instance,
+        reloaded,
+        fragment_path,
+        ShaderKind::Fragment,
+        variant,
+    ) {
+        Ok(frag) => Ok((vert, frag)),
+        Err(err) => {
+            vert.destroy(render_instance.device());
+            Err(err)
+        }
+    }
+}
+
+fn load_shader(
+    render_instance: &RenderInstance,
+    reloaded: &ReloadedShaders,
+    path: &str,
+    kind: ShaderKind,
+    variant: &ShaderVariantKey,
+) -> RenderResult<Shader> {
+    match reloaded.get(path, variant) {
+        Some(compiled) => Shader::new(
+            render_instance,
+            path,
+            &compiled.spirv,
+            kind,
+            "main",
+            variant,
+        ),
+        None => Shader::from_file_variant(render_instance, path, kind, "main", variant),
+    }
+}
+
+impl SequentialNode for PresentNode {
+    #[tracing::instrument(name = "PresentNode::update", skip_all)]
+    fn update(&mut self, world: &mut bevy::prelude::World) -> RenderResult<()> {
+        self.build_missing_variants(world)?;
 
         world.resource_scope(
-            |world, mut global_descriptors: Mut<super::global_descriptors::GlobalDescriptorSet>| {
-                global_descriptors.update_descriptor_set(
-                    self.pipeline.descriptor_sets[0],
-                    world.resource::<RenderInstance>(),
-                )
+            |world, mut global_descriptors: Mut<GlobalDescriptorSet>| -> RenderResult<()> {
+                let render_instance = world.resource::<RenderInstance>();
+                let frame_count = render_instance.0.frames.len();
+                let frame_index = render_instance.0.frame_index();
+                let generation = global_descriptors.generation();
+                for (variant, pipeline) in &self.pipelines {
+                    let written = self
+                        .written_generations
+                        .entry(variant.clone())
+                        .or_insert_with(|| vec![None; frame_count]);
+                    if written[frame_index] == Some(generation) {
+                        continue;
+                    }
+                    // `begin_frame` waited for the last frame that used this copy, the other
+                    // frames in flight have their own
+                    global_descriptors.update_descriptor_set(
+                        pipeline.descriptor_sets(frame_index)[0],
+                        render_instance,
+                    )?;
+                    written[frame_index] = Some(generation);
+                }
+                Ok(())
             },
-        );
+        )
     }
 
-    #[tracing::instrument(name = "PresentNode::run", skip_all)]
-    fn run(&self, world: &mut bevy::prelude::World) -> anyhow::Result<()> {
-        let mut objects = world.query::<(&Handle<Mesh>, &Handle<Material>, &Transform)>();
-        let assets = world.resource::<ProcessedRenderAssets>();
-        let global_descriptors = world.resource::<super::global_descriptors::GlobalDescriptorSet>();
+    fn reload_shaders(&mut self, world: &mut World, shaders: &ReloadedShaders) -> RenderResult<()> {
+        if !shaders.contains(VERTEX_SHADER) && !shaders.contains(FRAGMENT_SHADER) {
+            return Ok(());
+        }
 
         let render_instance = world.resource::<RenderInstance>();
+        let variants = self
+            .pipelines
+            .keys()
+            .cloned()
+            .chain(self.failed_variants.drain())
+            .collect::<Vec<_>>();
+        let mut rebuilt = vec![];
+        for variant in variants {
+            match self.build_variant(render_instance, shaders, &variant) {
+                Ok(pipeline) => rebuilt.push((variant, pipeline)),
+                Err(err) => {
+                    error!("Failed to rebuild the present pipeline for {variant:?}, keeping the old one: {err}");
+                    if !self.pipelines.contains_key(&variant) {
+                        self.failed_variants.insert(variant);
+                    }
+                }
+            }
+        }
+        if rebuilt.is_empty() {
+            return Ok(());
+        }
+
+        // frames in flight may still be using the old pipelines and their descriptor sets
+        render_instance.0.wait_idle()?;
+        for (variant, pipeline) in rebuilt {
+            // `update` writes the global descriptors into the new sets before they're used
+            self.written_generations.remove(&variant);
+            if let Some(old) = self.pipelines.insert(variant, pipeline) {
+                old.destroy(&render_instance.0);
+            }
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "PresentNode::run", skip_all)]
+    fn run(&self, world: &mut bevy::prelude::World, id: &str) -> RenderResult<()> {
+        let mut objects = world.query::<(&Handle<Mesh>, &Handle<Material>, &Transform)>();
+        let render_instance = world.resource::<RenderInstance>().0.clone();
         let objects_count = objects.iter(world).count();
 
         if objects_count == 0 {
             return Ok(());
         }
 
-        let renderer = render_instance.0.as_ref();
-        let present_index = unsafe {
-            renderer
-                .swapchain_loader
-                .acquire_next_image(
-                    renderer.swapchain,
-                    std::u64::MAX,
-                    renderer.present_complete_semaphore,
-                    vk::Fence::null(),
-                )
-                .unwrap()
-                .0
+        let renderer = render_instance.as_ref();
+        let frame = renderer.current_frame();
+        let Some(present_index) = renderer.acquire_next_image(frame.present_complete_semaphore)?
+        else {
+            // minimized or out of date, the swapchain gets rebuilt on the next acquire
+            return Ok(());
         };
+        let swapchain = renderer.swapchain.read().unwrap();
+        let screenshot_buffer = Screenshots::prepare(world, &swapchain);
 
-        record_submit_commandbuffer(
+        let assets = world.resource::<ProcessedRenderAssets>();
+        let global_descriptors = world.resource::<super::global_descriptors::GlobalDescriptorSet>();
+        let profiler = world.resource::<GpuProfiler>();
+
+        // uploads released to this queue since the last frame
+        let handoffs = renderer.take_queue_handoffs();
+        let rendered = renderer.timelines.graphics.next();
+        let semaphores = handoffs.iter().fold(
+            SubmitSemaphores::default()
+                .wait_binary(
+                    frame.present_complete_semaphore,
+                    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                )
+                .signal_binary(frame.rendering_complete_semaphore)
+                .signal(rendered),
+            |semaphores, handoff| {
+                semaphores.wait(handoff.released, vk::PipelineStageFlags::ALL_COMMANDS)
+            },
+        );
+
+        let submitted = record_submit_commandbuffer(
             &renderer.device,
-            renderer.draw_command_buffer,
-            renderer.draw_commands_reuse_fence,
-            renderer.present_queue,
-            &[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT],
-            &[renderer.present_complete_semaphore],
-            &[renderer.rendering_complete_semaphore],
+            frame.command_buffer,
+            frame.in_flight_fence,
+            &renderer.present_queue,
+            &semaphores,
             |device, draw_command_buffer| unsafe {
+                let pass_scope = PassScope::begin(world, renderer, draw_command_buffer, id);
+                renderer.cmd_acquire_from_queues(draw_command_buffer, &handoffs);
+
                 {
                     let image_memory_barrier = vk::ImageMemoryBarrier2::default()
                         .src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
@@ -136,7 +384,7 @@ impl SequentialNode for PresentNode {
                         .dst_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
                         .dst_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
                         .new_layout(vk::ImageLayout::ATTACHMENT_OPTIMAL)
-                        .image(renderer.present_images[present_index as usize])
+                        .image(swapchain.present_images[present_index as usize])
                         .subresource_range(vk::ImageSubresourceRange {
                             aspect_mask: vk::ImageAspectFlags::COLOR,
                             layer_count: 1,
@@ -153,7 +401,7 @@ impl SequentialNode for PresentNode {
                 }
 
                 let color_attach = &[vk::RenderingAttachmentInfo::default()
-                    .image_view(renderer.present_image_views[present_index as usize])
+                    .image_view(swapchain.present_image_views[present_index as usize])
                     .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
                     .load_op(vk::AttachmentLoadOp::CLEAR)
                     .store_op(vk::AttachmentStoreOp::STORE)
@@ -164,7 +412,7 @@ impl SequentialNode for PresentNode {
                     })];
 
                 let depth_attach = &vk::RenderingAttachmentInfo::default()
-                    .image_view(renderer.depth_image_view)
+                    .image_view(swapchain.depth_image_view)
                     .image_layout(vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL)
                     .load_op(vk::AttachmentLoadOp::CLEAR)
                     .store_op(vk::AttachmentStoreOp::STORE)
@@ -177,7 +425,7 @@ impl SequentialNode for PresentNode {
 
                 let render_pass_begin_info = vk::RenderingInfo::default()
                     .flags(RenderingFlags::CONTENTS_SECONDARY_COMMAND_BUFFERS)
-                    .render_area(renderer.surface_resolution.into())
+                    .render_area(swapchain.surface_resolution.into())
                     .layer_count(1)
                     .color_attachments(color_attach)
                     .depth_attachment(depth_attach);
@@ -186,43 +434,10 @@ impl SequentialNode for PresentNode {
                     .dynamic_rendering
                     .cmd_begin_rendering(draw_command_buffer, &render_pass_begin_info);
 
-                device.cmd_bind_pipeline(
-                    draw_command_buffer,
-                    PipelineBindPoint::GRAPHICS,
-                    self.pipeline.pipeline,
-                );
-
-                device.cmd_bind_descriptor_sets(
-                    draw_command_buffer,
-                    PipelineBindPoint::GRAPHICS,
-                    self.pipeline.layout,
-                    0,
-                    &self.pipeline.descriptor_sets,
-                    &[],
-                );
-
-                device.cmd_set_viewport(
-                    draw_command_buffer,
-                    0,
-                    &[vk::Viewport {
-                        x: 0.0,
-                        y: 0.0,
-                        width: renderer.surface_resolution.width as f32,
-                        height: renderer.surface_resolution.height as f32,
-                        min_depth: 0.0,
-                        max_depth: 1.0,
-                    }],
-                );
-
-                device.cmd_set_scissor(
-                    draw_command_buffer,
-                    0,
-                    &[renderer.surface_resolution.into()],
-                );
-
-                let secondary_command_buffers = renderer.threaded_command_buffers.read().unwrap();
+                let secondary_command_buffers = frame.threaded_command_buffers.read().unwrap();
                 // reset all secondary command buffers
-                secondary_command_buffers.iter().for_each(|(_, buffer)| {
+                for (_, thread) in secondary_command_buffers.iter() {
+                    let buffer = &thread.command_buffer;
                     let color_attachment_formats = &[renderer.surface_format.format];
                     let mut command_buffer_inheritance_info =
                         vk::CommandBufferInheritanceRenderingInfo::default()
@@ -238,41 +453,88 @@ impl SequentialNode for PresentNode {
                         .flags(vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE)
                         .inheritance_info(&inheritence_info);
 
-                    device
-                        .begin_command_buffer(*buffer, &command_buffer_begin_info)
-                        .expect("Begin commandbuffer");
-                });
+                    if let Err(err) =
+                        device.begin_command_buffer(*buffer, &command_buffer_begin_info)
+                    {
+                        // the ones already begun would still be recording when the next frame
+                        // begins them again
+                        reset_secondary_command_buffers(
+                            device,
+                            secondary_command_buffers
+                                .values()
+                                .map(|thread| thread.command_buffer)
+                                .take_while(|command_buffer| command_buffer != buffer),
+                        );
+                        return Err(err.into());
+                    }
+
+                    // secondary command buffers don't inherit any bound state from the primary
+                    self.default_pipeline()
+                        .bind(renderer, *buffer, swapchain.surface_resolution);
+                }
+
+                // meshes and materials whose upload failed have nothing to draw with
+                let drawable = objects
+                    .iter(world)
+                    .filter(|(mesh_handle, material_handle, _)| {
+                        assets.meshes.contains_key(*mesh_handle)
+                            && global_descriptors
+                                .buffers
+                                .contains_key(&material_handle.id())
+                    })
+                    .collect::<Vec<_>>();
+                let drawn = drawable.len();
 
                 let chunk_amount = self.draw_command_recording_chunk_size;
                 let chunked_handles: Vec<Vec<(&Handle<Mesh>, &Handle<Material>, &Transform)>> =
-                    objects
-                        .iter(world)
-                        .collect::<Vec<_>>()
+                    drawable
                         .chunks(chunk_amount)
                         .map(|c| c.to_vec())
                         .collect::<Vec<_>>();
 
                 let queue =
                     crossbeam_queue::ArrayQueue::<usize>::new(chunked_handles.len() * chunk_amount);
-                let camera_pointer = global_descriptors
-                    .buffers
-                    .get(&CAMERA_HANDLE)
-                    .unwrap()
-                    .device_addr;
+                let camera_pointer =
+                    global_descriptors.camera_buffers[renderer.frame_index()].device_addr;
+                let extent = swapchain.surface_resolution;
 
-                render_instance.0.command_thread_pool.scope(|scope| {
+                renderer.command_thread_pool.scope(|scope| {
                     let _ = info_span!("PresentNode::run::recording_draw_commands").entered();
-                    for chunk in chunked_handles.iter() {
-                        scope.spawn(|_| {
+                    // the spawned closures move their chunk index, everything else is borrowed
+                    let queue = &queue;
+                    for (chunk_index, chunk) in chunked_handles.iter().enumerate() {
+                        scope.spawn(move |_| {
                             let thread_index = rayon::current_thread_index().unwrap();
-                            let command_buffers = renderer.threaded_command_buffers.read().unwrap();
+                            let command_buffers = frame.threaded_command_buffers.read().unwrap();
                             let command_buffer = command_buffers.get(&thread_index).unwrap();
-                            let draw_command_buffer = *command_buffer;
+                            let draw_command_buffer = command_buffer.command_buffer;
+                            let chunk_label = format!("{id} chunk {chunk_index}");
+                            renderer.cmd_begin_label(draw_command_buffer, &chunk_label);
+                            let gpu_scope = profiler.begin_secondary_scope(
+                                renderer,
+                                draw_command_buffer,
+                                &chunk_label,
+                            );
+                            // the thread may have recorded another chunk into the buffer before
+                            let mut bound_pipeline = None;
                             for (mesh_handle, material_handle, transform) in chunk.iter() {
+                                let pipeline = assets
+                                    .material_variants
+                                    .get(&material_handle.id())
+                                    .and_then(|variant| self.pipelines.get(variant))
+                                    .unwrap_or_else(|| self.default_pipeline());
+                                if bound_pipeline != Some(pipeline.layout()) {
+                                    pipeline.bind(renderer, draw_command_buffer, extent);
+                                    bound_pipeline = Some(pipeline.layout());
+                                }
+
                                 device.cmd_push_constants(
                                     draw_command_buffer,
-                                    self.pipeline.layout,
-                                    vk::ShaderStageFlags::ALL_GRAPHICS,
+                                    pipeline.layout(),
+                                    pipeline
+                                        .push_constant_range()
+                                        .expect("checked in create_pipeline")
+                                        .stage_flags,
                                     0,
                                     bytemuck::bytes_of(&PushConstants {
                                         model: transform.compute_matrix(),
@@ -280,12 +542,13 @@ impl SequentialNode for PresentNode {
                                         material_pointer: global_descriptors
                                             .buffers
                                             .get(&material_handle.id())
-                                            .unwrap()
+                                            .expect("filtered out above")
                                             .device_addr,
                                     }),
                                 );
 
-                                let mesh = &assets.meshes.get(mesh_handle).unwrap();
+                                let mesh =
+                                    &assets.meshes.get(mesh_handle).expect("filtered out above");
 
                                 device.cmd_bind_vertex_buffers(
                                     draw_command_buffer,
@@ -318,16 +581,26 @@ impl SequentialNode for PresentNode {
                                     );
                                 }
                             }
+                            if let Some(gpu_scope) = gpu_scope {
+                                profiler.end_scope(renderer, draw_command_buffer, gpu_scope);
+                            }
+                            renderer.cmd_end_label(draw_command_buffer);
                             queue.push(thread_index).unwrap();
                         });
                     }
                 });
 
-                secondary_command_buffers.iter().for_each(|(_, buffer)| {
-                    device
-                        .end_command_buffer(*buffer)
-                        .expect("End commandbuffer");
-                });
+                for (_, thread) in secondary_command_buffers.iter() {
+                    if let Err(err) = device.end_command_buffer(thread.command_buffer) {
+                        reset_secondary_command_buffers(
+                            device,
+                            secondary_command_buffers
+                                .values()
+                                .map(|thread| thread.command_buffer),
+                        );
+                        return Err(err.into());
+                    }
+                }
 
                 let queue = queue.into_iter().collect::<Vec<_>>();
 
@@ -336,7 +609,7 @@ impl SequentialNode for PresentNode {
                     &secondary_command_buffers
                         .iter()
                         .filter(|(thread_index, _)| queue.contains(thread_index))
-                        .map(|(_, buffer)| *buffer)
+                        .map(|(_, thread)| thread.command_buffer)
                         .collect::<Vec<_>>(),
                 );
 
@@ -344,15 +617,24 @@ impl SequentialNode for PresentNode {
                     .dynamic_rendering
                     .cmd_end_rendering(draw_command_buffer);
 
-                {
+                if let Some(screenshot_buffer) = screenshot_buffer {
+                    renderer.cmd_copy_image_to_buffer(
+                        draw_command_buffer,
+                        swapchain.present_images[present_index as usize],
+                        vk::ImageLayout::ATTACHMENT_OPTIMAL,
+                        renderer.present_layout(),
+                        swapchain.surface_resolution,
+                        screenshot_buffer,
+                    );
+                } else {
                     let image_memory_barrier = vk::ImageMemoryBarrier2::default()
                         .src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
                         .src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
                         .old_layout(vk::ImageLayout::ATTACHMENT_OPTIMAL)
                         .dst_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
                         .dst_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_READ)
-                        .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
-                        .image(renderer.present_images[present_index as usize])
+                        .new_layout(renderer.present_layout())
+                        .image(swapchain.present_images[present_index as usize])
                         .subresource_range(vk::ImageSubresourceRange {
                             aspect_mask: vk::ImageAspectFlags::COLOR,
                             layer_count: 1,
@@ -367,23 +649,48 @@ impl SequentialNode for PresentNode {
                         .synchronization2
                         .cmd_pipeline_barrier2(draw_command_buffer, &dependency_info);
                 }
+
+                pass_scope.end(world, renderer, draw_command_buffer, drawn as u32);
+                Ok(())
             },
         );
+        if let Err(err) = submitted {
+            Screenshots::cancel(world);
+            // nothing acquired them, the next submit has to
+            for handoff in handoffs {
+                renderer.queue_handoff(handoff);
+            }
+            // the acquire still signals `present_complete_semaphore` and nothing signals the
+            // reserved timeline point, an empty submit consumes the one and signals the other.
+            // A failed submit already signaled `in_flight_fence` again.
+            let recovered = unsafe {
+                SubmitSemaphores::default()
+                    .wait_binary(
+                        frame.present_complete_semaphore,
+                        vk::PipelineStageFlags::ALL_COMMANDS,
+                    )
+                    .signal(rendered)
+                    .submit(
+                        &renderer.device,
+                        &renderer.present_queue,
+                        &[],
+                        vk::Fence::null(),
+                    )
+            };
+            if let Err(recover_err) = recovered {
+                error!("Failed to clean up after the failed frame submit: {recover_err}");
+            }
+            return Err(err);
+        }
+        Screenshots::submitted(world);
 
-        let wait_semaphors = [renderer.rendering_complete_semaphore];
-        let swapchains = [renderer.swapchain];
-        let image_indices = [present_index];
-        let present_info = vk::PresentInfoKHR::default()
-            .wait_semaphores(&wait_semaphors)
-            .swapchains(&swapchains)
-            .image_indices(&image_indices);
-
-        unsafe {
-            renderer
-                .swapchain_loader
-                .queue_present(renderer.present_queue, &present_info)
-                .unwrap();
-        };
-        Ok(())
+        drop(swapchain);
+        renderer.queue_present(present_index, &[frame.rendering_complete_semaphore])
+    }
+
+    fn destroy(&mut self, world: &mut bevy::prelude::World) {
+        for pipeline in self.pipelines.values() {
+            pipeline.destroy(&world.resource::<RenderInstance>().0);
+        }
     }
 }
@@ -5,7 +5,15 @@ use std::{
 
 use ash::vk::{self, CullModeFlags, DescriptorType, FrontFace, PolygonMode, PrimitiveTopology};
 
-use super::{shaders::Shader, RenderInstance};
+use crate::{
+    ctx::ExampleBase,
+    error::{RenderError, RenderResult},
+};
+
+use super::{
+    shaders::{PipelineInterface, ScalarType, Shader},
+    RenderInstance,
+};
 
 #[repr(C)]
 #[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
@@ -117,18 +125,10 @@ impl DepthStencilState {
     }
 }
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
-pub enum IndexFormat {
-    /// Indices are 16 bit unsigned integers.
-    Uint16 = 0,
-    /// Indices are 32 bit unsigned integers.
-    #[default]
-    Uint32 = 1,
-}
-
 /// Operation to perform on the stencil value.
 ///
+// mirrors wgpu, not every operation is used by a pipeline yet
+#[allow(dead_code)]
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
 pub enum StencilOperation {
@@ -198,6 +198,8 @@ impl Default for StencilFaceState {
     }
 }
 
+// mirrors wgpu, not every function is used by a pipeline yet
+#[allow(dead_code)]
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
 pub enum CompareFunction {
@@ -289,27 +291,238 @@ pub struct PrimitiveState {
     pub conservative: bool,
 }
 
+/// An attribute the vertex buffers provide, whether the vertex shader reads it or not.
+#[derive(Debug, Clone, Copy)]
+pub struct VertexAttribute {
+    /// Used in error messages.
+    pub name: &'static str,
+    pub location: u32,
+    pub binding: u32,
+    pub format: vk::Format,
+    pub offset: u32,
+}
+
+/// Everything the vertex buffers of a draw provide. A pipeline only gets the attributes its
+/// vertex shader declares, see [`VertexLayout::attributes_for`].
+#[derive(Debug, Clone)]
+pub struct VertexLayout {
+    pub bindings: Vec<vk::VertexInputBindingDescription>,
+    pub attributes: Vec<VertexAttribute>,
+}
+
+impl VertexLayout {
+    /// The attributes `vertex_shader` reads. Every input has to be provided with the same
+    /// component type and at least as many components.
+    pub fn attributes_for(
+        &self,
+        vertex_shader: &Shader,
+    ) -> RenderResult<Vec<vk::VertexInputAttributeDescription>> {
+        let mismatch = |message| RenderError::VertexLayoutMismatch {
+            name: vertex_shader.name.clone(),
+            message,
+        };
+
+        vertex_shader
+            .vertex_inputs
+            .iter()
+            .map(|input| {
+                let Some(attribute) = self
+                    .attributes
+                    .iter()
+                    .find(|attribute| attribute.location == input.location)
+                else {
+                    return Err(mismatch(format!(
+                        "it reads a {input} from location {}, which the vertex buffers don't provide",
+                        input.location
+                    )));
+                };
+                let compatible = format_components(attribute.format).is_some_and(
+                    |(scalar, components)| {
+                        scalar == input.scalar && components >= input.components
+                    },
+                );
+                if !compatible {
+                    return Err(mismatch(format!(
+                        "it reads a {input} from location {}, but `{}` is {:?}",
+                        input.location, attribute.name, attribute.format
+                    )));
+                }
+
+                Ok(vk::VertexInputAttributeDescription {
+                    location: attribute.location,
+                    binding: attribute.binding,
+                    format: attribute.format,
+                    offset: attribute.offset,
+                })
+            })
+            .collect()
+    }
+}
+
+/// How a vertex attribute format is presented to the shader, normalized formats read as floats.
+fn format_components(format: vk::Format) -> Option<(ScalarType, u32)> {
+    Some(match format {
+        vk::Format::R32_SFLOAT => (ScalarType::Float, 1),
+        vk::Format::R32G32_SFLOAT | vk::Format::R16G16_SFLOAT => (ScalarType::Float, 2),
+        vk::Format::R32G32B32_SFLOAT => (ScalarType::Float, 3),
+        vk::Format::R32G32B32A32_SFLOAT
+        | vk::Format::R16G16B16A16_SFLOAT
+        | vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SNORM => (ScalarType::Float, 4),
+        vk::Format::R32_SINT => (ScalarType::Sint, 1),
+        vk::Format::R32G32_SINT => (ScalarType::Sint, 2),
+        vk::Format::R32G32B32_SINT => (ScalarType::Sint, 3),
+        vk::Format::R32G32B32A32_SINT => (ScalarType::Sint, 4),
+        vk::Format::R32_UINT => (ScalarType::Uint, 1),
+        vk::Format::R32G32_UINT => (ScalarType::Uint, 2),
+        vk::Format::R32G32B32_UINT => (ScalarType::Uint, 3),
+        vk::Format::R32G32B32A32_UINT | vk::Format::R8G8B8A8_UINT => (ScalarType::Uint, 4),
+        _ => return None,
+    })
+}
+
 pub struct GraphicsPipelineDescriptor<'a> {
+    /// Names the pipeline and its layout in validation messages and capture tools.
+    pub label: &'a str,
     pub vertex_shader: Shader,
     pub fragment_shader: Shader,
-    pub vertex_input: vk::PipelineVertexInputStateCreateInfo<'a>,
+    pub vertex_layout: &'a VertexLayout,
     pub viewport: vk::Extent2D,
     pub primitive: PrimitiveState,
     pub depth_stencil: Option<DepthStencilState>,
-    pub push_constant_range: Option<vk::PushConstantRange>,
+}
+
+/// Creates the pipeline layout shared by the [`GraphicsPipeline`] and [`ShaderObjectPipeline`] paths
+/// from what the shaders declare, see [`PipelineInterface::merge`].
+fn create_pipeline_layout(
+    render_instance: &RenderInstance,
+    desc: &GraphicsPipelineDescriptor,
+    interface: &PipelineInterface,
+) -> RenderResult<(
+    vk::PipelineLayout,
+    Vec<vk::DescriptorSetLayout>,
+    Vec<HashMap<u32, DescriptorType>>,
+)> {
+    let (descriptor_set_layouts, set_layout_info) =
+        interface.create_descriptor_set_layouts(render_instance)?;
+    let device = render_instance.device();
+    let pipeline_layout = match unsafe {
+        device.create_pipeline_layout(
+            &vk::PipelineLayoutCreateInfo::default()
+                .set_layouts(&descriptor_set_layouts)
+                .push_constant_ranges(push_constant_ranges(interface)),
+            None,
+        )
+    } {
+        Ok(pipeline_layout) => pipeline_layout,
+        Err(err) => {
+            unsafe {
+                destroy_layout(
+                    device,
+                    vk::PipelineLayout::null(),
+                    &descriptor_set_layouts,
+                    vk::DescriptorPool::null(),
+                )
+            };
+            return Err(err.into());
+        }
+    };
+
+    let renderer = &render_instance.0;
+    renderer.set_object_name(pipeline_layout, desc.label);
+    for (index, set_layout) in descriptor_set_layouts.iter().enumerate() {
+        renderer.set_object_name(*set_layout, &format!("{} set {index}", desc.label));
+    }
+
+    Ok((pipeline_layout, descriptor_set_layouts, set_layout_info))
+}
+
+fn push_constant_ranges(interface: &PipelineInterface) -> &[vk::PushConstantRange] {
+    interface
+        .push_constant_range
+        .as_ref()
+        .map_or(&[], std::slice::from_ref)
+}
+
+/// Creates a copy of the descriptor sets for the layouts from [`create_pipeline_layout`] for every
+/// frame in flight and names them.
+fn create_descriptor_sets(
+    render_instance: &RenderInstance,
+    desc: &GraphicsPipelineDescriptor,
+    descriptor_set_layouts: &[vk::DescriptorSetLayout],
+    set_layout_info: &[HashMap<u32, DescriptorType>],
+) -> RenderResult<(vk::DescriptorPool, Vec<Vec<vk::DescriptorSet>>)> {
+    let renderer = &render_instance.0;
+    let (descriptor_pool, descriptor_sets) = desc.fragment_shader.create_descriptor_sets(
+        render_instance,
+        descriptor_set_layouts,
+        set_layout_info,
+        renderer.frames.len(),
+    )?;
+
+    renderer.set_object_name(descriptor_pool, desc.label);
+    for (frame_index, frame_sets) in descriptor_sets.iter().enumerate() {
+        for (index, descriptor_set) in frame_sets.iter().enumerate() {
+            renderer.set_object_name(
+                *descriptor_set,
+                &format!("{} frame {frame_index} set {index}", desc.label),
+            );
+        }
+    }
+
+    Ok((descriptor_pool, descriptor_sets))
+}
+
+/// Destroys what [`create_pipeline_layout`] and [`Shader::create_descriptor_sets`] created. Null
+/// handles are skipped, so it also cleans up after a creation that failed halfway.
+unsafe fn destroy_layout(
+    device: &ash::Device,
+    layout: vk::PipelineLayout,
+    descriptor_set_layouts: &[vk::DescriptorSetLayout],
+    descriptor_pool: vk::DescriptorPool,
+) {
+    // frees the descriptor sets allocated from it as well
+    device.destroy_descriptor_pool(descriptor_pool, None);
+    for set_layout in descriptor_set_layouts {
+        device.destroy_descriptor_set_layout(*set_layout, None);
+    }
+    device.destroy_pipeline_layout(layout, None);
 }
 
 #[derive(Debug)]
 pub struct GraphicsPipeline {
     pub pipeline: vk::Pipeline,
     pub layout: vk::PipelineLayout,
-    pub descriptor_sets: Vec<vk::DescriptorSet>,
+    pub descriptor_pool: vk::DescriptorPool,
+    /// A copy of the sets per frame in flight, indexed by [`ExampleBase::frame_index`], so a set
+    /// can be written while other frames are still reading theirs.
+    pub descriptor_sets: Vec<Vec<vk::DescriptorSet>>,
     pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
     pub set_layout_info: Vec<HashMap<u32, DescriptorType>>,
+    /// Push constants have to be pushed with exactly these stages.
+    pub push_constant_range: Option<vk::PushConstantRange>,
 }
 
 impl GraphicsPipeline {
-    pub fn new(render_instance: &RenderInstance, desc: GraphicsPipelineDescriptor) -> Self {
+    /// Consumes the shader modules in `desc`, they're destroyed whether creation succeeds or not.
+    pub fn new(
+        render_instance: &RenderInstance,
+        desc: GraphicsPipelineDescriptor,
+    ) -> RenderResult<Self> {
+        let device = render_instance.device();
+        let result = Self::create(render_instance, &desc);
+
+        // the pipeline doesn't need the modules anymore once it's been created
+        desc.vertex_shader.destroy(device);
+        desc.fragment_shader.destroy(device);
+
+        result
+    }
+
+    fn create(
+        render_instance: &RenderInstance,
+        desc: &GraphicsPipelineDescriptor,
+    ) -> RenderResult<Self> {
         let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
             rasterization_samples: vk::SampleCountFlags::TYPE_1,
             ..Default::default()
@@ -338,24 +551,14 @@ impl GraphicsPipeline {
         let dynamic_state_info =
             vk::PipelineDynamicStateCreateInfo::default().dynamic_states(&dynamic_state);
 
-        let (descriptor_set_layouts, set_layout_info) = desc
-            .fragment_shader
-            .create_descriptor_set_layouts(render_instance);
-        let pipeline_layout = unsafe {
-            render_instance
-                .device()
-                .create_pipeline_layout(
-                    &vk::PipelineLayoutCreateInfo::default()
-                        .set_layouts(&descriptor_set_layouts)
-                        .push_constant_ranges(
-                            desc.push_constant_range
-                                .as_ref()
-                                .map_or(&[], |range| std::slice::from_ref(range)),
-                        ),
-                    None,
-                )
-                .unwrap()
-        };
+        let vertex_attributes = desc.vertex_layout.attributes_for(&desc.vertex_shader)?;
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::default()
+            .vertex_binding_descriptions(&desc.vertex_layout.bindings)
+            .vertex_attribute_descriptions(&vertex_attributes);
+
+        let interface = PipelineInterface::merge(&[&desc.vertex_shader, &desc.fragment_shader])?;
+        let (pipeline_layout, descriptor_set_layouts, set_layout_info) =
+            create_pipeline_layout(render_instance, desc, &interface)?;
 
         let mut rasterization = vk::PipelineRasterizationStateCreateInfo::default()
             .polygon_mode(desc.primitive.polygon_mode)
@@ -408,20 +611,24 @@ impl GraphicsPipeline {
             }
         }
 
+        let vertex_specialization = desc.vertex_shader.specialization_info();
+        let fragment_specialization = desc.fragment_shader.specialization_info();
         let shader_stages = [
             vk::PipelineShaderStageCreateInfo::default()
                 .name(&desc.vertex_shader.entry_point_cstr)
                 .stage(vk::ShaderStageFlags::VERTEX)
-                .module(desc.vertex_shader.module),
+                .module(desc.vertex_shader.module)
+                .specialization_info(&vertex_specialization),
             vk::PipelineShaderStageCreateInfo::default()
                 .name(&desc.fragment_shader.entry_point_cstr)
                 .stage(vk::ShaderStageFlags::FRAGMENT)
-                .module(desc.fragment_shader.module),
+                .module(desc.fragment_shader.module)
+                .specialization_info(&fragment_specialization),
         ];
 
-        let input_assembly_state =
-            vk::PipelineInputAssemblyStateCreateInfo::default().topology(desc.primitive.topology).primitive_restart_enable(false);
-
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::default()
+            .topology(desc.primitive.topology)
+            .primitive_restart_enable(false);
 
         let viewports = &[vk::Viewport {
             x: 0.0,
@@ -443,7 +650,7 @@ impl GraphicsPipeline {
 
         let graphic_pipeline_info = vk::GraphicsPipelineCreateInfo::default()
             .stages(&shader_stages)
-            .vertex_input_state(&desc.vertex_input)
+            .vertex_input_state(&vertex_input)
             .input_assembly_state(&input_assembly_state)
             .viewport_state(&viewport_state)
             .rasterization_state(&rasterization)
@@ -455,29 +662,439 @@ impl GraphicsPipeline {
             .color_blend_state(&color_blend_state)
             .push_next(&mut rendering_info);
 
-        let pipeline = unsafe {
-            render_instance
-                .device()
-                .create_graphics_pipelines(
-                    vk::PipelineCache::null(),
-                    &[graphic_pipeline_info],
-                    None,
-                )
-                .unwrap()[0]
+        let device = render_instance.device();
+        let pipeline = match unsafe {
+            device.create_graphics_pipelines(
+                render_instance.pipeline_cache(),
+                &[graphic_pipeline_info],
+                None,
+            )
+        } {
+            Ok(pipelines) => pipelines[0],
+            Err((_, err)) => {
+                unsafe {
+                    destroy_layout(
+                        device,
+                        pipeline_layout,
+                        &descriptor_set_layouts,
+                        vk::DescriptorPool::null(),
+                    )
+                };
+                return Err(err.into());
+            }
         };
+        render_instance.0.set_object_name(pipeline, desc.label);
 
-        let descriptor_sets = desc.fragment_shader.create_descriptor_sets(
+        let (descriptor_pool, descriptor_sets) = match create_descriptor_sets(
             render_instance,
+            desc,
             &descriptor_set_layouts,
             &set_layout_info,
-        );
+        ) {
+            Ok(descriptor_sets) => descriptor_sets,
+            Err(err) => {
+                unsafe {
+                    device.destroy_pipeline(pipeline, None);
+                    destroy_layout(
+                        device,
+                        pipeline_layout,
+                        &descriptor_set_layouts,
+                        vk::DescriptorPool::null(),
+                    );
+                }
+                return Err(err);
+            }
+        };
 
-        Self {
+        Ok(Self {
             pipeline,
             layout: pipeline_layout,
+            descriptor_pool,
             descriptor_set_layouts,
             set_layout_info,
+            push_constant_range: interface.push_constant_range,
             descriptor_sets,
+        })
+    }
+
+    pub fn destroy(&self, device: &ash::Device) {
+        unsafe {
+            device.destroy_pipeline(self.pipeline, None);
+            destroy_layout(
+                device,
+                self.layout,
+                &self.descriptor_set_layouts,
+                self.descriptor_pool,
+            );
+        }
+    }
+
+    /// Binds the pipeline and its descriptor sets, and sets the viewport and scissor to `extent`.
+    pub unsafe fn bind(
+        &self,
+        renderer: &ExampleBase,
+        command_buffer: vk::CommandBuffer,
+        extent: vk::Extent2D,
+    ) {
+        let device = &renderer.device;
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.pipeline,
+        );
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.layout,
+            0,
+            &self.descriptor_sets[renderer.frame_index()],
+            &[],
+        );
+        device.cmd_set_viewport(command_buffer, 0, &[full_viewport(extent)]);
+        device.cmd_set_scissor(command_buffer, 0, &[extent.into()]);
+    }
+}
+
+fn full_viewport(extent: vk::Extent2D) -> vk::Viewport {
+    vk::Viewport {
+        x: 0.0,
+        y: 0.0,
+        width: extent.width as f32,
+        height: extent.height as f32,
+        min_depth: 0.0,
+        max_depth: 1.0,
+    }
+}
+
+/// The `VK_EXT_shader_object` counterpart of [`GraphicsPipeline`].
+///
+/// Instead of baking a `vk::Pipeline`, the stages are compiled into linked `vk::ShaderEXT`s and
+/// every piece of state a pipeline would bake is recorded as dynamic state in [`Self::bind`].
+#[derive(Debug)]
+pub struct ShaderObjectPipeline {
+    pub shaders: Vec<vk::ShaderEXT>,
+    pub stages: Vec<vk::ShaderStageFlags>,
+    pub layout: vk::PipelineLayout,
+    pub descriptor_pool: vk::DescriptorPool,
+    /// A copy of the sets per frame in flight, indexed by [`ExampleBase::frame_index`], so a set
+    /// can be written while other frames are still reading theirs.
+    pub descriptor_sets: Vec<Vec<vk::DescriptorSet>>,
+    pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+    pub set_layout_info: Vec<HashMap<u32, DescriptorType>>,
+    /// Push constants have to be pushed with exactly these stages.
+    pub push_constant_range: Option<vk::PushConstantRange>,
+    primitive: PrimitiveState,
+    depth_stencil: Option<DepthStencilState>,
+    vertex_bindings: Vec<vk::VertexInputBindingDescription2EXT<'static>>,
+    vertex_attributes: Vec<vk::VertexInputAttributeDescription2EXT<'static>>,
+}
+
+impl ShaderObjectPipeline {
+    /// The vertex layout is set with `cmd_set_vertex_input_ext` when binding.
+    pub fn new(
+        render_instance: &RenderInstance,
+        desc: GraphicsPipelineDescriptor,
+    ) -> RenderResult<Self> {
+        // shader objects are created straight from the SPIR-V, the modules are never used
+        desc.vertex_shader.destroy(render_instance.device());
+        desc.fragment_shader.destroy(render_instance.device());
+
+        let vertex_bindings = desc
+            .vertex_layout
+            .bindings
+            .iter()
+            .map(|binding| {
+                vk::VertexInputBindingDescription2EXT::default()
+                    .binding(binding.binding)
+                    .stride(binding.stride)
+                    .input_rate(binding.input_rate)
+                    .divisor(1)
+            })
+            .collect();
+        let vertex_attributes = desc
+            .vertex_layout
+            .attributes_for(&desc.vertex_shader)?
+            .iter()
+            .map(|attribute| {
+                vk::VertexInputAttributeDescription2EXT::default()
+                    .location(attribute.location)
+                    .binding(attribute.binding)
+                    .format(attribute.format)
+                    .offset(attribute.offset)
+            })
+            .collect();
+
+        let interface = PipelineInterface::merge(&[&desc.vertex_shader, &desc.fragment_shader])?;
+        let (layout, descriptor_set_layouts, set_layout_info) =
+            create_pipeline_layout(render_instance, &desc, &interface)?;
+
+        let device = render_instance.device();
+        let shaders = match Shader::create_linked_shader_objects(
+            render_instance,
+            &[&desc.vertex_shader, &desc.fragment_shader],
+            &descriptor_set_layouts,
+            push_constant_ranges(&interface),
+        ) {
+            Ok(shaders) => shaders,
+            Err(err) => {
+                unsafe {
+                    destroy_layout(
+                        device,
+                        layout,
+                        &descriptor_set_layouts,
+                        vk::DescriptorPool::null(),
+                    )
+                };
+                return Err(err);
+            }
+        };
+
+        let (descriptor_pool, descriptor_sets) = match create_descriptor_sets(
+            render_instance,
+            &desc,
+            &descriptor_set_layouts,
+            &set_layout_info,
+        ) {
+            Ok(descriptor_sets) => descriptor_sets,
+            Err(err) => {
+                let shader_object = render_instance
+                    .0
+                    .shader_object
+                    .as_ref()
+                    .expect("VK_EXT_shader_object is not enabled");
+                unsafe {
+                    for shader in &shaders {
+                        shader_object.destroy_shader(*shader, None);
+                    }
+                    destroy_layout(
+                        device,
+                        layout,
+                        &descriptor_set_layouts,
+                        vk::DescriptorPool::null(),
+                    );
+                }
+                return Err(err);
+            }
+        };
+
+        Ok(Self {
+            shaders,
+            stages: vec![
+                desc.vertex_shader.kind.to_vk_shader_stage_flag(),
+                desc.fragment_shader.kind.to_vk_shader_stage_flag(),
+            ],
+            layout,
+            descriptor_pool,
+            descriptor_sets,
+            descriptor_set_layouts,
+            set_layout_info,
+            push_constant_range: interface.push_constant_range,
+            primitive: desc.primitive,
+            depth_stencil: desc.depth_stencil,
+            vertex_bindings,
+            vertex_attributes,
+        })
+    }
+
+    pub fn destroy(&self, renderer: &ExampleBase) {
+        let shader_object = renderer
+            .shader_object
+            .as_ref()
+            .expect("VK_EXT_shader_object is not enabled");
+
+        unsafe {
+            for shader in &self.shaders {
+                shader_object.destroy_shader(*shader, None);
+            }
+            destroy_layout(
+                &renderer.device,
+                self.layout,
+                &self.descriptor_set_layouts,
+                self.descriptor_pool,
+            );
+        }
+    }
+
+    /// Binds the shaders and descriptor sets and records all state a [`GraphicsPipeline`] would bake.
+    ///
+    /// Depth clamp, logic op and alpha-to-one are left alone since their device features aren't enabled.
+    pub unsafe fn bind(
+        &self,
+        renderer: &ExampleBase,
+        command_buffer: vk::CommandBuffer,
+        extent: vk::Extent2D,
+    ) {
+        let device = &renderer.device;
+        let shader_object = renderer
+            .shader_object
+            .as_ref()
+            .expect("VK_EXT_shader_object is not enabled");
+
+        shader_object.cmd_bind_shaders(command_buffer, &self.stages, &self.shaders);
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.layout,
+            0,
+            &self.descriptor_sets[renderer.frame_index()],
+            &[],
+        );
+
+        shader_object.cmd_set_viewport_with_count(command_buffer, &[full_viewport(extent)]);
+        shader_object.cmd_set_scissor_with_count(command_buffer, &[extent.into()]);
+
+        // input assembly
+        shader_object.cmd_set_vertex_input(
+            command_buffer,
+            &self.vertex_bindings,
+            &self.vertex_attributes,
+        );
+        shader_object.cmd_set_primitive_topology(command_buffer, self.primitive.topology);
+        shader_object.cmd_set_primitive_restart_enable(command_buffer, false);
+
+        // rasterization
+        shader_object.cmd_set_rasterizer_discard_enable(command_buffer, false);
+        shader_object.cmd_set_polygon_mode(command_buffer, self.primitive.polygon_mode);
+        shader_object.cmd_set_cull_mode(command_buffer, self.primitive.cull_mode);
+        shader_object.cmd_set_front_face(command_buffer, self.primitive.front_face);
+        if self.primitive.polygon_mode == PolygonMode::LINE {
+            device.cmd_set_line_width(command_buffer, 1.0);
+        }
+        if self.primitive.conservative {
+            shader_object.cmd_set_conservative_rasterization_mode(
+                command_buffer,
+                vk::ConservativeRasterizationModeEXT::OVERESTIMATE,
+            );
+        }
+
+        // multisample
+        shader_object.cmd_set_rasterization_samples(command_buffer, vk::SampleCountFlags::TYPE_1);
+        shader_object.cmd_set_sample_mask(command_buffer, vk::SampleCountFlags::TYPE_1, &[!0]);
+        shader_object.cmd_set_alpha_to_coverage_enable(command_buffer, false);
+
+        // depth / stencil
+        let ds = self.depth_stencil.as_ref();
+        let depth_enabled = ds.map_or(false, |ds| ds.is_depth_enabled());
+        shader_object.cmd_set_depth_test_enable(command_buffer, depth_enabled);
+        shader_object.cmd_set_depth_write_enable(
+            command_buffer,
+            depth_enabled && ds.map_or(false, |ds| ds.depth_write_enabled),
+        );
+        shader_object.cmd_set_depth_compare_op(
+            command_buffer,
+            ds.map_or(vk::CompareOp::NEVER, |ds| map_comparison(ds.depth_compare)),
+        );
+        shader_object.cmd_set_depth_bounds_test_enable(command_buffer, false);
+
+        let bias = ds.map(|ds| ds.bias).filter(|bias| bias.is_enabled());
+        shader_object.cmd_set_depth_bias_enable(command_buffer, bias.is_some());
+        if let Some(bias) = bias {
+            device.cmd_set_depth_bias(
+                command_buffer,
+                bias.constant as f32,
+                bias.clamp,
+                bias.slope_scale,
+            );
+        }
+
+        let stencil = ds.map(|ds| &ds.stencil).filter(|s| s.is_enabled());
+        shader_object.cmd_set_stencil_test_enable(command_buffer, stencil.is_some());
+        if let Some(s) = stencil {
+            for (face_mask, face) in [
+                (vk::StencilFaceFlags::FRONT, &s.front),
+                (vk::StencilFaceFlags::BACK, &s.back),
+            ] {
+                shader_object.cmd_set_stencil_op(
+                    command_buffer,
+                    face_mask,
+                    map_stencil_op(face.fail_op),
+                    map_stencil_op(face.pass_op),
+                    map_stencil_op(face.depth_fail_op),
+                    map_comparison(face.compare),
+                );
+            }
+            device.cmd_set_stencil_compare_mask(
+                command_buffer,
+                vk::StencilFaceFlags::FRONT_AND_BACK,
+                s.read_mask,
+            );
+            device.cmd_set_stencil_write_mask(
+                command_buffer,
+                vk::StencilFaceFlags::FRONT_AND_BACK,
+                s.write_mask,
+            );
+            device.cmd_set_stencil_reference(
+                command_buffer,
+                vk::StencilFaceFlags::FRONT_AND_BACK,
+                0,
+            );
+        }
+
+        // color blend, matches the attachment state baked by `GraphicsPipeline::new`
+        shader_object.cmd_set_color_blend_enable(command_buffer, 0, &[vk::FALSE]);
+        shader_object.cmd_set_color_blend_equation(
+            command_buffer,
+            0,
+            &[vk::ColorBlendEquationEXT {
+                src_color_blend_factor: vk::BlendFactor::SRC_COLOR,
+                dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_DST_COLOR,
+                color_blend_op: vk::BlendOp::ADD,
+                src_alpha_blend_factor: vk::BlendFactor::ZERO,
+                dst_alpha_blend_factor: vk::BlendFactor::ZERO,
+                alpha_blend_op: vk::BlendOp::ADD,
+            }],
+        );
+        shader_object.cmd_set_color_write_mask(command_buffer, 0, &[vk::ColorComponentFlags::RGBA]);
+    }
+}
+
+/// The pipeline a node draws with, picked from the [`RenderBackend`](super::RenderBackend).
+#[derive(Debug)]
+pub enum RenderPipeline {
+    Graphics(GraphicsPipeline),
+    ShaderObject(ShaderObjectPipeline),
+}
+
+impl RenderPipeline {
+    pub fn layout(&self) -> vk::PipelineLayout {
+        match self {
+            Self::Graphics(pipeline) => pipeline.layout,
+            Self::ShaderObject(pipeline) => pipeline.layout,
+        }
+    }
+
+    /// The sets [`Self::bind`] binds for the frame in flight `frame_index`.
+    pub fn descriptor_sets(&self, frame_index: usize) -> &[vk::DescriptorSet] {
+        match self {
+            Self::Graphics(pipeline) => &pipeline.descriptor_sets[frame_index],
+            Self::ShaderObject(pipeline) => &pipeline.descriptor_sets[frame_index],
+        }
+    }
+
+    pub fn push_constant_range(&self) -> Option<vk::PushConstantRange> {
+        match self {
+            Self::Graphics(pipeline) => pipeline.push_constant_range,
+            Self::ShaderObject(pipeline) => pipeline.push_constant_range,
+        }
+    }
+
+    pub unsafe fn bind(
+        &self,
+        renderer: &ExampleBase,
+        command_buffer: vk::CommandBuffer,
+        extent: vk::Extent2D,
+    ) {
+        match self {
+            Self::Graphics(pipeline) => pipeline.bind(renderer, command_buffer, extent),
+            Self::ShaderObject(pipeline) => pipeline.bind(renderer, command_buffer, extent),
+        }
+    }
+
+    /// The GPU must be done with every frame that used the pipeline.
+    pub fn destroy(&self, renderer: &ExampleBase) {
+        match self {
+            Self::Graphics(pipeline) => pipeline.destroy(&renderer.device),
+            Self::ShaderObject(pipeline) => pipeline.destroy(renderer),
         }
     }
 }
@@ -0,0 +1,252 @@
+use std::{
+    fs,
+    hash::Hasher,
+    path::{Path, PathBuf},
+};
+
+use ash::{vk, Device, Instance};
+use bevy::prelude::*;
+use bytemuck::{Pod, Zeroable};
+
+use crate::error::RenderResult;
+
+use super::shader_cache::StableHasher;
+
+/// Bumped whenever [`CacheHeader`] changes, older files are discarded.
+const FORMAT_VERSION: u32 = 1;
+const MAGIC: [u8; 8] = *b"SDPCACHE";
+
+/// Written in front of the data the driver hands out. Drivers are supposed to reject data that
+/// isn't theirs, but not all of them do, so nothing is passed on unless this matches the device.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+struct CacheHeader {
+    magic: [u8; 8],
+    format_version: u32,
+    vendor_id: u32,
+    device_id: u32,
+    driver_version: u32,
+    pipeline_cache_uuid: [u8; vk::UUID_SIZE],
+    device_uuid: [u8; vk::UUID_SIZE],
+    data_size: u64,
+    checksum: u64,
+}
+
+/// The header every `vkGetPipelineCacheData` blob starts with, `VkPipelineCacheHeaderVersionOne`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct VulkanCacheHeader {
+    header_size: u32,
+    header_version: i32,
+    vendor_id: u32,
+    device_id: u32,
+    pipeline_cache_uuid: [u8; vk::UUID_SIZE],
+}
+
+/// A `vk::PipelineCache` that outlives the process. Every pipeline is created with it, so
+/// shaders only get compiled by the driver on the first launch.
+///
+/// Loaded from a file in the cache directory named after the device UUID and driver version, and
+/// written back by [`PipelineCache::save`]. A file that doesn't belong to this device or driver
+/// is ignored and overwritten on the next save.
+pub struct PipelineCache {
+    pub cache: vk::PipelineCache,
+    path: Option<PathBuf>,
+    header: CacheHeader,
+}
+
+impl PipelineCache {
+    /// Starts out empty when `cache_dir` is `None`, the file is missing or it doesn't match.
+    pub fn load(
+        instance: &Instance,
+        pdevice: vk::PhysicalDevice,
+        device: &Device,
+        cache_dir: Option<&Path>,
+    ) -> RenderResult<Self> {
+        let mut id_properties = vk::PhysicalDeviceIDProperties::default();
+        let mut properties = vk::PhysicalDeviceProperties2::default().push_next(&mut id_properties);
+        unsafe { instance.get_physical_device_properties2(pdevice, &mut properties) };
+        let properties = properties.properties;
+
+        let header = CacheHeader {
+            magic: MAGIC,
+            format_version: FORMAT_VERSION,
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            driver_version: properties.driver_version,
+            pipeline_cache_uuid: properties.pipeline_cache_uuid,
+            device_uuid: id_properties.device_uuid,
+            data_size: 0,
+            checksum: 0,
+        };
+        let path = cache_dir.map(|dir| {
+            let uuid: String = header
+                .device_uuid
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            dir.join(format!("pipelines-{uuid}-{:x}.bin", header.driver_version))
+        });
+
+        let data = path
+            .as_deref()
+            .and_then(|path| Self::read(path, &header))
+            .unwrap_or_default();
+        let cache = match Self::create(device, &data) {
+            Ok(cache) => cache,
+            Err(err) if !data.is_empty() => {
+                warn!("The driver rejected the pipeline cache, starting with an empty one: {err}");
+                Self::create(device, &[])?
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            cache,
+            path,
+            header,
+        })
+    }
+
+    /// Writes the cache to disk, it's replaced in one go so an interrupted save can't leave a
+    /// truncated file behind. Failing to save only costs the next launch some compile time.
+    pub fn save(&self, device: &Device) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let data = match unsafe { device.get_pipeline_cache_data(self.cache) } {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("Failed to read back the pipeline cache: {err}");
+                return;
+            }
+        };
+
+        let header = CacheHeader {
+            data_size: data.len() as u64,
+            checksum: checksum(&data),
+            ..self.header
+        };
+        let mut contents = bytemuck::bytes_of(&header).to_vec();
+        contents.extend_from_slice(&data);
+
+        let temporary = path.with_extension("tmp");
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(&temporary, &contents))
+            .and_then(|()| fs::rename(&temporary, path));
+        match result {
+            Ok(()) => info!(
+                "Saved {} bytes of pipeline cache to {}",
+                data.len(),
+                path.display()
+            ),
+            Err(err) => warn!(
+                "Failed to save the pipeline cache to {}: {err}",
+                path.display()
+            ),
+        }
+    }
+
+    pub fn destroy(&self, device: &Device) {
+        unsafe { device.destroy_pipeline_cache(self.cache, None) };
+    }
+
+    fn create(device: &Device, data: &[u8]) -> ash::prelude::VkResult<vk::PipelineCache> {
+        unsafe {
+            device.create_pipeline_cache(
+                &vk::PipelineCacheCreateInfo::default().initial_data(data),
+                None,
+            )
+        }
+    }
+
+    /// The driver's data when `path` holds a valid cache for the device described by `expected`.
+    fn read(path: &Path, expected: &CacheHeader) -> Option<Vec<u8>> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                info!("No pipeline cache at {} yet", path.display());
+                return None;
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to read the pipeline cache at {}: {err}",
+                    path.display()
+                );
+                return None;
+            }
+        };
+
+        match validate(&contents, expected) {
+            Ok(data) => {
+                info!(
+                    "Loaded {} bytes of pipeline cache from {}",
+                    data.len(),
+                    path.display()
+                );
+                Some(data.to_vec())
+            }
+            Err(reason) => {
+                warn!(
+                    "Ignoring the pipeline cache at {}: {reason}",
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+}
+
+fn validate<'a>(contents: &'a [u8], expected: &CacheHeader) -> Result<&'a [u8], &'static str> {
+    let header_size = std::mem::size_of::<CacheHeader>();
+    if contents.len() < header_size {
+        return Err("the file is truncated");
+    }
+    let header: CacheHeader = bytemuck::pod_read_unaligned(&contents[..header_size]);
+    let data = &contents[header_size..];
+
+    if header.magic != MAGIC || header.format_version != FORMAT_VERSION {
+        return Err("it was written by a different version");
+    }
+    if header.vendor_id != expected.vendor_id
+        || header.device_id != expected.device_id
+        || header.device_uuid != expected.device_uuid
+    {
+        return Err("it belongs to a different device");
+    }
+    if header.driver_version != expected.driver_version
+        || header.pipeline_cache_uuid != expected.pipeline_cache_uuid
+    {
+        return Err("it was written by a different driver");
+    }
+    if header.data_size != data.len() as u64 || header.checksum != checksum(data) {
+        return Err("the data is corrupted");
+    }
+
+    // the same checks against the header the driver wrote itself
+    let vulkan_header_size = std::mem::size_of::<VulkanCacheHeader>();
+    if data.len() < vulkan_header_size {
+        return Err("the driver's header is truncated");
+    }
+    let vulkan_header: VulkanCacheHeader =
+        bytemuck::pod_read_unaligned(&data[..vulkan_header_size]);
+    if (vulkan_header.header_size as usize) < vulkan_header_size
+        || vulkan_header.header_version != vk::PipelineCacheHeaderVersion::ONE.as_raw()
+        || vulkan_header.vendor_id != expected.vendor_id
+        || vulkan_header.device_id != expected.device_id
+        || vulkan_header.pipeline_cache_uuid != expected.pipeline_cache_uuid
+    {
+        return Err("the driver's header doesn't match the device");
+    }
+
+    Ok(data)
+}
+
+/// Only there to catch files that got corrupted on disk.
+fn checksum(data: &[u8]) -> u64 {
+    let mut hasher = StableHasher::default();
+    hasher.write(data);
+    hasher.finish()
+}
@@ -60,6 +60,7 @@ impl Box {
     }
 
     /// Creates a new box given the coordinates of two opposing corners.
+    #[allow(dead_code)] // part of the shape API ported from bevy
     pub fn from_corners(a: Vec3, b: Vec3) -> Box {
         let max = a.max(b);
         let min = a.min(b);
@@ -164,6 +165,7 @@ impl Quad {
         Self { size, flip: false }
     }
 
+    #[allow(dead_code)] // part of the shape API ported from bevy
     pub fn flipped(size: Vec2) -> Self {
         Self { size, flip: true }
     }
@@ -230,6 +232,7 @@ impl Default for Plane {
 
 impl Plane {
     /// Creates a new plane centered at the origin with the supplied side length and zero subdivisions.
+    #[allow(dead_code)] // part of the shape API ported from bevy
     pub fn from_size(size: f32) -> Self {
         Self {
             size,
@@ -0,0 +1,214 @@
+use std::path::PathBuf;
+
+use ash::vk;
+use bevy::{prelude::*, tasks::IoTaskPool};
+use gpu_allocator::MemoryLocation;
+
+use crate::{buffer::Buffer, ctx::SwapchainManager};
+
+use super::{extract::Extract, RenderAllocator, RenderInstance};
+
+/// Send this from the main app to save the next rendered frame as a PNG at `path`.
+#[derive(Event, Debug, Clone)]
+pub struct Screenshot {
+    pub path: PathBuf,
+}
+
+/// A copy of the present image that's been recorded into a frame, waiting for that frame's fence.
+struct InFlightScreenshot {
+    paths: Vec<PathBuf>,
+    buffer: Buffer,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    frame_index: usize,
+}
+
+#[derive(Resource, Default)]
+pub struct Screenshots {
+    requested: Vec<PathBuf>,
+    /// Recorded into the frame that's being submitted, see [`Screenshots::submitted`].
+    pending: Option<InFlightScreenshot>,
+    in_flight: Vec<InFlightScreenshot>,
+}
+
+pub(super) fn extract_screenshots(
+    mut events: Extract<EventReader<Screenshot>>,
+    mut screenshots: ResMut<Screenshots>,
+) {
+    screenshots
+        .requested
+        .extend(events.iter().map(|screenshot| screenshot.path.clone()));
+}
+
+impl Screenshots {
+    /// Called by the node that draws to the present image once it's been acquired. Returns the
+    /// buffer to copy the image into when a screenshot has been requested. The node has to call
+    /// [`Self::submitted`] or [`Self::cancel`] once it knows whether the frame was submitted.
+    pub fn prepare(world: &mut World, swapchain: &SwapchainManager) -> Option<vk::Buffer> {
+        world.resource_scope(|world, mut screenshots: Mut<Screenshots>| {
+            if screenshots.requested.is_empty() {
+                return None;
+            }
+
+            let renderer = world.resource::<RenderInstance>().0.clone();
+            let extent = swapchain.surface_resolution;
+            if !swapchain.image_usage.contains(vk::ImageUsageFlags::TRANSFER_SRC) {
+                error!(
+                    "The surface doesn't support copying from the swapchain, can't take a screenshot"
+                );
+                screenshots.requested.clear();
+                return None;
+            }
+
+            let buffer = match Buffer::new(
+                &renderer,
+                world.resource_mut::<RenderAllocator>().allocator(),
+                "screenshot",
+                &vk::BufferCreateInfo::default()
+                    .size((extent.width * extent.height * 4) as vk::DeviceSize)
+                    .usage(vk::BufferUsageFlags::TRANSFER_DST)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                MemoryLocation::GpuToCpu,
+            ) {
+                Ok(buffer) => buffer,
+                Err(err) => {
+                    error!("Failed to create the screenshot buffer: {err}");
+                    screenshots.requested.clear();
+                    return None;
+                }
+            };
+            let handle = buffer.buffer;
+
+            let paths = std::mem::take(&mut screenshots.requested);
+            screenshots.pending = Some(InFlightScreenshot {
+                paths,
+                buffer,
+                extent,
+                format: renderer.surface_format.format,
+                frame_index: renderer.frame_index(),
+            });
+
+            Some(handle)
+        })
+    }
+
+    /// The frame with the copy was submitted, its screenshot is saved once the frame finishes.
+    pub fn submitted(world: &mut World) {
+        let mut screenshots = world.resource_mut::<Screenshots>();
+        if let Some(screenshot) = screenshots.pending.take() {
+            screenshots.in_flight.push(screenshot);
+        }
+    }
+
+    /// The frame with the copy was never submitted. Frees the buffer and takes the screenshot on
+    /// the next frame instead.
+    pub fn cancel(world: &mut World) {
+        world.resource_scope(|world, mut screenshots: Mut<Screenshots>| {
+            let Some(mut screenshot) = screenshots.pending.take() else {
+                return;
+            };
+            let renderer = world.resource::<RenderInstance>().0.clone();
+            screenshot.buffer.destroy(
+                &renderer.device,
+                world.resource_mut::<RenderAllocator>().allocator(),
+            );
+            screenshots.requested.extend(screenshot.paths);
+        });
+    }
+
+    /// Runs after the fence of `frame_index` has been waited on. Reads back the screenshots that
+    /// frame copied and saves them on the IO task pool.
+    pub fn save_finished(world: &mut World, frame_index: usize) {
+        world.resource_scope(|world, mut screenshots: Mut<Screenshots>| {
+            let (finished, in_flight) = std::mem::take(&mut screenshots.in_flight)
+                .into_iter()
+                .partition::<Vec<_>, _>(|screenshot| screenshot.frame_index == frame_index);
+            screenshots.in_flight = in_flight;
+
+            let renderer = world.resource::<RenderInstance>().0.clone();
+            let mut render_allocator = world.resource_mut::<RenderAllocator>();
+
+            for mut screenshot in finished {
+                let byte_count = (screenshot.extent.width * screenshot.extent.height * 4) as usize;
+                let pixels = screenshot
+                    .buffer
+                    .allocation
+                    .as_ref()
+                    .unwrap()
+                    .mapped_slice()
+                    .unwrap()[..byte_count]
+                    .to_vec();
+                screenshot
+                    .buffer
+                    .destroy(&renderer.device, render_allocator.allocator());
+
+                let InFlightScreenshot {
+                    paths,
+                    extent,
+                    format,
+                    ..
+                } = screenshot;
+                IoTaskPool::get()
+                    .spawn(async move {
+                        let Some(image) = to_rgba8(pixels, extent, format) else {
+                            error!("Can't save a screenshot of a {format:?} swapchain");
+                            return;
+                        };
+
+                        for path in paths {
+                            match image.save(&path) {
+                                Ok(()) => info!("Saved screenshot to {}", path.display()),
+                                Err(err) => {
+                                    error!("Failed to save screenshot to {}: {err}", path.display())
+                                }
+                            }
+                        }
+                    })
+                    .detach();
+            }
+        });
+    }
+
+    /// Frees the buffers of every screenshot that's still in flight without saving them, for
+    /// when their frames will never finish.
+    pub fn discard_in_flight(world: &mut World) {
+        world.resource_scope(|world, mut screenshots: Mut<Screenshots>| {
+            let renderer = world.resource::<RenderInstance>().0.clone();
+            let mut render_allocator = world.resource_mut::<RenderAllocator>();
+
+            for mut screenshot in screenshots.in_flight.drain(..) {
+                warn!(
+                    "Dropping a screenshot that was never finished, it would have been saved to {:?}",
+                    screenshot.paths
+                );
+                screenshot
+                    .buffer
+                    .destroy(&renderer.device, render_allocator.allocator());
+            }
+        });
+    }
+}
+
+/// Converts tightly packed pixels in the swapchain format to RGBA8. The surface is composited
+/// opaque, so alpha is forced to 255.
+fn to_rgba8(
+    mut pixels: Vec<u8>,
+    extent: vk::Extent2D,
+    format: vk::Format,
+) -> Option<::image::RgbaImage> {
+    match format {
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => {}
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+        _ => return None,
+    }
+
+    for pixel in pixels.chunks_exact_mut(4) {
+        pixel[3] = 255;
+    }
+
+    ::image::RgbaImage::from_raw(extent.width, extent.height, pixels)
+}
@@ -0,0 +1,147 @@
+use std::{
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+
+/// Bumped whenever the layout of the cache, what goes into a key or the compile options change.
+const CACHE_VERSION: u32 = 2;
+
+/// FNV-1a. Unlike `DefaultHasher` it's guaranteed to hash the same across Rust versions, which
+/// anything written to disk needs.
+#[derive(Debug, Clone, Copy)]
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Compiled SPIR-V kept on disk, so shaderc only runs for shaders that changed.
+///
+/// A lookup starts from the hash of everything known before compiling: the source, the stage,
+/// the entry point, the macros and the compile options. It points to a list of the files the
+/// last compile included, and the contents of those are hashed in as well to find the artifact.
+/// Changing a shared include therefore misses the cache for every shader that includes it.
+#[derive(Debug, Clone)]
+pub struct ShaderCache {
+    dir: PathBuf,
+}
+
+/// The included files of one compile, hashed in the order shaderc asked for them.
+#[derive(Debug, Default)]
+struct Includes {
+    paths: Vec<PathBuf>,
+    hash: StableHasher,
+}
+
+impl Includes {
+    fn read(includes: &[PathBuf]) -> Option<Self> {
+        let mut result = Self::default();
+        for path in includes {
+            result.add(path, &fs::read_to_string(path).ok()?);
+        }
+        Some(result)
+    }
+
+    fn add(&mut self, path: &Path, content: &str) {
+        path.hash(&mut self.hash);
+        content.hash(&mut self.hash);
+        self.paths.push(path.to_path_buf());
+    }
+}
+
+impl ShaderCache {
+    pub fn new(cache_dir: &Path) -> Self {
+        Self {
+            dir: cache_dir.join("shaders"),
+        }
+    }
+
+    /// Hashes everything that goes into a compile apart from the included files. Hash the
+    /// inputs into the returned hasher and pass it to [`ShaderCache::get`] and
+    /// [`ShaderCache::insert`].
+    pub fn key() -> StableHasher {
+        let mut hasher = StableHasher::default();
+        CACHE_VERSION.hash(&mut hasher);
+        #[cfg(feature = "shader-compiler")]
+        shaderc::get_spirv_version().hash(&mut hasher);
+        hasher
+    }
+
+    /// The SPIR-V and the included files of the last compile with the same inputs, as long as
+    /// none of those files changed since.
+    pub fn get(&self, key: &StableHasher) -> Option<(Vec<u32>, Vec<PathBuf>)> {
+        let includes = fs::read_to_string(self.includes_path(key)).ok()?;
+        let includes = includes.lines().map(PathBuf::from).collect::<Vec<_>>();
+        let includes = Includes::read(&includes)?;
+
+        let bytes = fs::read(self.artifact_path(key, &includes)).ok()?;
+        if bytes.len() % 4 != 0 {
+            return None;
+        }
+        let spirv = bytes
+            .chunks_exact(4)
+            .map(|word| u32::from_ne_bytes(word.try_into().unwrap()))
+            .collect();
+        Some((spirv, includes.paths))
+    }
+
+    /// Stores the result of a compile, `includes` are the files shaderc resolved with their
+    /// contents at the time. Failing to write is only logged, the next launch compiles again.
+    pub fn insert(&self, key: &StableHasher, spirv: &[u32], includes: &[(PathBuf, String)]) {
+        let mut resolved = Includes::default();
+        for (path, content) in includes {
+            resolved.add(path, content);
+        }
+
+        let mut include_list = String::new();
+        for path in &resolved.paths {
+            include_list.push_str(&path.to_string_lossy());
+            include_list.push('\n');
+        }
+
+        let artifact_path = self.artifact_path(key, &resolved);
+        let result = fs::create_dir_all(&self.dir)
+            .and_then(|()| write_replacing(&artifact_path, bytemuck::cast_slice(spirv)))
+            .and_then(|()| write_replacing(&self.includes_path(key), include_list.as_bytes()));
+        if let Err(err) = result {
+            warn!(
+                "Failed to write the shader cache to {}: {err}",
+                self.dir.display()
+            );
+        }
+    }
+
+    fn includes_path(&self, key: &StableHasher) -> PathBuf {
+        self.dir.join(format!("{:016x}.includes", key.finish()))
+    }
+
+    fn artifact_path(&self, key: &StableHasher, includes: &Includes) -> PathBuf {
+        let mut hasher = *key;
+        includes.hash.finish().hash(&mut hasher);
+        self.dir.join(format!("{:016x}.spv", hasher.finish()))
+    }
+}
+
+/// Other processes only ever see the old or the new file, never a half written one.
+fn write_replacing(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
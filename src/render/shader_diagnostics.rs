@@ -0,0 +1,404 @@
+use std::{collections::HashMap, fmt};
+
+use bevy::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => f.write_str("error"),
+            Severity::Warning => f.write_str("warning"),
+        }
+    }
+}
+
+/// The `#include` line a file was pulled in by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncludeSite {
+    pub file: String,
+    pub line: u32,
+}
+
+/// A message shaderc reported, together with the source it points at.
+#[derive(Debug, Clone)]
+pub struct ShaderDiagnostic {
+    pub severity: Severity,
+    /// The path of the shader or of the include the message is about.
+    pub file: String,
+    pub line: Option<u32>,
+    /// Shaderc doesn't report columns, this is where the token quoted in the message starts on
+    /// the line, counted in characters from 1.
+    pub column: Option<u32>,
+    /// The length of the quoted token in characters.
+    pub length: u32,
+    pub message: String,
+    pub source_line: Option<String>,
+    /// The includes that lead from the compiled shader to `file`, innermost first.
+    pub included_from: Vec<IncludeSite>,
+}
+
+/// Everything shaderc reported for one compile.
+#[derive(Debug, Clone, Default)]
+pub struct ShaderDiagnostics(pub Vec<ShaderDiagnostic>);
+
+/// The files that went into one compile, to find the lines diagnostics point at.
+#[derive(Debug, Default)]
+pub struct SourceFiles {
+    contents: HashMap<String, String>,
+    included_from: HashMap<String, IncludeSite>,
+}
+
+impl SourceFiles {
+    /// `name` is what shaderc calls the file in its messages.
+    pub fn add(&mut self, name: &str, content: &str) {
+        self.contents.insert(name.to_string(), content.to_string());
+    }
+
+    /// `requested` is the name in the `#include` directive of `includer`.
+    pub fn add_include(&mut self, name: &str, content: &str, includer: &str, requested: &str) {
+        self.add(name, content);
+        let line = self.contents.get(includer).and_then(|source| {
+            source
+                .lines()
+                .position(|line| included_name(line) == Some(requested))
+        });
+        if let Some(line) = line {
+            self.included_from
+                .entry(name.to_string())
+                .or_insert(IncludeSite {
+                    file: includer.to_string(),
+                    line: line as u32 + 1,
+                });
+        }
+    }
+
+    fn include_chain(&self, file: &str) -> Vec<IncludeSite> {
+        let mut chain = Vec::new();
+        let mut current = file;
+        while let Some(site) = self.included_from.get(current) {
+            // a file that ends up including itself is rejected by shaderc, but don't hang on it
+            if chain.contains(site) {
+                break;
+            }
+            chain.push(site.clone());
+            current = &site.file;
+        }
+        chain
+    }
+}
+
+impl ShaderDiagnostics {
+    /// Parses shaderc's output, which has one `file:line: severity: message` line per diagnostic.
+    /// Lines that don't look like that are kept as diagnostics of `fallback_file`.
+    pub fn parse(output: &str, fallback_file: &str, sources: &SourceFiles) -> Self {
+        let diagnostics = output
+            .lines()
+            .map(str::trim)
+            .filter(|line| {
+                !line.is_empty()
+                    && !line.ends_with(" generated.")
+                    && !line.ends_with("compilation terminated")
+            })
+            .map(|line| {
+                let (file, line_number, severity, message) =
+                    split_line(line).unwrap_or((fallback_file, None, Severity::Error, line));
+                ShaderDiagnostic::new(severity, file, line_number, message, sources)
+            })
+            .collect();
+        Self(diagnostics)
+    }
+
+    /// Emits one event per diagnostic, with the location as fields.
+    pub fn log(&self) {
+        for diagnostic in &self.0 {
+            let ShaderDiagnostic {
+                file, line, column, ..
+            } = diagnostic;
+            match diagnostic.severity {
+                Severity::Error => error!(file = %file, line, column, "{diagnostic}"),
+                Severity::Warning => warn!(file = %file, line, column, "{diagnostic}"),
+            }
+        }
+    }
+}
+
+impl ShaderDiagnostic {
+    fn new(
+        severity: Severity,
+        file: &str,
+        line: Option<u32>,
+        message: &str,
+        sources: &SourceFiles,
+    ) -> Self {
+        let source_line = line.and_then(|line| {
+            let source = sources.contents.get(file)?;
+            source.lines().nth(line.checked_sub(1)? as usize)
+        });
+
+        // glslang quotes the offending token first, e.g. `'foo' : undeclared identifier`
+        let token = message
+            .strip_prefix('\'')
+            .and_then(|rest| rest.split_once('\''))
+            .map(|(token, _)| token)
+            .filter(|token| !token.is_empty());
+        let column = match (source_line, token) {
+            (Some(source_line), Some(token)) => source_line
+                .find(token)
+                .map(|offset| source_line[..offset].chars().count() as u32 + 1),
+            _ => None,
+        };
+
+        Self {
+            severity,
+            file: file.to_string(),
+            line,
+            column,
+            length: token.map_or(1, |token| token.chars().count() as u32),
+            message: message.to_string(),
+            source_line: source_line.map(str::to_string),
+            included_from: sources.include_chain(file),
+        }
+    }
+}
+
+/// The name between the quotes or angle brackets of an `#include` line.
+fn included_name(line: &str) -> Option<&str> {
+    let directive = line.trim_start().strip_prefix('#')?.trim_start();
+    let target = directive.strip_prefix("include")?.trim_start();
+    let (name, _) = match target.strip_prefix('"') {
+        Some(quoted) => quoted.split_once('"')?,
+        None => target.strip_prefix('<')?.split_once('>')?,
+    };
+    Some(name)
+}
+
+fn split_line(line: &str) -> Option<(&str, Option<u32>, Severity, &str)> {
+    [
+        (": error: ", Severity::Error),
+        (": warning: ", Severity::Warning),
+    ]
+    .into_iter()
+    .find_map(|(marker, severity)| {
+        let (location, message) = line.split_once(marker)?;
+        let (file, line) = match location.rsplit_once(':') {
+            Some((file, line)) if line.parse::<u32>().is_ok() => (file, line.parse().ok()),
+            _ => (location, None),
+        };
+        Some((file, line, severity, message.trim()))
+    })
+}
+
+impl fmt::Display for ShaderDiagnostic {
+    /// Formats like rustc does:
+    ///
+    /// ```text
+    /// error: 'foo' : undeclared identifier
+    ///   --> shader/global.glsl:12:5
+    ///    |
+    /// 12 |     foo = 1.0;
+    ///    |     ^^^
+    ///    = included from shader/main.frag:2
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}\n  --> {}",
+            self.severity, self.message, self.file
+        )?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+            if let Some(column) = self.column {
+                write!(f, ":{column}")?;
+            }
+        }
+
+        let gutter = " ".repeat(self.line.map_or(1, |line| line.to_string().len()));
+        if let (Some(line), Some(source_line)) = (self.line, &self.source_line) {
+            write!(f, "\n{gutter} |\n{line} | {source_line}")?;
+            if let Some(column) = self.column {
+                // keeps tabs so the caret lines up with the line above
+                let padding: String = source_line
+                    .chars()
+                    .take(column as usize - 1)
+                    .map(|char| if char == '\t' { '\t' } else { ' ' })
+                    .collect();
+                write!(
+                    f,
+                    "\n{gutter} | {padding}{}",
+                    "^".repeat(self.length as usize)
+                )?;
+            }
+        }
+        for site in &self.included_from {
+            write!(f, "\n{gutter} = included from {}:{}", site.file, site.line)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ShaderDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, diagnostic) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str("\n\n")?;
+            }
+            write!(f, "{diagnostic}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN_FRAG: &str = "#version 450\n\nvoid main() {\n    float x = foo;\n}\n";
+
+    fn main_frag_sources() -> SourceFiles {
+        let mut sources = SourceFiles::default();
+        sources.add("shader/main.frag", MAIN_FRAG);
+        sources
+    }
+
+    #[test]
+    fn parses_file_line_errors() {
+        let output = "shader/main.frag:4: error: 'foo' : undeclared identifier\n\
+                      shader/main.frag:4: warning: implicit conversion\n\
+                      1 error and 1 warning generated.\n";
+        let diagnostics = ShaderDiagnostics::parse(output, "fallback", &main_frag_sources());
+
+        assert_eq!(diagnostics.0.len(), 2);
+        let error = &diagnostics.0[0];
+        assert_eq!(error.severity, Severity::Error);
+        assert_eq!(error.file, "shader/main.frag");
+        assert_eq!(error.line, Some(4));
+        assert_eq!(error.message, "'foo' : undeclared identifier");
+        assert_eq!(error.source_line.as_deref(), Some("    float x = foo;"));
+        assert!(error.included_from.is_empty());
+        assert_eq!(diagnostics.0[1].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn keeps_lines_without_a_line_number() {
+        let output = "shader/main.frag: error: #version: bad profile name\n\
+                      shaderc failed for another reason\n";
+        let diagnostics =
+            ShaderDiagnostics::parse(output, "shader/main.frag", &main_frag_sources());
+
+        assert_eq!(diagnostics.0.len(), 2);
+        for diagnostic in &diagnostics.0 {
+            assert_eq!(diagnostic.severity, Severity::Error);
+            assert_eq!(diagnostic.file, "shader/main.frag");
+            assert_eq!(diagnostic.line, None);
+            assert_eq!(diagnostic.column, None);
+            assert_eq!(diagnostic.source_line, None);
+        }
+        assert_eq!(diagnostics.0[0].message, "#version: bad profile name");
+        assert_eq!(
+            diagnostics.0[1].message,
+            "shaderc failed for another reason"
+        );
+        assert_eq!(
+            diagnostics.0[1].to_string(),
+            "error: shaderc failed for another reason\n  --> shader/main.frag"
+        );
+    }
+
+    #[test]
+    fn points_the_caret_at_the_quoted_token() {
+        let output = "shader/main.frag:4: error: 'foo' : undeclared identifier\n";
+        let diagnostics = ShaderDiagnostics::parse(output, "fallback", &main_frag_sources());
+
+        let error = &diagnostics.0[0];
+        assert_eq!(error.column, Some(15));
+        assert_eq!(error.length, 3);
+        assert_eq!(
+            error.to_string(),
+            "error: 'foo' : undeclared identifier\n  \
+             --> shader/main.frag:4:15\n  \
+             |\n\
+             4 |     float x = foo;\n  \
+             |               ^^^"
+        );
+    }
+
+    #[test]
+    fn keeps_tabs_in_the_caret_padding() {
+        let mut sources = SourceFiles::default();
+        sources.add("shader/main.frag", "\tx = foo;\n");
+        let diagnostics = ShaderDiagnostics::parse(
+            "shader/main.frag:1: error: 'foo' : undeclared identifier",
+            "fallback",
+            &sources,
+        );
+
+        assert!(diagnostics.0[0].to_string().ends_with("\n  | \t    ^^^"));
+    }
+
+    #[test]
+    fn follows_a_two_level_include_chain() {
+        let mut sources = SourceFiles::default();
+        sources.add(
+            "shader/main.frag",
+            "#version 450\n#include \"lighting_common.glsl\"\n#include \"lighting.glsl\"\n",
+        );
+        sources.add_include(
+            "shader/lighting_common.glsl",
+            "// nothing\n",
+            "shader/main.frag",
+            "lighting_common.glsl",
+        );
+        sources.add_include(
+            "shader/lighting.glsl",
+            "#include <common.glsl>\n",
+            "shader/main.frag",
+            "lighting.glsl",
+        );
+        sources.add_include(
+            "shader/common.glsl",
+            "\n\nfloat f() { return bar; }\n",
+            "shader/lighting.glsl",
+            "common.glsl",
+        );
+
+        let diagnostics = ShaderDiagnostics::parse(
+            "shader/common.glsl:3: error: 'bar' : undeclared identifier",
+            "shader/main.frag",
+            &sources,
+        );
+
+        let error = &diagnostics.0[0];
+        assert_eq!(
+            error.included_from,
+            [
+                IncludeSite {
+                    file: "shader/lighting.glsl".to_string(),
+                    line: 1,
+                },
+                IncludeSite {
+                    file: "shader/main.frag".to_string(),
+                    line: 3,
+                },
+            ]
+        );
+        assert!(error.to_string().ends_with(
+            "\n  = included from shader/lighting.glsl:1\n  = included from shader/main.frag:3"
+        ));
+    }
+
+    #[test]
+    fn matches_the_exact_include_name() {
+        assert_eq!(included_name("#include \"a.glsl\""), Some("a.glsl"));
+        assert_eq!(
+            included_name("  #  include <a.glsl> // why"),
+            Some("a.glsl")
+        );
+        assert_eq!(included_name("// #include \"a.glsl\""), None);
+        assert_eq!(included_name("#define include_a \"a.glsl\""), None);
+    }
+}
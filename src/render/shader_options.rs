@@ -0,0 +1,71 @@
+//! The shaderc setup shared by `build.rs` and [`Shader::compile`](super::shaders::Shader::compile),
+//! so precompiled shaders come out the same as ones compiled at runtime. `build.rs` pulls this
+//! file in with `#[path]`, so it can only use `std` and `shaderc`.
+
+use std::path::{Path, PathBuf};
+
+/// Where `#include <name>` looks, relative to the crate root.
+pub const SHADER_DIR: &str = "shader";
+
+/// The binding each HLSL register class starts at, so `t0` and `s0` in the same space don't
+/// collide. Read-only structured buffers are `t` registers, writable ones `u` registers.
+const HLSL_BINDING_BASES: [(shaderc::ResourceKind, u32); 4] = [
+    (shaderc::ResourceKind::Texture, 0),
+    (shaderc::ResourceKind::Sampler, 16),
+    (shaderc::ResourceKind::Buffer, 32),
+    (shaderc::ResourceKind::UnorderedAccessView, 48),
+];
+
+/// Sets everything but the include callback. `defines` come on top of `EP`, which is `main`.
+pub fn configure<'a>(
+    options: &mut shaderc::CompileOptions,
+    hlsl: bool,
+    defines: impl IntoIterator<Item = (&'a str, &'a str)>,
+    optimize: bool,
+    debug_info: bool,
+) {
+    options.add_macro_definition("EP", Some("main"));
+    for (name, value) in defines {
+        options.add_macro_definition(name, Some(value));
+    }
+    options.set_target_env(
+        shaderc::TargetEnv::Vulkan,
+        shaderc::EnvVersion::Vulkan1_2 as u32,
+    );
+    if hlsl {
+        options.set_source_language(shaderc::SourceLanguage::HLSL);
+        options.set_hlsl_io_mapping(true);
+        options.set_hlsl_offsets(true);
+        options.set_auto_map_locations(true);
+        for (kind, base) in HLSL_BINDING_BASES {
+            options.set_binding_base(kind, base);
+        }
+    }
+    if optimize {
+        options.set_optimization_level(shaderc::OptimizationLevel::Performance);
+    } else {
+        options.set_optimization_level(shaderc::OptimizationLevel::Zero);
+    }
+    if debug_info {
+        options.set_generate_debug_info();
+    }
+}
+
+/// Reads the file an `#include` asks for: `"name"` next to the including file, `<name>` from
+/// [`SHADER_DIR`]. The path is what shaderc should get as the resolved name, so nested relative
+/// includes and diagnostics find the file.
+pub fn read_include(
+    name: &str,
+    include_type: shaderc::IncludeType,
+    source_file: &str,
+) -> Result<(PathBuf, String), String> {
+    let path = if include_type == shaderc::IncludeType::Relative {
+        Path::new(source_file).parent().unwrap().join(name)
+    } else {
+        Path::new(SHADER_DIR).join(name)
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(content) => Ok((path, content)),
+        Err(err) => Err(format!("can't read {}: {err}", path.display())),
+    }
+}
@@ -1,33 +1,60 @@
+#[cfg(feature = "shader-compiler")]
+use std::cell::RefCell;
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{btree_map::Entry, BTreeMap, HashMap},
     ffi::CString,
-    path::Path,
+    hash::Hash,
+    path::{Path, PathBuf},
 };
 
 use ash::vk::{self};
+use rspirv::{
+    dr::{Instruction, Module, Operand},
+    spirv::{Decoration, Op, StorageClass, Word},
+};
 use rspirv_reflect::BindingCount;
-use shaderc::CompilationArtifact;
 
-use crate::{chunky_list::TempList, ctx::SamplerDesc};
+use crate::{
+    chunky_list::TempList,
+    ctx::SamplerDesc,
+    error::{RenderError, RenderResult},
+};
 
-use super::RenderInstance;
+use super::{shader_cache::ShaderCache, RenderInstance};
+#[cfg(feature = "shader-compiler")]
+use super::{
+    shader_diagnostics::{ShaderDiagnostics, SourceFiles},
+    shader_options,
+};
 
 #[derive(Clone)]
 pub struct Shader {
+    /// The path it was compiled from, used in error messages.
+    pub name: String,
     pub kind: ShaderKind,
     pub spirv_descripor_set_layouts: StageDescriptorSetLayouts,
+    /// The push constant block, visible to this stage only.
+    pub push_constant_range: Option<vk::PushConstantRange>,
+    /// What a vertex shader reads from the vertex buffers, sorted by location. Empty for other
+    /// stages.
+    pub vertex_inputs: Vec<VertexInput>,
     pub entry_point: String,
     pub entry_point_cstr: CString,
     pub module: vk::ShaderModule,
+    pub spirv: Vec<u32>,
+    /// The specialization constants of the variant, in the layout of a `vk::SpecializationInfo`.
+    pub specialization_entries: Vec<vk::SpecializationMapEntry>,
+    pub specialization_data: Vec<u32>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Hash)]
 pub enum ShaderKind {
     Vertex,
     Fragment,
     Compute,
 }
 impl ShaderKind {
+    #[cfg(feature = "shader-compiler")]
     pub fn to_shaderc_kind(&self) -> shaderc::ShaderKind {
         match self {
             Self::Vertex => shaderc::ShaderKind::Vertex,
@@ -45,44 +72,237 @@ impl ShaderKind {
     }
 }
 
+/// Picked from the extension of the source file: `.hlsl` is HLSL, anything else GLSL.
+///
+/// HLSL spaces are descriptor sets and every register class gets its own range of bindings in
+/// them: `register(t2, space1)` is set 1, binding 2, while `s2` is binding 18, `b2` binding 34
+/// and `u2` binding 50. Vertex inputs get locations in declaration order unless they have a
+/// `[[vk::location(N)]]`, and push constants are declared with `[[vk::push_constant]]`. The `u_`
+/// and `sampler_` prefixes work on the resource names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderLanguage {
+    Glsl,
+    Hlsl,
+}
+
+impl ShaderLanguage {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("hlsl") => Self::Hlsl,
+            _ => Self::Glsl,
+        }
+    }
+}
+
+/// How shaders are compiled. The default is [`Self::PRECOMPILED`]: unoptimized with debug info in
+/// debug builds so captures show the source, optimized and stripped in release builds.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ShaderCompileSettings {
+    pub optimize: bool,
+    pub debug_info: bool,
+}
+
+impl ShaderCompileSettings {
+    /// What `build.rs` compiled [`PRECOMPILED_SHADERS`] with. Other settings need the shaders to be
+    /// compiled at runtime.
+    pub const PRECOMPILED: Self = Self {
+        optimize: cfg!(optimized_shaders),
+        debug_info: !cfg!(optimized_shaders),
+    };
+}
+
+impl Default for ShaderCompileSettings {
+    fn default() -> Self {
+        Self::PRECOMPILED
+    }
+}
+
+/// Selects a variant of a shader: the preprocessor defines it's compiled with and the values of
+/// its specialization constants, by `constant_id`. Every distinct key is compiled once.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ShaderVariantKey {
+    pub defines: BTreeMap<String, String>,
+    pub specialization_constants: BTreeMap<u32, u32>,
+}
+
+impl ShaderVariantKey {
+    /// Defines `name` as `1`.
+    pub fn define(self, name: &str) -> Self {
+        self.define_value(name, 1)
+    }
+
+    pub fn define_value(mut self, name: &str, value: impl ToString) -> Self {
+        self.defines.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Constants are 32 bits wide: bools are 0 or 1 and floats are passed as their bits.
+    pub fn specialize(mut self, constant_id: u32, value: u32) -> Self {
+        self.specialization_constants.insert(constant_id, value);
+        self
+    }
+}
+
+/// The component type of a [`VertexInput`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalarType {
+    Float,
+    Sint,
+    Uint,
+}
+
+/// A `layout(location = N) in` variable of a vertex shader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VertexInput {
+    pub location: u32,
+    pub scalar: ScalarType,
+    pub components: u32,
+}
+
+impl std::fmt::Display for VertexInput {
+    /// Formats the type like GLSL would, e.g. `vec3` or `uint`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (scalar, vector) = match self.scalar {
+            ScalarType::Float => ("float", "vec"),
+            ScalarType::Sint => ("int", "ivec"),
+            ScalarType::Uint => ("uint", "uvec"),
+        };
+        match self.components {
+            1 => f.write_str(scalar),
+            components => write!(f, "{vector}{components}"),
+        }
+    }
+}
+
+/// A shader `build.rs` compiled into the binary, for one set of defines.
+pub struct PrecompiledShader {
+    /// Relative to the crate root, like `shader/main.vert`.
+    pub path: &'static str,
+    pub entry_point: &'static str,
+    /// Only the defines the source mentions, the others can't have changed the SPIR-V.
+    pub defines: &'static [(&'static str, &'static str)],
+    /// Every identifier in the source and its includes, sorted.
+    pub referenced: &'static [&'static str],
+    pub spirv: &'static [u32],
+}
+
+/// Every shader in `shader/`, compiled by `build.rs`.
+pub static PRECOMPILED_SHADERS: &[PrecompiledShader] =
+    include!(concat!(env!("OUT_DIR"), "/precompiled_shaders.rs"));
+
+impl PrecompiledShader {
+    /// The defines of `variant` the shader mentions have to match exactly, the rest are ignored.
+    /// `path` may start with `./`.
+    pub fn find(
+        path: &str,
+        entry_point: &str,
+        variant: &ShaderVariantKey,
+    ) -> Option<&'static Self> {
+        let path = Path::new(path);
+        let path = path.strip_prefix(".").unwrap_or(path);
+        PRECOMPILED_SHADERS.iter().find(|shader| {
+            let used_defines = variant
+                .defines
+                .keys()
+                .filter(|name| shader.referenced.binary_search(&name.as_str()).is_ok())
+                .count();
+            Path::new(shader.path) == path
+                && shader.entry_point == entry_point
+                && shader.defines.len() == used_defines
+                && shader.defines.iter().all(|(name, value)| {
+                    variant.defines.get(*name).map(String::as_str) == Some(*value)
+                })
+        })
+    }
+}
+
+/// The output of [`Shader::compile`].
+pub struct CompiledShader {
+    pub spirv: Vec<u32>,
+    /// Every file the source included, directly or not.
+    pub includes: Vec<PathBuf>,
+}
+
 type DescriptorSetLayout = BTreeMap<u32, rspirv_reflect::DescriptorInfo>;
 type StageDescriptorSetLayouts = BTreeMap<u32, DescriptorSetLayout>;
 
 impl Shader {
+    /// Only the specialization constants of `variant` are used, the defines are already compiled
+    /// into `spirv`.
     pub fn new(
         render_instance: &RenderInstance,
-        spirv: CompilationArtifact,
+        name: &str,
+        spirv: &[u32],
         kind: ShaderKind,
         entry_point: &str,
-    ) -> Self {
-        let refl_info = rspirv_reflect::Reflection::new_from_spirv(spirv.as_binary_u8()).unwrap();
-        let descriptor_sets = refl_info.get_descriptor_sets().unwrap();
+        variant: &ShaderVariantKey,
+    ) -> RenderResult<Self> {
+        let reflection_error = |err: rspirv_reflect::ReflectError| RenderError::ShaderReflection {
+            name: name.to_string(),
+            message: err.to_string(),
+        };
+        let refl_info = rspirv_reflect::Reflection::new_from_spirv(bytemuck::cast_slice(spirv))
+            .map_err(reflection_error)?;
+        let descriptor_sets = refl_info.get_descriptor_sets().map_err(reflection_error)?;
+        let push_constant_range = refl_info
+            .get_push_constant_range()
+            .map_err(reflection_error)?
+            .map(|block| vk::PushConstantRange {
+                stage_flags: kind.to_vk_shader_stage_flag(),
+                offset: block.offset,
+                size: block.size,
+            });
+        let vertex_inputs = match kind {
+            ShaderKind::Vertex => reflect_vertex_inputs(&refl_info.0).map_err(|message| {
+                RenderError::ShaderReflection {
+                    name: name.to_string(),
+                    message,
+                }
+            })?,
+            _ => Vec::new(),
+        };
 
         let module = unsafe {
             render_instance
                 .device()
-                .create_shader_module(
-                    &vk::ShaderModuleCreateInfo::default().code(&spirv.as_binary()),
-                    None,
-                )
-                .expect("Vertex shader module error")
+                .create_shader_module(&vk::ShaderModuleCreateInfo::default().code(spirv), None)?
         };
+        render_instance.0.set_object_name(module, name);
+
+        let specialization_entries = variant
+            .specialization_constants
+            .keys()
+            .enumerate()
+            .map(|(index, constant_id)| vk::SpecializationMapEntry {
+                constant_id: *constant_id,
+                offset: (index * std::mem::size_of::<u32>()) as u32,
+                size: std::mem::size_of::<u32>(),
+            })
+            .collect();
 
-        Self {
+        Ok(Self {
+            name: name.to_string(),
             kind,
             spirv_descripor_set_layouts: descriptor_sets,
+            push_constant_range,
+            vertex_inputs,
             entry_point: entry_point.to_string(),
             entry_point_cstr: CString::new(entry_point).unwrap(),
             module,
-        }
+            spirv: spirv.to_vec(),
+            specialization_entries,
+            specialization_data: variant.specialization_constants.values().copied().collect(),
+        })
     }
 
+    /// Allocates `copies` of the sets from a new pool, which the caller owns and has to destroy.
     pub fn create_descriptor_sets(
         &self,
         render_instance: &RenderInstance,
         descriptor_set_layouts: &[vk::DescriptorSetLayout],
         set_layout_info: &[HashMap<u32, vk::DescriptorType>],
-    ) -> Vec<vk::DescriptorSet> {
+        copies: usize,
+    ) -> RenderResult<(vk::DescriptorPool, Vec<Vec<vk::DescriptorSet>>)> {
         let mut descriptor_pool_sizes: Vec<vk::DescriptorPoolSize> = Vec::new();
         for bindings in set_layout_info.iter() {
             for ty in bindings.values() {
@@ -97,49 +317,212 @@ impl Shader {
                 }
             }
         }
+        for pool_size in &mut descriptor_pool_sizes {
+            pool_size.descriptor_count *= copies as u32;
+        }
 
         let descriptor_pool_info = vk::DescriptorPoolCreateInfo::default()
             .pool_sizes(&descriptor_pool_sizes)
-            .max_sets(2);
+            .max_sets((descriptor_set_layouts.len() * copies).max(1) as u32);
 
-        let descriptor_pool = unsafe {
-            render_instance
-                .device()
-                .create_descriptor_pool(&descriptor_pool_info, None)
-                .unwrap()
-        };
+        let device = render_instance.device();
+        let descriptor_pool =
+            unsafe { device.create_descriptor_pool(&descriptor_pool_info, None)? };
 
         let desc_alloc_info = vk::DescriptorSetAllocateInfo::default()
             .descriptor_pool(descriptor_pool)
             .set_layouts(descriptor_set_layouts);
-        let descriptor_sets = unsafe {
-            render_instance
-                .device()
-                .allocate_descriptor_sets(&desc_alloc_info)
-                .unwrap()
+        let descriptor_sets = (0..copies)
+            .map(|_| unsafe { device.allocate_descriptor_sets(&desc_alloc_info) })
+            .collect::<Result<Vec<_>, _>>();
+        let descriptor_sets = match descriptor_sets {
+            Ok(descriptor_sets) => descriptor_sets,
+            Err(err) => {
+                unsafe { device.destroy_descriptor_pool(descriptor_pool, None) };
+                return Err(err.into());
+            }
         };
 
-        descriptor_sets
+        Ok((descriptor_pool, descriptor_sets))
+    }
+
+    pub fn destroy(&self, device: &ash::Device) {
+        unsafe { device.destroy_shader_module(self.module, None) };
+    }
+
+    pub fn specialization_info(&self) -> vk::SpecializationInfo {
+        vk::SpecializationInfo::default()
+            .map_entries(&self.specialization_entries)
+            .data(bytemuck::cast_slice(&self.specialization_data))
     }
 
-    // pub fn ext_shader_create_info(&self) -> ShaderCreateInfoEXT {
-    //     ShaderCreateInfoEXT::default()
-    //         .name(self.entry_point_cstr.as_c_str())
-    //         .code(&self.spirv)
-    //         .code_type(ShaderCodeTypeEXT::SPIRV)
-    //         .stage(self.kind.to_vk_shader_stage_flag())
-    // }
+    /// `specialization_info` is the one from [`Shader::specialization_info`], it has to outlive
+    /// the create info.
+    pub fn ext_shader_create_info<'a>(
+        &'a self,
+        specialization_info: &'a vk::SpecializationInfo<'a>,
+    ) -> vk::ShaderCreateInfoEXT<'a> {
+        vk::ShaderCreateInfoEXT::default()
+            .name(self.entry_point_cstr.as_c_str())
+            .code(bytemuck::cast_slice(&self.spirv))
+            .code_type(vk::ShaderCodeTypeEXT::SPIRV)
+            .stage(self.kind.to_vk_shader_stage_flag())
+            .specialization_info(specialization_info)
+    }
+
+    /// Creates one `vk::ShaderEXT` per shader, in the same order. The shaders are passed in
+    /// pipeline stage order and created in a single call so they get linked together.
+    pub fn create_linked_shader_objects(
+        render_instance: &RenderInstance,
+        shaders: &[&Shader],
+        descriptor_set_layouts: &[vk::DescriptorSetLayout],
+        push_constant_ranges: &[vk::PushConstantRange],
+    ) -> RenderResult<Vec<vk::ShaderEXT>> {
+        let Some(shader_object) = render_instance.0.shader_object.as_ref() else {
+            return Err(RenderError::ShaderObjectDisabled);
+        };
+
+        let flags = if shaders.len() > 1 {
+            vk::ShaderCreateFlagsEXT::LINK_STAGE
+        } else {
+            vk::ShaderCreateFlagsEXT::empty()
+        };
+
+        let specialization_infos = shaders
+            .iter()
+            .map(|shader| shader.specialization_info())
+            .collect::<Vec<_>>();
+        let create_infos = shaders
+            .iter()
+            .zip(&specialization_infos)
+            .enumerate()
+            .map(|(index, (shader, specialization_info))| {
+                let next_stage = shaders
+                    .get(index + 1)
+                    .map_or(vk::ShaderStageFlags::empty(), |next| {
+                        next.kind.to_vk_shader_stage_flag()
+                    });
+
+                shader
+                    .ext_shader_create_info(specialization_info)
+                    .flags(flags)
+                    .next_stage(next_stage)
+                    .set_layouts(descriptor_set_layouts)
+                    .push_constant_ranges(push_constant_ranges)
+            })
+            .collect::<Vec<_>>();
+
+        let shader_objects = unsafe { shader_object.create_shaders(&create_infos, None) }?;
+        for (shader, shader_object) in shaders.iter().zip(&shader_objects) {
+            render_instance
+                .0
+                .set_object_name(*shader_object, &shader.name);
+        }
+        Ok(shader_objects)
+    }
+}
+
+/// A binding as seen by all stages of a pipeline.
+struct PipelineBinding {
+    info: rspirv_reflect::DescriptorInfo,
+    stage_flags: vk::ShaderStageFlags,
+    /// The first shader that declared it, used in error messages.
+    declared_by: String,
+}
+
+/// The descriptor bindings and push constants of every stage of a pipeline merged together, which
+/// is all that's needed to create its layout.
+pub struct PipelineInterface {
+    /// The shaders it was merged from, used in error messages.
+    name: String,
+    sets: BTreeMap<u32, BTreeMap<u32, PipelineBinding>>,
+    pub push_constant_range: Option<vk::PushConstantRange>,
+}
+
+impl PipelineInterface {
+    /// A binding declared by several stages has to have the same type, count and name in all of
+    /// them, since the name decides about immutable samplers and dynamic buffers. Bindings are
+    /// only visible to the stages that declare them.
+    pub fn merge(shaders: &[&Shader]) -> RenderResult<Self> {
+        let name = shaders
+            .iter()
+            .map(|shader| shader.name.as_str())
+            .collect::<Vec<_>>()
+            .join(" + ");
+        let mut sets: BTreeMap<u32, BTreeMap<u32, PipelineBinding>> = BTreeMap::new();
+        let mut push_constant_range: Option<vk::PushConstantRange> = None;
+
+        for shader in shaders {
+            let stage = shader.kind.to_vk_shader_stage_flag();
+            for (set_index, set) in &shader.spirv_descripor_set_layouts {
+                let merged_set = sets.entry(*set_index).or_default();
+                for (binding_index, info) in set {
+                    let merged = match merged_set.entry(*binding_index) {
+                        Entry::Vacant(entry) => entry.insert(PipelineBinding {
+                            info: info.clone(),
+                            stage_flags: vk::ShaderStageFlags::empty(),
+                            declared_by: shader.name.clone(),
+                        }),
+                        Entry::Occupied(entry) => entry.into_mut(),
+                    };
+                    if merged.info.ty != info.ty
+                        || merged.info.binding_count != info.binding_count
+                        || merged.info.name != info.name
+                    {
+                        return Err(RenderError::ShaderReflection {
+                            name,
+                            message: format!(
+                                "set {set_index} binding {binding_index} is `{}` ({:?}, {:?}) in {} but `{}` ({:?}, {:?}) in {}",
+                                merged.info.name,
+                                merged.info.ty,
+                                merged.info.binding_count,
+                                merged.declared_by,
+                                info.name,
+                                info.ty,
+                                info.binding_count,
+                                shader.name
+                            ),
+                        });
+                    }
+                    merged.stage_flags |= stage;
+                }
+            }
+
+            // a range may cover more than a stage reads, so a single one spanning the blocks of
+            // all stages is always valid
+            if let Some(range) = shader.push_constant_range {
+                push_constant_range = Some(match push_constant_range {
+                    Some(merged) => {
+                        let offset = merged.offset.min(range.offset);
+                        let end = (merged.offset + merged.size).max(range.offset + range.size);
+                        vk::PushConstantRange {
+                            stage_flags: merged.stage_flags | range.stage_flags,
+                            offset,
+                            size: end - offset,
+                        }
+                    }
+                    None => range,
+                });
+            }
+        }
+
+        Ok(Self {
+            name,
+            sets,
+            push_constant_range,
+        })
+    }
 
     pub fn create_descriptor_set_layouts(
         &self,
         render_instance: &RenderInstance,
-    ) -> (
+    ) -> RenderResult<(
         Vec<vk::DescriptorSetLayout>,
         Vec<HashMap<u32, vk::DescriptorType>>,
-    ) {
+    )> {
         let samplers = TempList::new();
         let set_count = self
-            .spirv_descripor_set_layouts
+            .sets
             .keys()
             .map(|set_index| *set_index + 1)
             .max()
@@ -150,8 +533,7 @@ impl Shader {
             Vec::with_capacity(set_count as usize);
 
         for set_index in 0..set_count {
-            let stage_flags = vk::ShaderStageFlags::ALL;
-            let set = self.spirv_descripor_set_layouts.get(&set_index);
+            let set = self.sets.get(&set_index);
 
             if let Some(set) = set {
                 let mut bindings: Vec<vk::DescriptorSetLayoutBinding> =
@@ -161,7 +543,9 @@ impl Shader {
 
                 let mut set_layout_create_flags = vk::DescriptorSetLayoutCreateFlags::empty();
 
-                for (binding_index, binding) in set.iter() {
+                for (binding_index, merged) in set.iter() {
+                    let binding = &merged.info;
+
                     // if binding.name.starts_with("u_") {
                     //     binding_flags[bindings.len()] =
                     //         vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
@@ -178,13 +562,11 @@ impl Shader {
                     } else {
                         match binding.binding_count {
                             BindingCount::One => 1,
-                            BindingCount::StaticSized(size) => size.try_into().unwrap(),
+                            BindingCount::StaticSized(size) => size as u32,
                             BindingCount::Unbounded => render_instance.0.max_descriptor_count,
                         }
                     };
 
-                    println!("{} binding: {:?} {}", binding_index, binding, descriptor_count);
-
                     match binding.ty {
                         rspirv_reflect::DescriptorType::UNIFORM_BUFFER
                         | rspirv_reflect::DescriptorType::UNIFORM_TEXEL_BUFFER
@@ -225,54 +607,29 @@ impl Shader {
                                     rspirv_reflect::DescriptorType::SAMPLED_IMAGE => {
                                         vk::DescriptorType::SAMPLED_IMAGE
                                     }
-                                    _ => unimplemented!("{:?}", binding),
+                                    _ => unreachable!(),
                                 })
-                                .stage_flags(stage_flags),
+                                .stage_flags(merged.stage_flags),
                         ),
 
                         rspirv_reflect::DescriptorType::SAMPLER => {
-                            let name_prefix = "sampler_";
-                            if let Some(mut spec) = binding.name.strip_prefix(name_prefix) {
-                                let texel_filter = match &spec[..1] {
-                                    "n" => vk::Filter::NEAREST,
-                                    "l" => vk::Filter::LINEAR,
-                                    _ => panic!("{}", &spec[..1]),
-                                };
-                                spec = &spec[1..];
-
-                                let mipmap_mode = match &spec[..1] {
-                                    "n" => vk::SamplerMipmapMode::NEAREST,
-                                    "l" => vk::SamplerMipmapMode::LINEAR,
-                                    _ => panic!("{}", &spec[..1]),
-                                };
-                                spec = &spec[1..];
-
-                                let address_modes = match spec {
-                                    "r" => vk::SamplerAddressMode::REPEAT,
-                                    "mr" => vk::SamplerAddressMode::MIRRORED_REPEAT,
-                                    "c" => vk::SamplerAddressMode::CLAMP_TO_EDGE,
-                                    "cb" => vk::SamplerAddressMode::CLAMP_TO_BORDER,
-                                    _ => panic!("{}", spec),
-                                };
-
-                                let renderer = &render_instance.0;
-                                bindings.push(
-                                    vk::DescriptorSetLayoutBinding::default()
-                                        .descriptor_count(1)
-                                        .descriptor_type(vk::DescriptorType::SAMPLER)
-                                        .stage_flags(stage_flags)
-                                        .binding(*binding_index)
-                                        .immutable_samplers(std::slice::from_ref(samplers.add(
-                                            renderer.get_sampler(SamplerDesc {
-                                                texel_filter,
-                                                mipmap_mode,
-                                                address_modes,
-                                            }),
-                                        ))),
-                                );
-                            } else {
-                                panic!("{}", binding.name);
-                            }
+                            let Some(desc) = parse_sampler_name(&binding.name) else {
+                                return Err(self.reflection_error(format!(
+                                    "sampler `{}` isn't named like `sampler_<filter><mipmap><address mode>`, e.g. `sampler_nlr`",
+                                    binding.name
+                                )));
+                            };
+
+                            bindings.push(
+                                vk::DescriptorSetLayoutBinding::default()
+                                    .descriptor_count(1)
+                                    .descriptor_type(vk::DescriptorType::SAMPLER)
+                                    .stage_flags(merged.stage_flags)
+                                    .binding(*binding_index)
+                                    .immutable_samplers(std::slice::from_ref(
+                                        samplers.add(render_instance.0.get_sampler(desc)),
+                                    )),
+                            );
                         }
                         rspirv_reflect::DescriptorType::ACCELERATION_STRUCTURE_KHR => bindings
                             .push(
@@ -280,10 +637,15 @@ impl Shader {
                                     .binding(*binding_index)
                                     .descriptor_count(descriptor_count) // TODO
                                     .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
-                                    .stage_flags(stage_flags),
+                                    .stage_flags(merged.stage_flags),
                             ),
 
-                        _ => unimplemented!("{:?}", binding),
+                        _ => {
+                            return Err(self.reflection_error(format!(
+                                "binding {binding_index} `{}` has unsupported descriptor type {:?}",
+                                binding.name, binding.ty
+                            )))
+                        }
                     }
                 }
 
@@ -292,16 +654,13 @@ impl Shader {
                         .binding_flags(&binding_flags);
 
                 let set_layout = unsafe {
-                    render_instance
-                        .device()
-                        .create_descriptor_set_layout(
-                            &vk::DescriptorSetLayoutCreateInfo::default()
-                                .flags(set_layout_create_flags)
-                                .bindings(&bindings)
-                                .push_next(&mut binding_flags_create_info),
-                            None,
-                        )
-                        .unwrap()
+                    render_instance.device().create_descriptor_set_layout(
+                        &vk::DescriptorSetLayoutCreateInfo::default()
+                            .flags(set_layout_create_flags)
+                            .bindings(&bindings)
+                            .push_next(&mut binding_flags_create_info),
+                        None,
+                    )?
                 };
 
                 set_layouts.push(set_layout);
@@ -313,13 +672,10 @@ impl Shader {
                 );
             } else {
                 let set_layout = unsafe {
-                    render_instance
-                        .device()
-                        .create_descriptor_set_layout(
-                            &vk::DescriptorSetLayoutCreateInfo::default(),
-                            None,
-                        )
-                        .unwrap()
+                    render_instance.device().create_descriptor_set_layout(
+                        &vk::DescriptorSetLayoutCreateInfo::default(),
+                        None,
+                    )?
                 };
 
                 set_layouts.push(set_layout);
@@ -327,53 +683,342 @@ impl Shader {
             }
         }
 
-        (set_layouts, set_layout_info)
+        Ok((set_layouts, set_layout_info))
     }
 
-    pub fn from_file(
+    fn reflection_error(&self, message: String) -> RenderError {
+        RenderError::ShaderReflection {
+            name: self.name.clone(),
+            message,
+        }
+    }
+}
+
+impl Shader {
+    /// Compiles the variant of the shader at `path` selected by `variant`. Unless shaders are
+    /// hot reloaded, the SPIR-V `build.rs` embedded is used when the variant was precompiled with
+    /// the renderer's [`ShaderCompileSettings`].
+    pub fn from_file_variant(
         render_instance: &RenderInstance,
         path: &str,
         kind: ShaderKind,
         entry_point: &str,
-    ) -> Self {
+        variant: &ShaderVariantKey,
+    ) -> RenderResult<Self> {
+        // without the compiler the precompiled SPIR-V is all there is, whatever the settings
+        let settings_match = render_instance.0.shader_settings
+            == ShaderCompileSettings::PRECOMPILED
+            || !cfg!(feature = "shader-compiler");
+        let precompiled = PrecompiledShader::find(path, entry_point, variant)
+            .filter(|_| settings_match && render_instance.0.shader_watcher.is_none());
+        if let Some(precompiled) = precompiled {
+            return Self::new(
+                render_instance,
+                path,
+                precompiled.spirv,
+                kind,
+                entry_point,
+                variant,
+            );
+        }
+
+        let compiled = Self::compile(
+            path,
+            &kind,
+            entry_point,
+            variant,
+            &render_instance.0.shader_settings,
+            render_instance.0.shader_cache.as_ref(),
+        );
+        // a shader that doesn't compile is watched too, so fixing it reloads it
+        if let Some(watcher) = &render_instance.0.shader_watcher {
+            let includes = compiled
+                .as_ref()
+                .map_or(&[][..], |compiled| &compiled.includes);
+            watcher.track(path, &kind, entry_point, variant, includes);
+        }
+        let compiled = compiled?;
+        Self::new(
+            render_instance,
+            path,
+            &compiled.spirv,
+            kind,
+            entry_point,
+            variant,
+        )
+    }
+
+    /// Compiles the GLSL or HLSL at `path` with shaderc, see [`ShaderLanguage`], or takes the SPIR-V from `cache` when neither
+    /// the source, its includes nor the options changed since it was last compiled.
+    #[cfg(feature = "shader-compiler")]
+    pub fn compile(
+        path: &str,
+        kind: &ShaderKind,
+        entry_point: &str,
+        variant: &ShaderVariantKey,
+        settings: &ShaderCompileSettings,
+        cache: Option<&ShaderCache>,
+    ) -> RenderResult<CompiledShader> {
+        let source = std::fs::read_to_string(path).map_err(|source| RenderError::ShaderSource {
+            path: path.into(),
+            source,
+        })?;
+
+        let mut key = ShaderCache::key();
+        (path, &source, kind, entry_point, &variant.defines, settings).hash(&mut key);
+        if let Some((spirv, includes)) = cache.and_then(|cache| cache.get(&key)) {
+            return Ok(CompiledShader { spirv, includes });
+        }
+
+        // every file the include callback resolved, with the contents it was compiled with
+        let includes = RefCell::new(Vec::<(PathBuf, String)>::new());
+        let sources = RefCell::new(SourceFiles::default());
+        sources.borrow_mut().add(path, &source);
+
         let compiler = shaderc::Compiler::new().unwrap();
         let mut options = shaderc::CompileOptions::new().unwrap();
-        options.add_macro_definition("EP", Some("main"));
-        options.set_target_env(
-            shaderc::TargetEnv::Vulkan,
-            shaderc::EnvVersion::Vulkan1_2 as u32,
+        shader_options::configure(
+            &mut options,
+            ShaderLanguage::from_path(Path::new(path)) == ShaderLanguage::Hlsl,
+            variant
+                .defines
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+            settings.optimize,
+            settings.debug_info,
         );
-        options.set_optimization_level(shaderc::OptimizationLevel::Zero);
-        options.set_generate_debug_info();
         options.set_include_callback(|name, include_type, source_file, _depth| {
-            let path = if include_type == shaderc::IncludeType::Relative {
-                Path::new(Path::new(source_file).parent().unwrap()).join(name)
-            } else {
-                Path::new("shader").join(name)
-            };
-
-            match std::fs::read_to_string(&path) {
-                Ok(glsl_code) => Ok(shaderc::ResolvedInclude {
-                    resolved_name: String::from(name),
-                    content: glsl_code,
-                }),
-                Err(err) => Err(format!(
-                    "Failed to resolve include to {} in {} (was looking for {:?}): {}",
-                    name, source_file, path, err
-                )),
-            }
+            let (path, content) = shader_options::read_include(name, include_type, source_file)?;
+            // named by its path, so diagnostics and nested relative includes find it
+            let resolved_name = path.to_string_lossy().into_owned();
+            sources
+                .borrow_mut()
+                .add_include(&resolved_name, &content, source_file, name);
+            includes.borrow_mut().push((path, content.clone()));
+            Ok(shaderc::ResolvedInclude {
+                resolved_name,
+                content,
+            })
         });
 
-        let spirv = compiler
+        let artifact = compiler
             .compile_into_spirv(
-                &std::fs::read_to_string(path).unwrap(),
+                &source,
                 kind.to_shaderc_kind(),
                 path,
                 entry_point,
                 Some(&options),
             )
-            .unwrap();
+            .map_err(|err| {
+                let output = match err {
+                    shaderc::Error::CompilationError(_, output) => output,
+                    err => err.to_string(),
+                };
+                RenderError::ShaderCompilation {
+                    name: path.to_string(),
+                    diagnostics: ShaderDiagnostics::parse(&output, path, &sources.borrow()),
+                }
+            })?;
+        drop(options);
+        if artifact.get_num_warnings() > 0 {
+            ShaderDiagnostics::parse(&artifact.get_warning_messages(), path, &sources.borrow())
+                .log();
+        }
+
+        let includes = includes.into_inner();
+        let spirv = artifact.as_binary().to_vec();
+        if let Some(cache) = cache {
+            cache.insert(&key, &spirv, &includes);
+        }
+        Ok(CompiledShader {
+            spirv,
+            includes: includes.into_iter().map(|(path, _)| path).collect(),
+        })
+    }
+
+    /// Only precompiled shaders can be loaded without the `shader-compiler` feature.
+    #[cfg(not(feature = "shader-compiler"))]
+    pub fn compile(
+        path: &str,
+        _kind: &ShaderKind,
+        _entry_point: &str,
+        _variant: &ShaderVariantKey,
+        _settings: &ShaderCompileSettings,
+        _cache: Option<&ShaderCache>,
+    ) -> RenderResult<CompiledShader> {
+        Err(RenderError::ShaderCompilerDisabled {
+            name: path.to_string(),
+        })
+    }
+}
+
+/// Finds the `Input` variables with a location, built-ins like `gl_VertexIndex` don't have one.
+fn reflect_vertex_inputs(module: &Module) -> Result<Vec<VertexInput>, String> {
+    let locations = module
+        .annotations
+        .iter()
+        .filter_map(|inst| match (inst.class.opcode, inst.operands.as_slice()) {
+            (
+                Op::Decorate,
+                [Operand::IdRef(target), Operand::Decoration(Decoration::Location), Operand::LiteralInt32(location)],
+            ) => Some((*target, *location)),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+    let types = module
+        .types_global_values
+        .iter()
+        .filter_map(|inst| Some((inst.result_id?, inst)))
+        .collect::<HashMap<_, _>>();
+
+    let mut inputs = Vec::new();
+    for variable in &module.types_global_values {
+        if variable.class.opcode != Op::Variable
+            || !matches!(
+                variable.operands.first(),
+                Some(Operand::StorageClass(StorageClass::Input))
+            )
+        {
+            continue;
+        }
+        let Some(location) = variable.result_id.and_then(|id| locations.get(&id)) else {
+            continue;
+        };
+
+        let pointee = variable
+            .result_type
+            .and_then(|pointer| types.get(&pointer))
+            .and_then(|pointer| match pointer.operands.as_slice() {
+                [Operand::StorageClass(_), Operand::IdRef(pointee)] => types.get(pointee),
+                _ => None,
+            });
+        let Some((scalar, components)) = pointee.and_then(|ty| vertex_input_type(ty, &types))
+        else {
+            return Err(format!(
+                "the input at location {location} isn't a 32 bit scalar or vector, which is all vertex buffers provide"
+            ));
+        };
+        inputs.push(VertexInput {
+            location: *location,
+            scalar,
+            components,
+        });
+    }
 
-        Self::new(render_instance, spirv, kind, entry_point)
+    inputs.sort_by_key(|input| input.location);
+    Ok(inputs)
+}
+
+fn vertex_input_type(
+    ty: &Instruction,
+    types: &HashMap<Word, &Instruction>,
+) -> Option<(ScalarType, u32)> {
+    match (ty.class.opcode, ty.operands.as_slice()) {
+        (Op::TypeFloat, [Operand::LiteralInt32(32)]) => Some((ScalarType::Float, 1)),
+        (Op::TypeInt, [Operand::LiteralInt32(32), Operand::LiteralInt32(signedness)]) => {
+            Some(match signedness {
+                0 => (ScalarType::Uint, 1),
+                _ => (ScalarType::Sint, 1),
+            })
+        }
+        (Op::TypeVector, [Operand::IdRef(component), Operand::LiteralInt32(count)]) => {
+            match vertex_input_type(types.get(component)?, types)? {
+                (scalar, 1) => Some((scalar, *count)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Parses the immutable sampler a binding named like `sampler_nlr` asks for: the texel filter and
+/// mipmap mode as `n`earest or `l`inear, followed by the address mode as `r`epeat, `mr` mirrored
+/// repeat, `c`lamp to edge or `cb` clamp to border.
+fn parse_sampler_name(name: &str) -> Option<SamplerDesc> {
+    let spec = name.strip_prefix("sampler_")?;
+
+    let texel_filter = match spec.get(..1)? {
+        "n" => vk::Filter::NEAREST,
+        "l" => vk::Filter::LINEAR,
+        _ => return None,
+    };
+    let mipmap_mode = match spec.get(1..2)? {
+        "n" => vk::SamplerMipmapMode::NEAREST,
+        "l" => vk::SamplerMipmapMode::LINEAR,
+        _ => return None,
+    };
+    let address_modes = match spec.get(2..)? {
+        "r" => vk::SamplerAddressMode::REPEAT,
+        "mr" => vk::SamplerAddressMode::MIRRORED_REPEAT,
+        "c" => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        "cb" => vk::SamplerAddressMode::CLAMP_TO_BORDER,
+        _ => return None,
+    };
+
+    Some(SamplerDesc {
+        texel_filter,
+        mipmap_mode,
+        address_modes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HLSL_SHADER: &str = "shader/textured.hlsl";
+
+    #[cfg(feature = "shader-compiler")]
+    fn compile_hlsl(kind: ShaderKind, entry_point: &str) -> rspirv_reflect::Reflection {
+        let compiled = Shader::compile(
+            HLSL_SHADER,
+            &kind,
+            entry_point,
+            &ShaderVariantKey::default(),
+            &ShaderCompileSettings::default(),
+            None,
+        )
+        .unwrap_or_else(|err| panic!("{err}"));
+        rspirv_reflect::Reflection::new_from_spirv(bytemuck::cast_slice(&compiled.spirv)).unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "shader-compiler")]
+    fn hlsl_register_classes_get_separate_bindings() {
+        let reflection = compile_hlsl(ShaderKind::Fragment, "ps_main");
+        let sets = reflection.get_descriptor_sets().unwrap();
+
+        let textures = &sets[&0][&0];
+        assert_eq!(textures.name, "u_textures");
+        assert_eq!(textures.ty, rspirv_reflect::DescriptorType::SAMPLED_IMAGE);
+        let sampler = &sets[&0][&16];
+        assert_eq!(sampler.name, "sampler_llr");
+        assert_eq!(sampler.ty, rspirv_reflect::DescriptorType::SAMPLER);
+        assert!(parse_sampler_name(&sampler.name).is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "shader-compiler")]
+    fn reflects_hlsl_vertex_inputs_and_push_constants() {
+        let reflection = compile_hlsl(ShaderKind::Vertex, "vs_main");
+
+        let inputs = reflect_vertex_inputs(&reflection.0).unwrap();
+        let inputs = inputs
+            .iter()
+            .map(|input| (input.location, input.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(inputs, [(0, "vec3".to_string()), (2, "vec2".to_string())]);
+        assert!(reflection.get_push_constant_range().unwrap().is_some());
+    }
+
+    #[test]
+    fn hlsl_entry_points_are_precompiled() {
+        for entry_point in ["vs_main", "ps_main"] {
+            assert!(
+                PrecompiledShader::find(HLSL_SHADER, entry_point, &ShaderVariantKey::default())
+                    .is_some(),
+                "{entry_point} wasn't precompiled"
+            );
+        }
     }
 }
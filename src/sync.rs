@@ -0,0 +1,259 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, MutexGuard,
+};
+
+use ash::{vk, Device};
+
+use crate::error::RenderResult;
+
+/// A value on a [`Timeline`]. Reached once everything submitted before the submit that signals it
+/// has finished on the GPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SyncPoint {
+    pub semaphore: vk::Semaphore,
+    pub value: u64,
+}
+
+impl SyncPoint {
+    pub fn is_complete(&self, device: &Device) -> bool {
+        unsafe { device.get_semaphore_counter_value(self.semaphore) }
+            .map_or(false, |value| value >= self.value)
+    }
+
+    /// Blocks until the GPU reaches this point.
+    pub fn wait(&self, device: &Device) -> RenderResult<()> {
+        unsafe {
+            device.wait_semaphores(
+                &vk::SemaphoreWaitInfo::default()
+                    .semaphores(std::slice::from_ref(&self.semaphore))
+                    .values(std::slice::from_ref(&self.value)),
+                std::u64::MAX,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A timeline semaphore counting the submits to one queue.
+///
+/// Values have to be signaled in the order they were handed out, submits that race each other
+/// should reserve them through [`SubmitSemaphores::submit_signaling`].
+pub struct Timeline {
+    pub semaphore: vk::Semaphore,
+    last_submitted: AtomicU64,
+}
+
+impl Timeline {
+    pub unsafe fn new(device: &Device) -> RenderResult<Self> {
+        let mut type_create_info = vk::SemaphoreTypeCreateInfo::default()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(0);
+        let semaphore = device.create_semaphore(
+            &vk::SemaphoreCreateInfo::default().push_next(&mut type_create_info),
+            None,
+        )?;
+
+        Ok(Self {
+            semaphore,
+            last_submitted: AtomicU64::new(0),
+        })
+    }
+
+    /// Reserves the point the next submit to this queue should signal.
+    pub fn next(&self) -> SyncPoint {
+        SyncPoint {
+            semaphore: self.semaphore,
+            value: self.last_submitted.fetch_add(1, Ordering::AcqRel) + 1,
+        }
+    }
+
+    /// Gives `point` back after the submit that should have signaled it failed. Only works for the
+    /// latest point, which it is when it was reserved with the queue locked.
+    fn release(&self, point: SyncPoint) {
+        let _ = self.last_submitted.compare_exchange(
+            point.value,
+            point.value - 1,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+    }
+
+    /// The most recently reserved point, waiting on it waits for all work on this timeline.
+    pub fn last_submitted(&self) -> SyncPoint {
+        SyncPoint {
+            semaphore: self.semaphore,
+            value: self.last_submitted.load(Ordering::Acquire),
+        }
+    }
+
+    pub unsafe fn destroy(&self, device: &Device) {
+        device.destroy_semaphore(self.semaphore, None);
+    }
+}
+
+/// A queue and the lock every submit to it goes through, host access to a queue has to be
+/// externally synchronized. Queues with the same handle share the lock.
+#[derive(Debug, Clone)]
+pub struct Queue {
+    pub handle: vk::Queue,
+    lock: Arc<Mutex<()>>,
+}
+
+impl Queue {
+    pub fn new(handle: vk::Queue) -> Self {
+        Self {
+            handle,
+            lock: Arc::default(),
+        }
+    }
+
+    /// `handle`, sharing the lock of `other` when they're the same queue.
+    pub fn aliasing(handle: vk::Queue, other: &Queue) -> Self {
+        if handle == other.handle {
+            other.clone()
+        } else {
+            Self::new(handle)
+        }
+    }
+
+    /// Whether both are the same queue, locking both would deadlock.
+    pub fn aliases(&self, other: &Queue) -> bool {
+        Arc::ptr_eq(&self.lock, &other.lock)
+    }
+
+    /// Held while the queue is used from the host.
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap()
+    }
+}
+
+/// One timeline for each queue in [`ExampleBase`](crate::ctx::ExampleBase). Queues that share a
+/// family still get their own timeline.
+pub struct QueueTimelines {
+    pub graphics: Timeline,
+    pub transfer: Timeline,
+}
+
+impl QueueTimelines {
+    pub unsafe fn new(device: &Device) -> RenderResult<Self> {
+        Ok(Self {
+            graphics: Timeline::new(device)?,
+            transfer: Timeline::new(device)?,
+        })
+    }
+
+    pub unsafe fn destroy(&self, device: &Device) {
+        self.graphics.destroy(device);
+        self.transfer.destroy(device);
+    }
+}
+
+/// The semaphores a submit waits on and signals. Binary semaphores, like the ones from the
+/// swapchain, and timeline points can be mixed.
+#[derive(Debug, Clone, Default)]
+pub struct SubmitSemaphores {
+    wait_semaphores: Vec<vk::Semaphore>,
+    wait_values: Vec<u64>,
+    wait_mask: Vec<vk::PipelineStageFlags>,
+    signal_semaphores: Vec<vk::Semaphore>,
+    signal_values: Vec<u64>,
+}
+
+impl SubmitSemaphores {
+    pub fn wait(mut self, point: SyncPoint, stage: vk::PipelineStageFlags) -> Self {
+        self.wait_semaphores.push(point.semaphore);
+        self.wait_values.push(point.value);
+        self.wait_mask.push(stage);
+        self
+    }
+
+    pub fn wait_binary(mut self, semaphore: vk::Semaphore, stage: vk::PipelineStageFlags) -> Self {
+        self.wait_semaphores.push(semaphore);
+        // ignored for binary semaphores
+        self.wait_values.push(0);
+        self.wait_mask.push(stage);
+        self
+    }
+
+    pub fn signal(mut self, point: SyncPoint) -> Self {
+        self.signal_semaphores.push(point.semaphore);
+        self.signal_values.push(point.value);
+        self
+    }
+
+    pub fn signal_binary(mut self, semaphore: vk::Semaphore) -> Self {
+        self.signal_semaphores.push(semaphore);
+        self.signal_values.push(0);
+        self
+    }
+
+    /// Submits `command_buffers` to `queue`. When that fails `fence` is still signaled by an
+    /// empty submit, so it can be waited on like after any other submit.
+    pub unsafe fn submit(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        command_buffers: &[vk::CommandBuffer],
+        fence: vk::Fence,
+    ) -> RenderResult<()> {
+        let _queue = queue.lock();
+        self.submit_locked(device, queue, command_buffers, fence)
+    }
+
+    /// Submits with the next point on `timeline` signaled and returns it. The point is reserved
+    /// with the queue locked, so points are signaled in order even when several threads submit,
+    /// and given back when the submit fails so nothing waits on a point that's never signaled.
+    pub unsafe fn submit_signaling(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        timeline: &Timeline,
+        command_buffers: &[vk::CommandBuffer],
+        fence: vk::Fence,
+    ) -> RenderResult<SyncPoint> {
+        let _queue = queue.lock();
+        let point = timeline.next();
+        let submitted =
+            self.clone()
+                .signal(point)
+                .submit_locked(device, queue, command_buffers, fence);
+        if submitted.is_err() {
+            timeline.release(point);
+        }
+        submitted.map(|()| point)
+    }
+
+    unsafe fn submit_locked(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        command_buffers: &[vk::CommandBuffer],
+        fence: vk::Fence,
+    ) -> RenderResult<()> {
+        let mut timeline_submit_info = vk::TimelineSemaphoreSubmitInfo::default()
+            .wait_semaphore_values(&self.wait_values)
+            .signal_semaphore_values(&self.signal_values);
+
+        let submit_info = vk::SubmitInfo::default()
+            .wait_semaphores(&self.wait_semaphores)
+            .wait_dst_stage_mask(&self.wait_mask)
+            .command_buffers(command_buffers)
+            .signal_semaphores(&self.signal_semaphores)
+            .push_next(&mut timeline_submit_info);
+
+        let submitted = device.queue_submit(queue.handle, &[submit_info], fence);
+        if let Err(err) = submitted {
+            // the fence was reset for this submit, the next wait on it would block forever
+            if fence != vk::Fence::null() {
+                if let Err(signal_err) = device.queue_submit(queue.handle, &[], fence) {
+                    tracing::error!(
+                        "Failed to signal the fence of a failed submit ({err}): {signal_err}"
+                    );
+                }
+            }
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
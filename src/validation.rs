@@ -0,0 +1,242 @@
+use std::borrow::Cow;
+use std::ffi::{c_void, CStr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use ash::prelude::VkResult;
+use ash::{vk, Entry};
+
+const VALIDATION_LAYER: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"VK_LAYER_KHRONOS_validation\0") };
+/// Emulates `VK_KHR_synchronization2` on drivers that don't have it yet.
+const SYNCHRONIZATION2_LAYER: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"VK_LAYER_KHRONOS_synchronization2\0") };
+
+/// Which parts of the Khronos validation layer run, set on the
+/// [`RenderPlugin`](crate::render::RenderPlugin).
+///
+/// Everything the layer and the driver report goes to `tracing` under the `vulkan` target, with
+/// the message id as a field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationSettings {
+    /// Enables `VK_LAYER_KHRONOS_validation`, the other options need it. On in debug builds.
+    pub enabled: bool,
+    /// Instruments shaders to catch out of bounds accesses and the like on the GPU. Slow.
+    pub gpu_assisted: bool,
+    pub best_practices: bool,
+    /// Reports missing barriers and other hazards between commands.
+    pub synchronization: bool,
+    /// Panic on the renderer thread after the first validation error, so tests fail on them.
+    pub panic_on_error: bool,
+    /// Loads `VK_LAYER_KHRONOS_synchronization2` when it's installed, for drivers that don't
+    /// support the extension natively. Independent of `enabled`.
+    pub emulate_synchronization2: bool,
+}
+
+impl Default for ValidationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: cfg!(debug_assertions),
+            gpu_assisted: false,
+            best_practices: false,
+            synchronization: false,
+            panic_on_error: false,
+            emulate_synchronization2: false,
+        }
+    }
+}
+
+impl ValidationSettings {
+    /// Validation with synchronization checks that panics on errors, for tests. GPU-assisted
+    /// validation stays off, software rasterizers take ages with it.
+    pub fn strict() -> Self {
+        Self {
+            enabled: true,
+            gpu_assisted: false,
+            best_practices: false,
+            synchronization: true,
+            panic_on_error: true,
+            emulate_synchronization2: false,
+        }
+    }
+
+    /// The instance layers to enable. Layers that aren't installed are skipped with a warning,
+    /// so validation never stops the renderer from starting.
+    pub fn layers(&self, entry: &Entry) -> VkResult<Vec<&'static CStr>> {
+        let available = entry.enumerate_instance_layer_properties()?;
+        let is_available = |layer: &CStr| {
+            available.iter().any(
+                |properties| unsafe { CStr::from_ptr(properties.layer_name.as_ptr()) } == layer,
+            )
+        };
+
+        let mut layers = vec![];
+        if self.emulate_synchronization2 {
+            if is_available(SYNCHRONIZATION2_LAYER) {
+                layers.push(SYNCHRONIZATION2_LAYER);
+            } else {
+                tracing::warn!(
+                    "Emulating synchronization2 is enabled, but {} isn't installed",
+                    SYNCHRONIZATION2_LAYER.to_string_lossy()
+                );
+            }
+        }
+        if self.enabled {
+            if is_available(VALIDATION_LAYER) {
+                layers.push(VALIDATION_LAYER);
+            } else {
+                tracing::warn!(
+                    "Validation is enabled, but {} isn't installed",
+                    VALIDATION_LAYER.to_string_lossy()
+                );
+            }
+        }
+        Ok(layers)
+    }
+
+    /// Goes into a `vk::ValidationFeaturesEXT` when it isn't empty.
+    pub fn enabled_features(&self) -> Vec<vk::ValidationFeatureEnableEXT> {
+        if !self.enabled {
+            return vec![];
+        }
+
+        let mut features = vec![];
+        if self.gpu_assisted {
+            features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED);
+            features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED_RESERVE_BINDING_SLOT);
+        }
+        if self.best_practices {
+            features.push(vk::ValidationFeatureEnableEXT::BEST_PRACTICES);
+        }
+        if self.synchronization {
+            features.push(vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION);
+        }
+        features
+    }
+}
+
+/// Shared with [`vulkan_debug_callback`] through its user data pointer, so it has to stay at the
+/// same address until the messenger is destroyed.
+#[derive(Debug, Default)]
+pub struct DebugMessengerState {
+    panic_on_error: bool,
+    errors: AtomicU32,
+    first_error: Mutex<Option<String>>,
+}
+
+impl DebugMessengerState {
+    pub fn new(settings: &ValidationSettings) -> Self {
+        Self {
+            panic_on_error: settings.panic_on_error,
+            ..Default::default()
+        }
+    }
+
+    pub fn create_info(&self) -> vk::DebugUtilsMessengerCreateInfoEXT {
+        vk::DebugUtilsMessengerCreateInfoEXT::default()
+            .message_severity(
+                vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
+                    | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                    | vk::DebugUtilsMessageSeverityFlagsEXT::INFO,
+            )
+            .message_type(
+                vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                    | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+                    | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+            )
+            .pfn_user_callback(Some(vulkan_debug_callback))
+            .user_data(self as *const Self as *mut c_void)
+    }
+
+    pub fn error_count(&self) -> u32 {
+        self.errors.load(Ordering::Acquire)
+    }
+
+    /// Panics when [`ValidationSettings::panic_on_error`] is set and an error has been reported.
+    /// Panicking in the callback itself would unwind into the driver.
+    pub fn check(&self) {
+        if !self.panic_on_error || self.error_count() == 0 || std::thread::panicking() {
+            return;
+        }
+        let first_error = self.first_error.lock().unwrap().take();
+        panic!(
+            "{} Vulkan validation error(s), the first one: {}",
+            self.error_count(),
+            first_error.unwrap_or_default()
+        );
+    }
+
+    fn record_error(&self, message: &str) {
+        if self.errors.fetch_add(1, Ordering::AcqRel) == 0 {
+            *self.first_error.lock().unwrap() = Some(message.to_string());
+        }
+    }
+}
+
+/// Routes messages into `tracing`. Info goes to debug, the loader and layers are chatty at that
+/// level.
+unsafe extern "system" fn vulkan_debug_callback(
+    message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    user_data: *mut c_void,
+) -> vk::Bool32 {
+    let callback_data = *p_callback_data;
+    let message_id_number = callback_data.message_id_number;
+
+    let message_id_name = if callback_data.p_message_id_name.is_null() {
+        Cow::from("")
+    } else {
+        CStr::from_ptr(callback_data.p_message_id_name).to_string_lossy()
+    };
+
+    let message = if callback_data.p_message.is_null() {
+        Cow::from("")
+    } else {
+        CStr::from_ptr(callback_data.p_message).to_string_lossy()
+    };
+
+    match message_severity {
+        vk::DebugUtilsMessageSeverityFlagsEXT::ERROR => {
+            tracing::error!(
+                target: "vulkan",
+                message_id = %message_id_name,
+                message_id_number,
+                ?message_type,
+                "{message}"
+            );
+            if let Some(state) = (user_data as *const DebugMessengerState).as_ref() {
+                state.record_error(&message);
+            }
+        }
+        vk::DebugUtilsMessageSeverityFlagsEXT::WARNING => {
+            tracing::warn!(
+                target: "vulkan",
+                message_id = %message_id_name,
+                message_id_number,
+                ?message_type,
+                "{message}"
+            )
+        }
+        vk::DebugUtilsMessageSeverityFlagsEXT::INFO => {
+            tracing::debug!(
+                target: "vulkan",
+                message_id = %message_id_name,
+                message_id_number,
+                ?message_type,
+                "{message}"
+            )
+        }
+        _ => {
+            tracing::trace!(
+                target: "vulkan",
+                message_id = %message_id_name,
+                message_id_number,
+                ?message_type,
+                "{message}"
+            )
+        }
+    }
+
+    vk::FALSE
+}